@@ -0,0 +1,91 @@
+// build.rs - Builds the user programs in user/ and packs them into the
+// initial archive (a plain ustar file) that the kernel embeds.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const USER_TARGET: &str = "x86_64-unknown-none";
+
+fn main() {
+    let manifest_dir = PathBuf::from(env::var_os("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    let user_dir = manifest_dir.join("user");
+    let target_dir = out_dir.join("user");
+
+    println!("cargo:rerun-if-changed=user/Cargo.toml");
+    println!("cargo:rerun-if-changed=user/src");
+
+    // A cargo of its own, with a target directory of its own. The flags and
+    // wrappers set for the kernel's build (clippy's among them) don't apply.
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    let status = Command::new(cargo)
+        .current_dir(&user_dir)
+        .args(["build", "--release", "--bins", "--target", USER_TARGET])
+        .arg("--target-dir")
+        .arg(&target_dir)
+        .env_remove("RUSTFLAGS")
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("RUSTC_WORKSPACE_WRAPPER")
+        .status()
+        .expect("failed to run cargo for the user programs");
+    assert!(status.success(), "building the user programs failed");
+
+    let binaries = target_dir.join(USER_TARGET).join("release");
+    let mut archive = Vec::new();
+    for name in program_names(&user_dir.join("src/bin")) {
+        let data = fs::read(binaries.join(&name)).expect("user program missing");
+        append_file(&mut archive, &name, &data);
+    }
+    // Two empty blocks end the archive
+    archive.resize(archive.len() + 1024, 0);
+    fs::write(out_dir.join("initrd.tar"), archive).unwrap();
+}
+
+/// One program per file in `src/bin`, sorted by name.
+fn program_names(bin_dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = fs::read_dir(bin_dir)
+        .unwrap()
+        .filter_map(|entry| {
+            let path = entry.unwrap().path();
+            let name = path.file_stem()?.to_str()?.to_owned();
+            (path.extension()? == "rs").then_some(name)
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+/// Appends a ustar header for a regular file, then its data padded to 512
+/// bytes.
+fn append_file(archive: &mut Vec<u8>, name: &str, data: &[u8]) {
+    assert!(name.len() < 100, "file name too long for the archive: {}", name);
+
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o755);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], data.len() as u64);
+    write_octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is taken with its own field filled with spaces
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    write_octal(&mut header[148..155], u64::from(checksum));
+
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(512), 0);
+}
+
+/// Zero-padded octal digits, NUL-terminated, filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    assert_eq!(digits.len(), field.len(), "value too large for its tar field");
+    field.copy_from_slice(digits.as_bytes());
+}
@@ -0,0 +1,311 @@
+use alloc::vec::Vec;
+use core::{mem, ptr, slice};
+use spin::Once;
+use crate::memory;
+
+/// Segment of the Extended BIOS Data Area, stored in the BIOS data area.
+const EBDA_POINTER: u64 = 0x40e;
+const BIOS_AREA_START: u64 = 0xe0000;
+const BIOS_AREA_END: u64 = 0x100000;
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+static ACPI: Once<Option<Acpi>> = Once::new();
+
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // ACPI 2.0 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by every system description table.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+const SDT_HEADER_SIZE: usize = mem::size_of::<SdtHeader>();
+
+pub struct Acpi {
+    pub revision: u8,
+    /// Signature and physical address of every table the RSDT/XSDT lists.
+    tables: Vec<([u8; 4], u64)>,
+    madt: Option<Madt>,
+}
+
+/// What the MADT says about the interrupt controllers.
+#[derive(Debug, Default)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    /// Set if the machine also has the legacy 8259 PICs.
+    pub has_legacy_pics: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub acpi_id: u8,
+    pub apic_id: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ wired to a different global system interrupt, or with a
+/// polarity or trigger mode other than the ISA default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+}
+
+/// Looks for the ACPI tables. Returns false if there are none.
+///
+/// Needs `memory::init` and the heap.
+pub fn init() -> bool {
+    let found = ACPI.call_once(|| unsafe { find_rsdp() }.map(|rsdp| unsafe { load(rsdp) }))
+        .is_some();
+    if !found {
+        log::warn!("No ACPI tables found");
+    }
+    found
+}
+
+pub fn get() -> Option<&'static Acpi> {
+    ACPI.wait().and_then(|acpi| acpi.as_ref())
+}
+
+impl Acpi {
+    pub fn madt(&self) -> Option<&Madt> {
+        self.madt.as_ref()
+    }
+
+    /// Physical address of the first table with the given signature.
+    pub fn find_table(&self, signature: &[u8; 4]) -> Option<u64> {
+        self.tables
+            .iter()
+            .find(|(table_signature, _)| table_signature == signature)
+            .map(|&(_, address)| address)
+    }
+}
+
+fn phys_to_ptr<T>(address: u64) -> *const T {
+    (memory::physical_memory_offset() + address).as_ptr()
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// Returns the bytes of the table at `address` if its checksum is valid.
+///
+/// # Safety
+///
+/// `address` must point to an ACPI table in mapped physical memory.
+pub unsafe fn table_bytes(address: u64) -> Option<&'static [u8]> {
+    let header: SdtHeader = unsafe { ptr::read_unaligned(phys_to_ptr(address)) };
+    let bytes = unsafe { slice::from_raw_parts(phys_to_ptr::<u8>(address), header.length as usize) };
+    checksum_ok(bytes).then_some(bytes)
+}
+
+/// Searches the first KiB of the EBDA, then the BIOS area below 1 MiB.
+unsafe fn find_rsdp() -> Option<Rsdp> {
+    let ebda = u64::from(unsafe { ptr::read_unaligned(phys_to_ptr::<u16>(EBDA_POINTER)) }) << 4;
+
+    let mut areas = [(ebda, ebda + 1024), (BIOS_AREA_START, BIOS_AREA_END)];
+    if ebda == 0 {
+        areas[0] = (0, 0);
+    }
+
+    for (start, end) in areas {
+        for address in (start..end).step_by(16) {
+            let signature: [u8; 8] = unsafe { ptr::read_unaligned(phys_to_ptr(address)) };
+            if &signature != RSDP_SIGNATURE {
+                continue;
+            }
+
+            // The checksum of version 1 covers the first 20 bytes only
+            let bytes = unsafe { slice::from_raw_parts(phys_to_ptr::<u8>(address), 20) };
+            if checksum_ok(bytes) {
+                return Some(unsafe { ptr::read_unaligned(phys_to_ptr(address)) });
+            }
+        }
+    }
+    None
+}
+
+unsafe fn load(rsdp: Rsdp) -> Acpi {
+    // Prefer the XSDT, which has 64-bit entries
+    let (root, entry_size) = if rsdp.revision >= 2 && rsdp.xsdt_address != 0 {
+        (rsdp.xsdt_address, 8)
+    } else {
+        (u64::from(rsdp.rsdt_address), 4)
+    };
+
+    let mut tables = Vec::new();
+    if let Some(bytes) = unsafe { table_bytes(root) } {
+        for entry in bytes[SDT_HEADER_SIZE..].chunks_exact(entry_size) {
+            let address = if entry_size == 8 {
+                u64::from_le_bytes(entry.try_into().unwrap())
+            } else {
+                u64::from(u32::from_le_bytes(entry.try_into().unwrap()))
+            };
+            let header: SdtHeader = unsafe { ptr::read_unaligned(phys_to_ptr(address)) };
+            tables.push((header.signature, address));
+        }
+    }
+
+    let mut acpi = Acpi {
+        revision: rsdp.revision,
+        tables,
+        madt: None,
+    };
+    acpi.madt = acpi
+        .find_table(b"APIC")
+        .and_then(|address| unsafe { table_bytes(address) })
+        .map(parse_madt);
+
+    log::info!("ACPI revision {} with {} tables", acpi.revision, acpi.tables.len());
+    if let Some(madt) = &acpi.madt {
+        log::info!(
+            "MADT: {} processors, {} I/O APICs, {} overrides",
+            madt.processors.len(),
+            madt.io_apics.len(),
+            madt.overrides.len()
+        );
+    }
+    acpi
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Parses a MADT, header included.
+fn parse_madt(bytes: &[u8]) -> Madt {
+    const PCAT_COMPAT: u32 = 1;
+
+    let mut madt = Madt {
+        local_apic_address: u64::from(read_u32(bytes, SDT_HEADER_SIZE)),
+        has_legacy_pics: read_u32(bytes, SDT_HEADER_SIZE + 4) & PCAT_COMPAT != 0,
+        ..Madt::default()
+    };
+
+    // Variable length entries, each starting with its type and length
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= bytes.len() {
+        let entry_type = bytes[offset];
+        let length = bytes[offset + 1] as usize;
+        if length < 2 || offset + length > bytes.len() {
+            break;
+        }
+        let entry = &bytes[offset..offset + length];
+
+        match (entry_type, length) {
+            (0, 8) => madt.processors.push(Processor {
+                acpi_id: entry[2],
+                apic_id: entry[3],
+                enabled: read_u32(entry, 4) & 1 != 0,
+            }),
+            (1, 12) => madt.io_apics.push(IoApic {
+                id: entry[2],
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8),
+            }),
+            (2, 10) => madt.overrides.push(InterruptOverride {
+                source: entry[3],
+                gsi: read_u32(entry, 4),
+                flags: read_u16(entry, 8),
+            }),
+            (5, 12) => madt.local_apic_address = read_u64(entry, 4),
+            _ => {}
+        }
+        offset += length;
+    }
+    madt
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    fn madt_bytes() -> Vec<u8> {
+        let mut bytes = vec![0u8; SDT_HEADER_SIZE];
+        bytes[..4].copy_from_slice(b"APIC");
+        bytes.extend_from_slice(&0xfee0_0000u32.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        // Local APIC: ACPI ID 0, APIC ID 0, enabled
+        bytes.extend_from_slice(&[0, 8, 0, 0, 1, 0, 0, 0]);
+        // Local APIC: ACPI ID 1, APIC ID 1, disabled
+        bytes.extend_from_slice(&[0, 8, 1, 1, 0, 0, 0, 0]);
+        // I/O APIC 2 at 0xfec00000, GSI base 0
+        bytes.extend_from_slice(&[1, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]);
+        // ISA IRQ 0 -> GSI 2
+        bytes.extend_from_slice(&[2, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        // Unknown entry type is skipped
+        bytes.extend_from_slice(&[9, 4, 0, 0]);
+
+        let length = bytes.len() as u32;
+        bytes[4..8].copy_from_slice(&length.to_le_bytes());
+        bytes
+    }
+
+    #[test_case]
+    fn test_checksum() {
+        assert!(checksum_ok(&[0x01, 0xff]));
+        assert!(!checksum_ok(&[0x01, 0xfe]));
+    }
+
+    #[test_case]
+    fn test_parse_madt() {
+        let madt = parse_madt(&madt_bytes());
+        assert_eq!(madt.local_apic_address, 0xfee0_0000);
+        assert!(madt.has_legacy_pics);
+        assert_eq!(madt.processors.len(), 2);
+        assert!(madt.processors[0].enabled);
+        assert!(!madt.processors[1].enabled);
+        assert_eq!(madt.io_apics, [IoApic { id: 2, address: 0xfec0_0000, gsi_base: 0 }]);
+        assert_eq!(madt.overrides, [InterruptOverride { source: 0, gsi: 2, flags: 0 }]);
+    }
+
+    #[test_case]
+    fn test_parse_madt_stops_at_truncated_entry() {
+        let mut bytes = madt_bytes();
+        bytes.extend_from_slice(&[1, 12, 3]);
+        assert_eq!(parse_madt(&bytes).io_apics.len(), 1);
+    }
+}
@@ -0,0 +1,64 @@
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{FrameAllocator, Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use fixed_size_block::FixedSizeBlockAllocator;
+use crate::memory;
+use crate::sync::{IrqSafeMutex, IrqSafeMutexGuard};
+
+pub mod fixed_size_block;
+
+pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_SIZE: usize = 1024 * 1024; // 1 MiB
+
+#[global_allocator]
+static ALLOCATOR: Locked<FixedSizeBlockAllocator> = Locked::new(FixedSizeBlockAllocator::new());
+
+/// Maps the heap region to fresh frames and hands it to the global allocator.
+/// Must be called after `memory::init`.
+pub fn init_heap() -> Result<(), MapToError<Size4KiB>> {
+    let page_range = {
+        let heap_start = VirtAddr::new(HEAP_START as u64);
+        let heap_end = heap_start + HEAP_SIZE - 1u64;
+        let heap_start_page = Page::containing_address(heap_start);
+        let heap_end_page = Page::containing_address(heap_end);
+        Page::range_inclusive(heap_start_page, heap_end_page)
+    };
+
+    let mut frame_allocator = memory::frame_allocator();
+    for page in page_range {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { memory::map_page(page, frame, flags, &mut *frame_allocator)? };
+    }
+
+    unsafe {
+        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+    }
+    log::info!("Heap of {} KiB at {:#x}", HEAP_SIZE / 1024, HEAP_START);
+
+    Ok(())
+}
+
+/// A wrapper around an `IrqSafeMutex` to permit trait implementations.
+///
+/// Allocations run with interrupts disabled, so a thread can't be preempted
+/// while it holds the heap. Code that allocates with interrupts off would
+/// otherwise spin forever on a lock whose holder can't run again.
+pub struct Locked<A> {
+    inner: IrqSafeMutex<A>,
+}
+
+impl<A> Locked<A> {
+    pub const fn new(inner: A) -> Self {
+        Locked {
+            inner: IrqSafeMutex::named("heap", inner),
+        }
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, A> {
+        self.inner.lock()
+    }
+}
@@ -0,0 +1,313 @@
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::{PhysAddr, VirtAddr};
+use crate::acpi::{self, InterruptOverride, Madt};
+use crate::memory;
+use crate::pic::{InterruptIndex, PICS};
+
+/// Where the local APIC registers are mapped, followed by one page for
+/// each I/O APIC.
+const APIC_MMIO_START: u64 = 0x_5555_5555_0000;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+// Local APIC registers
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TASK_PRIORITY: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL: usize = 0x380;
+const LAPIC_TIMER_CURRENT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+// Interrupt command register fields
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+
+/// Timer ticks to count local APIC timer decrements over.
+const CALIBRATION_TICKS: u64 = 10;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// I/O APIC registers, accessed through a select and a data window
+const IOAPIC_SELECT: usize = 0x00;
+const IOAPIC_WINDOW: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL_TRIGGERED: u32 = 1 << 15;
+const REDIRECT_MASKED: u32 = 1 << 16;
+
+/// Legacy IRQs routed through the I/O APIC.
+const ROUTED_IRQS: [InterruptIndex; 4] = [
+    InterruptIndex::Keyboard,
+    InterruptIndex::SerialPort1,
+    InterruptIndex::Rtc,
+    InterruptIndex::Mouse,
+];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether interrupts come through the APICs instead of the 8259 PICs.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn lapic_read(register: usize) -> u32 {
+    unsafe { ptr::read_volatile((APIC_MMIO_START as usize + register) as *const u32) }
+}
+
+fn lapic_write(register: usize, value: u32) {
+    unsafe { ptr::write_volatile((APIC_MMIO_START as usize + register) as *mut u32, value) }
+}
+
+/// APIC ID of the CPU this runs on.
+pub fn local_apic_id() -> u8 {
+    (lapic_read(LAPIC_ID) >> 24) as u8
+}
+
+/// Enables the local APIC of an application processor. Its timer stays
+/// off: the boot processor keeps time and runs the scheduler.
+pub fn init_ap() {
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+    }
+    lapic_write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+    lapic_write(LAPIC_TASK_PRIORITY, 0);
+}
+
+/// Sends an INIT IPI, which resets the processor to wait for a startup IPI.
+pub(crate) fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_ASSERT);
+}
+
+/// Sends a startup IPI. The processor starts in real mode at physical
+/// address `page * 0x1000`.
+pub(crate) fn send_startup(apic_id: u8, page: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_ASSERT | u32::from(page));
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        lapic_write(LAPIC_ICR_HIGH, u32::from(apic_id) << 24);
+        // Writing the low half sends the IPI
+        lapic_write(LAPIC_ICR_LOW, command);
+        while lapic_read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
+
+/// Acknowledges the interrupt being handled. Used instead of the PIC's EOI
+/// once the APICs are enabled.
+pub fn end_of_interrupt() {
+    lapic_write(LAPIC_EOI, 0);
+}
+
+struct IoApic {
+    base: usize,
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_SELECT) as *mut u32, register);
+            ptr::read_volatile((self.base + IOAPIC_WINDOW) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile((self.base + IOAPIC_SELECT) as *mut u32, register);
+            ptr::write_volatile((self.base + IOAPIC_WINDOW) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn set_redirect(&self, gsi: u32, low: u32, destination: u8) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        self.write(register + 1, u32::from(destination) << 24);
+        self.write(register, low);
+    }
+}
+
+/// Switches interrupt delivery from the 8259 PICs to the local APIC and
+/// I/O APICs described by the ACPI MADT. Returns false, leaving the PICs in
+/// charge, if there is no MADT or no I/O APIC.
+///
+/// Needs `acpi::init`, and the PIT timer running for calibration.
+pub fn init() -> bool {
+    let madt = match acpi::get().and_then(|acpi| acpi.madt()) {
+        Some(madt) if !madt.io_apics.is_empty() => madt,
+        _ => {
+            log::info!("No I/O APIC, staying with the 8259 PICs");
+            return false;
+        }
+    };
+
+    let io_apics = match unsafe { map_registers(madt) } {
+        Ok(io_apics) => io_apics,
+        Err(_) => {
+            log::warn!("Could not map the APIC registers, staying with the 8259 PICs");
+            return false;
+        }
+    };
+
+    unsafe {
+        let mut apic_base = Msr::new(IA32_APIC_BASE);
+        let value = apic_base.read();
+        apic_base.write(value | APIC_BASE_ENABLE);
+    }
+    lapic_write(LAPIC_SPURIOUS, LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+    lapic_write(LAPIC_TASK_PRIORITY, 0);
+
+    let count_per_tick = calibrate_timer();
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for io_apic in &io_apics {
+            for entry in 0..io_apic.entries {
+                io_apic.set_redirect(io_apic.gsi_base + entry, REDIRECT_MASKED, 0);
+            }
+        }
+
+        let destination = local_apic_id();
+        for index in ROUTED_IRQS {
+            route_irq(&io_apics, &madt.overrides, index, destination);
+        }
+
+        // The local APIC timer takes over from the PIT at the same rate
+        lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        lapic_write(LAPIC_LVT_TIMER, TIMER_PERIODIC | u32::from(InterruptIndex::Timer.as_u8()));
+        lapic_write(LAPIC_TIMER_INITIAL, count_per_tick);
+
+        unsafe { PICS.lock().disable() };
+        ENABLED.store(true, Ordering::Relaxed);
+    });
+
+    log::info!(
+        "Local APIC {} enabled, timer at {} counts per tick",
+        local_apic_id(),
+        count_per_tick
+    );
+    true
+}
+
+unsafe fn map_registers(madt: &Madt) -> Result<Vec<IoApic>, ()> {
+    let mut virt = APIC_MMIO_START;
+    unsafe {
+        memory::map_mmio(VirtAddr::new(virt), PhysAddr::new(madt.local_apic_address))
+            .map_err(|_| ())?;
+    }
+
+    let mut io_apics = Vec::new();
+    for io_apic in &madt.io_apics {
+        virt += 0x1000;
+        let phys = PhysAddr::new(u64::from(io_apic.address));
+        unsafe { memory::map_mmio(VirtAddr::new(virt), phys).map_err(|_| ())? };
+
+        // The registers needn't start on a page boundary
+        let base = (virt + phys.as_u64() % 0x1000) as usize;
+        let mut io_apic = IoApic {
+            base,
+            gsi_base: io_apic.gsi_base,
+            entries: 0,
+        };
+        io_apic.entries = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apics.push(io_apic);
+    }
+    Ok(io_apics)
+}
+
+/// Counts how far the local APIC timer decrements during one PIT tick.
+fn calibrate_timer() -> u32 {
+    use crate::time;
+    use x86_64::instructions::hlt;
+
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+
+    // Start on a tick boundary so only whole ticks are measured
+    let start = time::ticks();
+    while time::ticks() == start {
+        hlt();
+    }
+
+    lapic_write(LAPIC_TIMER_INITIAL, u32::MAX);
+    let start = time::ticks();
+    while time::ticks() < start + CALIBRATION_TICKS {
+        hlt();
+    }
+    let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+    lapic_write(LAPIC_TIMER_INITIAL, 0);
+
+    elapsed / CALIBRATION_TICKS as u32
+}
+
+/// Redirection entry flags for an ISA IRQ. Without an override, ISA IRQs
+/// are edge triggered and active high.
+fn redirect_flags(flags: u16) -> u32 {
+    const POLARITY_ACTIVE_LOW: u16 = 0b11;
+    const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+    let mut redirect = 0;
+    if flags & POLARITY_ACTIVE_LOW == POLARITY_ACTIVE_LOW {
+        redirect |= REDIRECT_ACTIVE_LOW;
+    }
+    if flags & TRIGGER_LEVEL == TRIGGER_LEVEL {
+        redirect |= REDIRECT_LEVEL_TRIGGERED;
+    }
+    redirect
+}
+
+fn route_irq(
+    io_apics: &[IoApic],
+    overrides: &[InterruptOverride],
+    index: InterruptIndex,
+    destination: u8,
+) {
+    let irq = index.irq();
+    let (gsi, flags) = overrides
+        .iter()
+        .find(|entry| entry.source == irq)
+        .map_or((u32::from(irq), 0), |entry| (entry.gsi, entry.flags));
+
+    if let Some(io_apic) = io_apics.iter().find(|io_apic| io_apic.handles(gsi)) {
+        let low = u32::from(index.as_u8()) | redirect_flags(flags);
+        io_apic.set_redirect(gsi, low, destination);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_redirect_flags() {
+        assert_eq!(redirect_flags(0), 0);
+        assert_eq!(redirect_flags(0b0101), 0);
+        assert_eq!(redirect_flags(0b0011), REDIRECT_ACTIVE_LOW);
+        assert_eq!(redirect_flags(0b1100), REDIRECT_LEVEL_TRIGGERED);
+        assert_eq!(redirect_flags(0b1111), REDIRECT_ACTIVE_LOW | REDIRECT_LEVEL_TRIGGERED);
+    }
+}
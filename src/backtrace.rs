@@ -0,0 +1,210 @@
+use core::arch::asm;
+use core::fmt::{self, Write};
+use x86_64::registers::control::Cr3;
+
+/// Space for the symbol table. `tools/ksymtab.py` fills it in after linking
+/// (the cargo runner calls it), so the kernel image carries its own symbols.
+const SYMBOL_TABLE_SIZE: usize = 1024 * 1024;
+const MAGIC: &[u8; 8] = b"KSYMTAB1";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 16;
+
+/// Stop walking after this many frames, in case the chain loops.
+const MAX_FRAMES: usize = 32;
+
+#[repr(C, align(8))]
+struct SymbolTable([u8; SYMBOL_TABLE_SIZE]);
+
+#[used]
+#[link_section = ".ksymtab"]
+static SYMBOL_TABLE: SymbolTable = SymbolTable([0; SYMBOL_TABLE_SIZE]);
+
+/// A function symbol that an address was resolved to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: &'static str,
+    pub address: u64,
+    pub offset: u64,
+}
+
+/// Returns the current frame pointer. Inlined, so this is the caller's frame.
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    rbp
+}
+
+/// Looks up the function containing `addr` in the embedded symbol table.
+pub fn resolve(addr: u64) -> Option<Symbol> {
+    // The compiler only sees zeros in the table, so hide where it comes from
+    let table = unsafe { &*core::hint::black_box(&raw const SYMBOL_TABLE.0) };
+    resolve_in(table, addr)
+}
+
+fn resolve_in(table: &'static [u8], addr: u64) -> Option<Symbol> {
+    if table.len() < HEADER_SIZE || &table[..8] != MAGIC {
+        return None;
+    }
+    let count = read_u32(table, 8)? as usize;
+    let strings = read_u32(table, 12)? as usize;
+
+    let entry = |index: usize| {
+        let at = HEADER_SIZE + index * ENTRY_SIZE;
+        Some((read_u64(table, at)?, read_u32(table, at + 8)?, read_u32(table, at + 12)?))
+    };
+
+    // The last entry that starts at or below `addr`
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        if entry(mid)?.0 <= addr {
+            low = mid + 1;
+        } else {
+            high = mid;
+        }
+    }
+    let (address, size, name_offset) = entry(low.checked_sub(1)?)?;
+    if size != 0 && addr >= address + size as u64 {
+        return None;
+    }
+
+    let name = table.get(strings + name_offset as usize..)?;
+    let name = &name[..name.iter().position(|&b| b == 0)?];
+    Some(Symbol {
+        name: core::str::from_utf8(name).ok()?,
+        address,
+        offset: addr - address,
+    })
+}
+
+fn read_u32(table: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(table.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(table: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(table.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// Writes a symbolized backtrace, starting at `rip` if given and then
+/// following the frame pointer chain from `rbp`.
+///
+/// Takes no locks and allocates nothing, so it is safe to call from the
+/// panic handler and exception handlers.
+pub fn write_backtrace(out: &mut impl Write, rip: Option<u64>, mut rbp: u64) -> fmt::Result {
+    writeln!(out, "Backtrace:")?;
+
+    let mut frame = 0;
+    if let Some(rip) = rip {
+        write_frame(out, frame, rip, rip)?;
+        frame += 1;
+    }
+
+    while frame < MAX_FRAMES && rbp != 0 && rbp.is_multiple_of(8) {
+        // Each frame holds the caller's rbp, followed by the return address
+        if !is_mapped(rbp) || !is_mapped(rbp + 15) {
+            break;
+        }
+        let (next, ret) = unsafe { (*(rbp as *const u64), *((rbp + 8) as *const u64)) };
+        if ret == 0 {
+            break;
+        }
+        // The call instruction ends at the return address
+        write_frame(out, frame, ret, ret - 1)?;
+        frame += 1;
+
+        // Stacks grow down, so the caller's frame is always higher up
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+    }
+    Ok(())
+}
+
+fn write_frame(out: &mut impl Write, frame: usize, addr: u64, lookup: u64) -> fmt::Result {
+    match resolve(lookup) {
+        Some(symbol) => writeln!(
+            out,
+            "  #{:<2} {:#018x} {}+{:#x}",
+            frame,
+            addr,
+            symbol.name,
+            addr - symbol.address
+        ),
+        None => writeln!(out, "  #{:<2} {:#018x} <unknown>", frame, addr),
+    }
+}
+
+/// Checks whether `addr` is mapped by walking the page tables directly,
+/// since the mapper's lock may be held by the code that crashed.
+fn is_mapped(addr: u64) -> bool {
+    const PRESENT: u64 = 1;
+    const HUGE_PAGE: u64 = 1 << 7;
+    const ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+    let offset = match crate::memory::boot_info() {
+        Some(boot_info) => boot_info.physical_memory_offset,
+        // Before paging is set up there is no way to check
+        None => return true,
+    };
+
+    let mut table = Cr3::read().0.start_address().as_u64();
+    for level in (0..4).rev() {
+        let index = (addr >> (12 + 9 * level)) & 0x1ff;
+        let entry = unsafe { *((offset + table + index * 8) as *const u64) };
+        if entry & PRESENT == 0 {
+            return false;
+        }
+        if level == 0 || (level < 3 && entry & HUGE_PAGE != 0) {
+            return true;
+        }
+        table = entry & ADDRESS_MASK;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    #[test_case]
+    fn test_resolve_in_table() {
+        // Two symbols at 0x1000 (size 0x10) and 0x2000 (no size)
+        static TABLE: [u8; 54] = {
+            let mut table = [0; 54];
+            let header = *b"KSYMTAB1\x02\x00\x00\x00\x30\x00\x00\x00";
+            let mut i = 0;
+            while i < 16 {
+                table[i] = header[i];
+                i += 1;
+            }
+            table[17] = 0x10;
+            table[24] = 0x10;
+            table[33] = 0x20;
+            table[44] = 4;
+            table[48] = b'f';
+            table[49] = b'o';
+            table[50] = b'o';
+            table[52] = b'b';
+            table
+        };
+
+        let foo = resolve_in(&TABLE, 0x1004).unwrap();
+        assert_eq!((foo.name, foo.address, foo.offset), ("foo", 0x1000, 4));
+        assert_eq!(resolve_in(&TABLE, 0x1010), None);
+        assert_eq!(resolve_in(&TABLE, 0xfff), None);
+        assert_eq!(resolve_in(&TABLE, 0x2345).unwrap().name, "b");
+        assert_eq!(resolve_in(&[0; 16], 0x1000), None);
+    }
+
+    #[test_case]
+    fn test_backtrace_walks_frames() {
+        let mut out = String::new();
+        write_backtrace(&mut out, Some(0x1234), frame_pointer()).unwrap();
+        assert!(out.starts_with("Backtrace:\n  #0  0x0000000000001234"));
+        // The test runner and kernel entry are above this frame
+        assert!(out.lines().count() > 3);
+    }
+}
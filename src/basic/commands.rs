@@ -1,106 +1,59 @@
 // commands.rs - System commands (LIST, RUN, NEW, SAVE, LOAD, DIR, DELETE, CLS)
 
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
 use crate::println;
 use super::types::*;
 
 pub fn add_line(program: &mut Program, number: u16, code: &str) {
-    let mut insert_pos = program.line_count;
-    
-    for i in 0..program.line_count {
-        if program.lines[i].number == number {
-            program.lines[i].set(number, code);
-            return;
-        }
-        if program.lines[i].number > number {
-            insert_pos = i;
-            break;
-        }
-    }
-
-    if program.line_count < MAX_LINES {
-        for i in (insert_pos..program.line_count).rev() {
-            program.lines[i + 1] = program.lines[i];
-        }
-        program.lines[insert_pos].set(number, code);
-        program.line_count += 1;
+    match program.position(number) {
+        Ok(i) => program.lines[i].code = code.to_string(),
+        Err(i) => program.lines.insert(i, Line { number, code: code.to_string() }),
     }
 }
 
 pub fn delete_line(program: &mut Program, number: u16) {
-    for i in 0..program.line_count {
-        if program.lines[i].number == number {
-            for j in i..program.line_count - 1 {
-                program.lines[j] = program.lines[j + 1];
-            }
-            program.line_count -= 1;
+    match program.position(number) {
+        Ok(i) => {
+            program.lines.remove(i);
             println!("Line {} deleted", number);
-            return;
         }
+        Err(_) => println!("Line {} not found", number),
     }
-    println!("Line {} not found", number);
 }
 
 pub fn list(program: &Program) {
-    for i in 0..program.line_count {
-        let line = &program.lines[i];
-        println!("{} {}", line.number, line.as_str());
+    for line in &program.lines {
+        println!("{} {}", line.number, line.code);
     }
 }
 
 pub fn cls() {
     // Clear screen by printing newlines
     for _ in 0..25 {
-        println!("");
+        println!();
     }
 }
 
-pub fn save(
-    program: &mut Program,
-    programs: &mut [Program; MAX_PROGRAMS],
-    program_count: &mut usize,
-    name: &str,
-) {
-    if *program_count >= MAX_PROGRAMS {
-        println!("Program storage full");
-        return;
-    }
-
-    let name_bytes = name.as_bytes();
-    let name_len = name_bytes.len().min(16);
-
-    program.name_len = name_len;
-    program.name[..name_len].copy_from_slice(&name_bytes[..name_len]);
-    
-    programs[*program_count] = *program;
-    *program_count += 1;
-
+pub fn save(program: &Program, programs: &mut BTreeMap<String, Program>, name: &str) {
+    // The first program saved under a name is the one kept
+    programs.entry(name.to_string()).or_insert_with(|| program.clone());
     println!("Program saved as '{}'", name);
 }
 
-pub fn load(
-    program: &mut Program,
-    programs: &[Program; MAX_PROGRAMS],
-    program_count: usize,
-    name: &str,
-) {
-    for i in 0..program_count {
-        let prog_name = core::str::from_utf8(&programs[i].name[..programs[i].name_len])
-            .unwrap_or("");
-        
-        if prog_name == name {
-            *program = programs[i];
+pub fn load(program: &mut Program, programs: &BTreeMap<String, Program>, name: &str) {
+    match programs.get(name) {
+        Some(saved) => {
+            *program = saved.clone();
             println!("Program '{}' loaded", name);
-            return;
         }
+        None => println!("Program '{}' not found", name),
     }
-    println!("Program '{}' not found", name);
 }
 
-pub fn dir(programs: &[Program; MAX_PROGRAMS], program_count: usize) {
+pub fn dir(programs: &BTreeMap<String, Program>) {
     println!("Stored programs:");
-    for i in 0..program_count {
-        let name = core::str::from_utf8(&programs[i].name[..programs[i].name_len])
-            .unwrap_or("???");
+    for name in programs.keys() {
         println!("  {}", name);
     }
 }
@@ -1,7 +1,8 @@
-// evaluator.rs - Expression and condition evaluation with RND()
+// evaluator.rs - Expression and condition evaluation with RND(), TIME$ and DATE$
 
+use alloc::string::{String, ToString};
 use super::parser;
-use super::types::{MAX_ARRAY_SIZE, MAX_ARRAYS};
+use super::types::Variables;
 
 // Simple LCG random number generator
 static mut RNG_STATE: u32 = 12345;
@@ -13,12 +14,7 @@ pub fn rnd(max: i32) -> i32 {
     }
 }
 
-pub fn evaluate(
-    expr: &str,
-    variables: &[i32; 26],
-    arrays: &[[i32; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-    array_dims: &[usize; MAX_ARRAYS],
-) -> Option<i32> {
+pub fn evaluate(expr: &str, vars: &Variables) -> Option<i32> {
     let expr = expr.trim();
 
     // Check for INKEY() function
@@ -29,7 +25,7 @@ pub fn evaluate(
     // Check for RND(n) function
     if expr.starts_with("RND(") && expr.ends_with(')') {
         let arg = &expr[4..expr.len() - 1];
-        if let Some(n) = evaluate(arg, variables, arrays, array_dims) {
+        if let Some(n) = evaluate(arg, vars) {
             if n > 0 {
                 return Some(rnd(n));
             }
@@ -39,14 +35,14 @@ pub fn evaluate(
 
     // Array access
     if expr.contains('(') && expr.contains(')') {
-        if let Some((array_idx, elem_idx)) = parse_array_access(expr, variables, array_dims) {
-            return Some(arrays[array_idx][elem_idx]);
+        if let Some((array_idx, elem_idx)) = parse_array_access(expr, vars) {
+            return Some(vars.arrays[array_idx][elem_idx]);
         }
     }
 
     // Variable
     if let Some(var_idx) = parser::var_index(expr) {
-        return Some(variables[var_idx]);
+        return Some(vars.numbers[var_idx]);
     }
 
     // Number
@@ -57,14 +53,14 @@ pub fn evaluate(
     // Arithmetic
     for op in &['+', '-', '*', '/'] {
         if let Some(pos) = expr.find(*op) {
-            let left = evaluate(&expr[..pos], variables, arrays, array_dims)?;
-            let right = evaluate(&expr[pos + 1..], variables, arrays, array_dims)?;
+            let left = evaluate(&expr[..pos], vars)?;
+            let right = evaluate(&expr[pos + 1..], vars)?;
 
             return Some(match op {
                 '+' => left + right,
                 '-' => left - right,
                 '*' => left * right,
-                '/' => if right != 0 { left / right } else { 0 },
+                '/' if right != 0 => left / right,
                 _ => 0,
             });
         }
@@ -73,17 +69,37 @@ pub fn evaluate(
     None
 }
 
-pub fn evaluate_condition(
-    cond: &str,
-    variables: &[i32; 26],
-    arrays: &[[i32; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-    array_dims: &[usize; MAX_ARRAYS],
-) -> bool {
+/// Evaluates a string expression: a literal, a string variable, or the
+/// TIME$ and DATE$ functions.
+pub fn evaluate_string(expr: &str, vars: &Variables) -> Option<String> {
+    let expr = expr.trim();
+
+    // String literal
+    if expr.len() >= 2 && expr.starts_with('"') && expr.ends_with('"') {
+        return Some(expr[1..expr.len() - 1].to_string());
+    }
+
+    match parser::to_upper(expr).as_str() {
+        "TIME$" => return Some(crate::rtc::DateTime::now().time_string()),
+        "DATE$" => return Some(crate::rtc::DateTime::now().date_string()),
+        _ => {}
+    }
+
+    // String variable (A$-Z$)
+    if expr.ends_with('$') && expr.len() == 2 {
+        let str_idx = parser::var_index(&expr[..1])?;
+        return Some(vars.strings[str_idx].clone());
+    }
+
+    None
+}
+
+pub fn evaluate_condition(cond: &str, vars: &Variables) -> bool {
     // Support both = and ==
     for op in &[">=", "<=", "<>", "==", "=", ">", "<"] {
         if let Some(pos) = cond.find(op) {
-            let left = evaluate(&cond[..pos], variables, arrays, array_dims).unwrap_or(0);
-            let right = evaluate(&cond[pos + op.len()..], variables, arrays, array_dims).unwrap_or(0);
+            let left = evaluate(&cond[..pos], vars).unwrap_or(0);
+            let right = evaluate(&cond[pos + op.len()..], vars).unwrap_or(0);
 
             return match *op {
                 ">" => left > right,
@@ -99,24 +115,20 @@ pub fn evaluate_condition(
     false
 }
 
-fn parse_array_access(
-    expr: &str,
-    variables: &[i32; 26],
-    array_dims: &[usize; MAX_ARRAYS],
-) -> Option<(usize, usize)> {
+fn parse_array_access(expr: &str, vars: &Variables) -> Option<(usize, usize)> {
     if let Some(paren_start) = expr.find('(') {
         if let Some(paren_end) = expr.find(')') {
             let array_name = expr[..paren_start].trim();
             let index_expr = expr[paren_start + 1..paren_end].trim();
             
             if let Some(array_idx) = parser::array_index(array_name) {
-                if array_dims[array_idx] == 0 {
+                let size = vars.arrays[array_idx].len();
+                if size == 0 {
                     return None;
                 }
-                
-                let dummy_arrays = [[0; MAX_ARRAY_SIZE]; MAX_ARRAYS];
-                if let Some(elem_idx) = evaluate(index_expr, variables, &dummy_arrays, array_dims) {
-                    if elem_idx >= 0 && (elem_idx as usize) < array_dims[array_idx] {
+
+                if let Some(elem_idx) = evaluate(index_expr, vars) {
+                    if elem_idx >= 0 && (elem_idx as usize) < size {
                         return Some((array_idx, elem_idx as usize));
                     }
                 }
@@ -125,3 +137,126 @@ fn parse_array_access(
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use alloc::vec;
+
+    struct State {
+        vars: Variables,
+    }
+
+    impl State {
+        fn new() -> Self {
+            State {
+                vars: Variables::new(),
+            }
+        }
+
+        fn eval(&self, expr: &str) -> Option<i32> {
+            evaluate(expr, &self.vars)
+        }
+
+        fn cond(&self, cond: &str) -> bool {
+            evaluate_condition(cond, &self.vars)
+        }
+    }
+
+    #[test_case]
+    fn test_evaluate_numbers() {
+        let state = State::new();
+        assert_eq!(state.eval("42"), Some(42));
+        assert_eq!(state.eval("  7 "), Some(7));
+        assert_eq!(state.eval("-3"), Some(-3));
+    }
+
+    #[test_case]
+    fn test_evaluate_variables() {
+        let mut state = State::new();
+        state.vars.numbers[0] = 5;
+        state.vars.numbers[25] = -2;
+        assert_eq!(state.eval("A"), Some(5));
+        assert_eq!(state.eval("a"), Some(5));
+        assert_eq!(state.eval("Z"), Some(-2));
+    }
+
+    #[test_case]
+    fn test_evaluate_arithmetic() {
+        let mut state = State::new();
+        state.vars.numbers[0] = 6;
+        assert_eq!(state.eval("1 + 2"), Some(3));
+        assert_eq!(state.eval("A * 7"), Some(42));
+        assert_eq!(state.eval("A / 4"), Some(1));
+        assert_eq!(state.eval("A - 10"), Some(-4));
+        assert_eq!(state.eval("1 + 2 * 3"), Some(7));
+    }
+
+    #[test_case]
+    fn test_evaluate_division_by_zero_is_zero() {
+        let state = State::new();
+        assert_eq!(state.eval("5 / 0"), Some(0));
+    }
+
+    #[test_case]
+    fn test_evaluate_invalid() {
+        let state = State::new();
+        assert_eq!(state.eval("HELLO"), None);
+        assert_eq!(state.eval("1 + FOO"), None);
+    }
+
+    #[test_case]
+    fn test_evaluate_array_access() {
+        let mut state = State::new();
+        state.vars.arrays[1] = vec![0; 5];
+        state.vars.arrays[1][3] = 99;
+        state.vars.numbers[8] = 3;
+        assert_eq!(state.eval("B(3)"), Some(99));
+        assert_eq!(state.eval("B(I)"), Some(99));
+        // Undimensioned arrays and out of range indices don't evaluate
+        assert_eq!(state.eval("C(0)"), None);
+        assert_eq!(state.eval("B(5)"), None);
+    }
+
+    #[test_case]
+    fn test_evaluate_rnd_in_range() {
+        let state = State::new();
+        for _ in 0..100 {
+            let value = state.eval("RND(6)").unwrap();
+            assert!((0..6).contains(&value));
+        }
+        assert_eq!(state.eval("RND(0)"), Some(0));
+    }
+
+    #[test_case]
+    fn test_evaluate_string() {
+        let mut state = State::new();
+        state.vars.strings[18] = "HELLO".to_string();
+        assert_eq!(evaluate_string("\"HI THERE\"", &state.vars).as_deref(), Some("HI THERE"));
+        assert_eq!(evaluate_string("S$", &state.vars).as_deref(), Some("HELLO"));
+        assert_eq!(evaluate_string("s$", &state.vars).as_deref(), Some("HELLO"));
+        assert_eq!(evaluate_string("42", &state.vars), None);
+
+        let time = evaluate_string("TIME$", &state.vars).unwrap();
+        assert_eq!(time.len(), 8);
+        assert_eq!(&time[2..3], ":");
+        let date = evaluate_string("date$", &state.vars).unwrap();
+        assert_eq!(date.len(), 10);
+        assert_eq!(&date[2..3], "-");
+    }
+
+    #[test_case]
+    fn test_evaluate_condition() {
+        let mut state = State::new();
+        state.vars.numbers[0] = 3;
+        assert!(state.cond("A = 3"));
+        assert!(state.cond("A == 3"));
+        assert!(state.cond("A <> 4"));
+        assert!(state.cond("A < 4"));
+        assert!(state.cond("A >= 3"));
+        assert!(state.cond("A <= 3"));
+        assert!(!state.cond("A > 3"));
+        assert!(!state.cond("A"));
+    }
+}
@@ -1,39 +1,31 @@
 // interpreter.rs - Main BASIC interpreter with full Snake game support
 
+use alloc::collections::BTreeMap;
+use alloc::string::String;
 use crate::println;
 use super::{types::*, parser, commands, arrays, statements};
 
 pub struct BasicInterpreter {
     program: Program,
-    programs: [Program; MAX_PROGRAMS],
-    program_count: usize,
-    variables: [i32; 26],
-    arrays: [[i32; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-    array_dims: [usize; MAX_ARRAYS],
-    strings: [[u8; 80]; 26], // String variables A$-Z$
-    string_lens: [usize; 26],
-    pc: usize,
-    running: bool,
-    for_stack: [(usize, usize, i32); 8],
-    for_stack_ptr: usize,
+    programs: BTreeMap<String, Program>,
+    vars: Variables,
+    state: ExecState,
     instruction_count: usize,
 }
 
+impl Default for BasicInterpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BasicInterpreter {
     pub const fn new() -> Self {
         BasicInterpreter {
             program: Program::new(),
-            programs: [Program::new(); MAX_PROGRAMS],
-            program_count: 0,
-            variables: [0; 26],
-            arrays: [[0; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-            array_dims: [0; MAX_ARRAYS],
-            strings: [[0; 80]; 26],
-            string_lens: [0; 26],
-            pc: 0,
-            running: false,
-            for_stack: [(0, 0, 0); 8],
-            for_stack_ptr: 0,
+            programs: BTreeMap::new(),
+            vars: Variables::new(),
+            state: ExecState::new(),
             instruction_count: 0,
         }
     }
@@ -59,98 +51,102 @@ impl BasicInterpreter {
 
     fn execute_command(&mut self, cmd: &str) {
         let cmd_upper = parser::to_upper(cmd);
-        
-        if cmd_upper.starts_with(b"LIST") {
+
+        if cmd_upper.starts_with("LIST") {
             commands::list(&self.program);
-        } else if cmd_upper.starts_with(b"RUN") {
+        } else if cmd_upper.starts_with("RUN") {
             self.run();
-        } else if cmd_upper.starts_with(b"NEW") {
+        } else if cmd_upper.starts_with("NEW") {
             self.clear_program();
-        } else if cmd_upper.starts_with(b"SAVE ") {
+        } else if cmd_upper.starts_with("SAVE ") {
             let name = cmd[5..].trim();
-            commands::save(&mut self.program, &mut self.programs, &mut self.program_count, name);
-        } else if cmd_upper.starts_with(b"LOAD ") {
+            commands::save(&self.program, &mut self.programs, name);
+        } else if cmd_upper.starts_with("LOAD ") {
             let name = cmd[5..].trim();
-            commands::load(&mut self.program, &self.programs, self.program_count, name);
-        } else if cmd_upper.starts_with(b"DIR") {
-            commands::dir(&self.programs, self.program_count);
-        } else if cmd_upper.starts_with(b"DELETE ") || cmd_upper.starts_with(b"DEL ") {
-            let start = if cmd_upper.starts_with(b"DELETE ") { 7 } else { 4 };
+            commands::load(&mut self.program, &self.programs, name);
+        } else if cmd_upper.starts_with("DIR") {
+            commands::dir(&self.programs);
+        } else if cmd_upper.starts_with("DELETE ") || cmd_upper.starts_with("DEL ") {
+            let start = if cmd_upper.starts_with("DELETE ") { 7 } else { 4 };
             if let Ok(line_num) = cmd[start..].trim().parse::<u16>() {
                 commands::delete_line(&mut self.program, line_num);
             } else {
                 println!("Usage: DELETE line_number");
             }
-        } else if cmd_upper.starts_with(b"DIM ") {
-            arrays::cmd_dim(&cmd[4..], &mut self.array_dims);
-        } else if cmd_upper.starts_with(b"CLS") {
+        } else if cmd_upper.starts_with("DIM ") {
+            arrays::cmd_dim(&cmd[4..], &mut self.vars);
+        } else if cmd_upper.starts_with("CLS") {
             commands::cls();
-        } else if cmd_upper.starts_with(b"EXIT") {
+        } else if cmd_upper.starts_with("EXIT") {
             println!("Exiting BASIC mode");
         } else {
-            statements::execute_statement(
-                cmd,
-                &mut self.variables,
-                &mut self.arrays,
-                &mut self.array_dims,
-                &mut self.strings,
-                &mut self.string_lens,
-                &self.program,
-                &mut self.pc,
-                &mut self.running,
-                &mut self.for_stack,
-                &mut self.for_stack_ptr,
-            );
+            // A SLEEP is left for the caller to wait out (see `take_sleep`)
+            statements::execute_statement(cmd, &mut self.vars, &self.program, &mut self.state);
         }
     }
 
+    /// Starts the program from its first line. The lines are executed by
+    /// calling `step` until it returns false.
     fn run(&mut self) {
-        self.running = true;
-        self.pc = 0;
-        self.for_stack_ptr = 0;
+        self.state.running = !self.program.lines.is_empty();
+        self.state.pc = 0;
+        self.state.for_stack.clear();
+        self.state.sleep_ms = None;
         self.instruction_count = 0;
+    }
+
+    /// Executes up to `max_lines` lines of the running program, stopping
+    /// early at a SLEEP (see `take_sleep`). Returns whether the program is
+    /// still running afterwards.
+    pub fn step(&mut self, max_lines: usize) -> bool {
+        for _ in 0..max_lines {
+            if !self.state.running || self.state.pc >= self.program.lines.len() {
+                break;
+            }
 
-        while self.running && self.pc < self.program.line_count {
             self.instruction_count += 1;
             if self.instruction_count >= MAX_INSTRUCTIONS {
-                println!("");
+                println!();
                 println!("ERROR: Program stopped - too many instructions (possible infinite loop)");
                 println!("Executed {} instructions.", MAX_INSTRUCTIONS);
-                self.running = false;
+                self.state.running = false;
+                break;
+            }
+
+            let line = &self.program.lines[self.state.pc].code;
+            statements::execute_statement(line, &mut self.vars, &self.program, &mut self.state);
+            self.state.pc = self.state.pc.wrapping_add(1);
+
+            if self.state.sleep_ms.is_some() {
                 break;
             }
+        }
 
-            let mut line_buf = [0u8; MAX_LINE_LEN];
-            let line_len = self.program.lines[self.pc].len;
-            line_buf[..line_len].copy_from_slice(&self.program.lines[self.pc].data[..line_len]);
-            let line = core::str::from_utf8(&line_buf[..line_len]).unwrap_or("");
-            
-            statements::execute_statement(
-                line,
-                &mut self.variables,
-                &mut self.arrays,
-                &mut self.array_dims,
-                &mut self.strings,
-                &mut self.string_lens,
-                &self.program,
-                &mut self.pc,
-                &mut self.running,
-                &mut self.for_stack,
-                &mut self.for_stack_ptr,
-            );
-            self.pc += 1;
+        if self.state.pc >= self.program.lines.len() {
+            self.state.running = false;
         }
+        self.state.running
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.state.running
+    }
+
+    /// Returns how long the program asked to SLEEP, if it did.
+    pub fn take_sleep(&mut self) -> Option<u64> {
+        self.state.sleep_ms.take()
+    }
 
-        self.running = false;
+    /// Stops a running program, e.g. after it triggered a CPU exception.
+    pub fn stop(&mut self) {
+        self.state.running = false;
+        self.state.for_stack.clear();
+        self.state.sleep_ms = None;
     }
 
     fn clear_program(&mut self) {
         self.program = Program::new();
-        self.variables = [0; 26];
-        self.arrays = [[0; MAX_ARRAY_SIZE]; MAX_ARRAYS];
-        self.array_dims = [0; MAX_ARRAYS];
-        self.strings = [[0; 80]; 26];
-        self.string_lens = [0; 26];
+        self.vars = Variables::new();
         println!("Program cleared");
     }
 }
@@ -9,3 +9,26 @@ mod statements;
 mod interpreter;
 
 pub use interpreter::BasicInterpreter;
+
+/// Lines executed between yields while a program runs.
+const LINES_PER_STEP: usize = 100;
+
+/// Runs the program started by `RUN` a few lines at a time, letting other
+/// tasks run in between and while it sleeps.
+pub async fn run_program() {
+    loop {
+        let (running, sleep_ms) = {
+            let mut basic = crate::BASIC.lock();
+            let running = basic.step(LINES_PER_STEP);
+            (running, basic.take_sleep())
+        };
+
+        if let Some(ms) = sleep_ms {
+            crate::time::sleep(ms).await;
+        }
+        if !running {
+            break;
+        }
+        crate::task::yield_now().await;
+    }
+}
@@ -1,111 +1,71 @@
 // statements.rs - Programming statements with INKEY(), SLEEP, and string support
 
 use crate::{print, println};
-use super::parser::{self, ByteArrayHelper};
+use super::parser;
 use super::evaluator;
 use super::arrays;
 use super::types::*;
 
-pub fn cmd_print(
-    expr: &str,
-    variables: &[i32; 26],
-    arrays: &[[i32; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-    array_dims: &[usize; MAX_ARRAYS],
-    strings: &[[u8; 80]; 26],
-    string_lens: &[usize; 26],
-) {
+pub fn cmd_print(expr: &str, vars: &Variables) {
     let expr = expr.trim();
-    
-    // String literal
-    if expr.starts_with('"') && expr.ends_with('"') {
-        println!("{}", &expr[1..expr.len() - 1]);
-    // String variable (S$, T$, etc - represented as A$-Z$)
-    } else if expr.ends_with('$') && expr.len() == 2 {
-        if let Some(str_idx) = parser::var_index(&expr[..1]) {
-            let s = core::str::from_utf8(&strings[str_idx][..string_lens[str_idx]]).unwrap_or("");
-            println!("{}", s);
-        }
+
+    // String literal, string variable (A$-Z$), TIME$ or DATE$
+    if let Some(text) = evaluator::evaluate_string(expr, vars) {
+        println!("{}", text);
     } else if expr.contains('(') && expr.contains(')') {
-        if let Some((array_idx, elem_idx)) = arrays::parse_array_access(expr, variables, arrays, array_dims) {
-            println!("{}", arrays[array_idx][elem_idx]);
+        if let Some((array_idx, elem_idx)) = arrays::parse_array_access(expr, vars) {
+            println!("{}", vars.arrays[array_idx][elem_idx]);
         }
     } else if let Some(var_idx) = parser::var_index(expr) {
-        println!("{}", variables[var_idx]);
+        println!("{}", vars.numbers[var_idx]);
     } else if let Ok(num) = expr.parse::<i32>() {
         println!("{}", num);
-    } else {
-        if let Some(result) = evaluator::evaluate(expr, variables, arrays, array_dims) {
-            println!("{}", result);
-        }
+    } else if let Some(result) = evaluator::evaluate(expr, vars) {
+        println!("{}", result);
     }
 }
 
-pub fn cmd_print_no_newline(
-    expr: &str,
-    variables: &[i32; 26],
-    arrays: &[[i32; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-    array_dims: &[usize; MAX_ARRAYS],
-    strings: &[[u8; 80]; 26],
-    string_lens: &[usize; 26],
-) {
+pub fn cmd_print_no_newline(expr: &str, vars: &Variables) {
     let expr = expr.trim();
-    
-    if expr.starts_with('"') && expr.ends_with('"') {
-        print!("{} ", &expr[1..expr.len() - 1]);
-    } else if expr.ends_with('$') && expr.len() == 2 {
-        if let Some(str_idx) = parser::var_index(&expr[..1]) {
-            let s = core::str::from_utf8(&strings[str_idx][..string_lens[str_idx]]).unwrap_or("");
-            print!("{} ", s);
-        }
+
+    if let Some(text) = evaluator::evaluate_string(expr, vars) {
+        print!("{} ", text);
     } else if expr.contains('(') && expr.contains(')') {
-        if let Some((array_idx, elem_idx)) = arrays::parse_array_access(expr, variables, arrays, array_dims) {
-            print!("{} ", arrays[array_idx][elem_idx]);
+        if let Some((array_idx, elem_idx)) = arrays::parse_array_access(expr, vars) {
+            print!("{} ", vars.arrays[array_idx][elem_idx]);
         }
     } else if let Some(var_idx) = parser::var_index(expr) {
-        print!("{} ", variables[var_idx]);
+        print!("{} ", vars.numbers[var_idx]);
     } else if let Ok(num) = expr.parse::<i32>() {
         print!("{} ", num);
-    } else {
-        if let Some(result) = evaluator::evaluate(expr, variables, arrays, array_dims) {
-            print!("{} ", result);
-        }
+    } else if let Some(result) = evaluator::evaluate(expr, vars) {
+        print!("{} ", result);
     }
 }
 
-pub fn cmd_let(
-    expr: &str,
-    variables: &mut [i32; 26],
-    arrays: &mut [[i32; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-    array_dims: &[usize; MAX_ARRAYS],
-    strings: &mut [[u8; 80]; 26],
-    string_lens: &mut [usize; 26],
-) {
+pub fn cmd_let(expr: &str, vars: &mut Variables) {
     if let Some(eq_pos) = expr.find('=') {
         let var = expr[..eq_pos].trim();
         let value_expr = expr[eq_pos + 1..].trim();
 
-        // String variable assignment: LET A$ = "HELLO"
+        // String variable assignment: LET A$ = "HELLO" or LET T$ = TIME$
         if var.ends_with('$') && var.len() == 2 {
             if let Some(str_idx) = parser::var_index(&var[..1]) {
-                if value_expr.starts_with('"') && value_expr.ends_with('"') {
-                    let content = &value_expr[1..value_expr.len() - 1];
-                    let bytes = content.as_bytes();
-                    let len = bytes.len().min(80);
-                    strings[str_idx][..len].copy_from_slice(&bytes[..len]);
-                    string_lens[str_idx] = len;
+                if let Some(value) = evaluator::evaluate_string(value_expr, vars) {
+                    vars.strings[str_idx] = value;
                 }
             }
         // Array assignment
         } else if var.contains('(') && var.contains(')') {
-            if let Some((array_idx, elem_idx)) = arrays::parse_array_access(var, variables, arrays, array_dims) {
-                if let Some(value) = evaluator::evaluate(value_expr, variables, arrays, array_dims) {
-                    arrays[array_idx][elem_idx] = value;
+            if let Some((array_idx, elem_idx)) = arrays::parse_array_access(var, vars) {
+                if let Some(value) = evaluator::evaluate(value_expr, vars) {
+                    vars.arrays[array_idx][elem_idx] = value;
                 }
             }
         // Regular variable
         } else if let Some(var_idx) = parser::var_index(var) {
-            if let Some(value) = evaluator::evaluate(value_expr, variables, arrays, array_dims) {
-                variables[var_idx] = value;
+            if let Some(value) = evaluator::evaluate(value_expr, vars) {
+                vars.numbers[var_idx] = value;
             }
         }
     }
@@ -113,104 +73,64 @@ pub fn cmd_let(
 
 pub fn cmd_goto(line_str: &str, program: &Program, pc: &mut usize) {
     if let Ok(target) = line_str.trim().parse::<u16>() {
-        for i in 0..program.line_count {
-            if program.lines[i].number == target {
-                *pc = i.wrapping_sub(1);
-                return;
-            }
+        if let Ok(i) = program.position(target) {
+            *pc = i.wrapping_sub(1);
         }
     }
 }
 
-pub fn cmd_if(
-    expr: &str,
-    variables: &mut [i32; 26],
-    arrays: &mut [[i32; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-    array_dims: &mut [usize; MAX_ARRAYS],
-    strings: &mut [[u8; 80]; 26],
-    string_lens: &mut [usize; 26],
-    program: &Program,
-    pc: &mut usize,
-    running: &mut bool,
-    for_stack: &mut [(usize, usize, i32); 8],
-    for_stack_ptr: &mut usize,
-) {
+pub fn cmd_if(expr: &str, vars: &mut Variables, program: &Program, state: &mut ExecState) {
     let upper = parser::to_upper(expr);
-    if let Some(then_pos) = upper.find_bytes(b"THEN") {
+    if let Some(then_pos) = upper.find("THEN") {
         let condition = expr[..then_pos].trim();
         let action = expr[then_pos + 4..].trim();
 
-        if evaluator::evaluate_condition(condition, variables, arrays, array_dims) {
-            execute_statement(
-                action,
-                variables,
-                arrays,
-                array_dims,
-                strings,
-                string_lens,
-                program,
-                pc,
-                running,
-                for_stack,
-                for_stack_ptr,
-            );
+        if evaluator::evaluate_condition(condition, vars) {
+            execute_statement(action, vars, program, state);
         }
     }
 }
 
-pub fn cmd_for(
-    expr: &str,
-    variables: &mut [i32; 26],
-    current_pc: usize,
-    for_stack: &mut [(usize, usize, i32); 8],
-    for_stack_ptr: &mut usize,
-) {
+pub fn cmd_for(expr: &str, vars: &mut Variables, state: &mut ExecState) {
     if let Some(eq_pos) = expr.find('=') {
         let var = expr[..eq_pos].trim();
         let rest = expr[eq_pos + 1..].trim();
-        
+
         let upper_rest = parser::to_upper(rest);
-        if let Some(to_pos) = upper_rest.find_bytes(b" TO ") {
+        if let Some(to_pos) = upper_rest.find(" TO ") {
             let start_str = rest[..to_pos].trim();
             let end_str = rest[to_pos + 4..].trim();
-            
+
             if let (Some(var_idx), Ok(start), Ok(end)) = (
                 parser::var_index(var),
                 start_str.parse::<i32>(),
                 end_str.parse::<i32>(),
             ) {
-                variables[var_idx] = start;
-                if *for_stack_ptr < 8 {
-                    for_stack[*for_stack_ptr] = (current_pc, var_idx, end);
-                    *for_stack_ptr += 1;
+                vars.numbers[var_idx] = start;
+                if state.for_stack.len() < MAX_FOR_DEPTH {
+                    state.for_stack.push((state.pc, var_idx, end));
                 }
             }
         }
     }
 }
 
-pub fn cmd_next(
-    variables: &mut [i32; 26],
-    pc: &mut usize,
-    for_stack: &mut [(usize, usize, i32); 8],
-    for_stack_ptr: &mut usize,
-) {
-    if *for_stack_ptr > 0 {
-        let (loop_start, var_idx, end_val) = for_stack[*for_stack_ptr - 1];
-        variables[var_idx] += 1;
+pub fn cmd_next(vars: &mut Variables, state: &mut ExecState) {
+    if let Some(&(loop_start, var_idx, end_val)) = state.for_stack.last() {
+        vars.numbers[var_idx] += 1;
 
-        if variables[var_idx] <= end_val {
-            *pc = loop_start;
+        if vars.numbers[var_idx] <= end_val {
+            state.pc = loop_start;
         } else {
-            *for_stack_ptr -= 1;
+            state.for_stack.pop();
         }
     }
 }
 
-pub fn cmd_input(var: &str, variables: &mut [i32; 26]) {
+pub fn cmd_input(var: &str, vars: &mut Variables) {
     if let Some(var_idx) = parser::var_index(var.trim()) {
         print!("? ");
-        variables[var_idx] = 0;
+        vars.numbers[var_idx] = 0;
     }
 }
 
@@ -219,66 +139,46 @@ pub fn cmd_inkey() -> i32 {
     crate::keyboard_buffer::get_key()
 }
 
-pub fn cmd_sleep(ms: i32) {
-    // Simple busy-wait delay
-    // In a real implementation, you'd use a timer interrupt
-    let loops = ms * 10000; // Rough approximation
-    for _ in 0..loops {
-        // Busy wait - use a dummy volatile read
-        unsafe { 
-            let dummy: u32 = 0;
-            core::ptr::read_volatile(&dummy);
-        }
-    }
+pub fn cmd_sleep(ms: u64, state: &mut ExecState) {
+    // The interpreter does the actual waiting, see `BasicInterpreter::step`
+    state.sleep_ms = Some(ms);
 }
 
-pub fn execute_statement(
-    stmt: &str,
-    variables: &mut [i32; 26],
-    arrays: &mut [[i32; MAX_ARRAY_SIZE]; MAX_ARRAYS],
-    array_dims: &mut [usize; MAX_ARRAYS],
-    strings: &mut [[u8; 80]; 26],
-    string_lens: &mut [usize; 26],
-    program: &Program,
-    pc: &mut usize,
-    running: &mut bool,
-    for_stack: &mut [(usize, usize, i32); 8],
-    for_stack_ptr: &mut usize,
-) {
+pub fn execute_statement(stmt: &str, vars: &mut Variables, program: &Program, state: &mut ExecState) {
     let stmt = stmt.trim();
     let upper = parser::to_upper(stmt);
 
-    if upper.starts_with(b"PRINT ") {
+    if upper.starts_with("PRINT ") {
         let expr = &stmt[6..];
         if expr.trim_end().ends_with(';') {
-            cmd_print_no_newline(&expr[..expr.trim_end().len() - 1], variables, arrays, array_dims, strings, string_lens);
+            cmd_print_no_newline(&expr[..expr.trim_end().len() - 1], vars);
         } else {
-            cmd_print(expr, variables, arrays, array_dims, strings, string_lens);
+            cmd_print(expr, vars);
         }
-    } else if upper.starts_with(b"DIM ") {
-        arrays::cmd_dim(&stmt[4..], array_dims);
-    } else if upper.starts_with(b"LET ") {
-        cmd_let(&stmt[4..], variables, arrays, array_dims, strings, string_lens);
-    } else if upper.starts_with(b"GOTO ") {
-        cmd_goto(&stmt[5..], program, pc);
-    } else if upper.starts_with(b"IF ") {
-        cmd_if(&stmt[3..], variables, arrays, array_dims, strings, string_lens, program, pc, running, for_stack, for_stack_ptr);
-    } else if upper.starts_with(b"FOR ") {
-        cmd_for(&stmt[4..], variables, *pc, for_stack, for_stack_ptr);
-    } else if upper.starts_with(b"NEXT") {
-        cmd_next(variables, pc, for_stack, for_stack_ptr);
-    } else if upper.starts_with(b"INPUT ") {
-        cmd_input(&stmt[6..], variables);
-    } else if upper.starts_with(b"SLEEP ") {
-        if let Ok(ms) = stmt[6..].trim().parse::<i32>() {
-            cmd_sleep(ms);
+    } else if upper.starts_with("DIM ") {
+        arrays::cmd_dim(&stmt[4..], vars);
+    } else if upper.starts_with("LET ") {
+        cmd_let(&stmt[4..], vars);
+    } else if upper.starts_with("GOTO ") {
+        cmd_goto(&stmt[5..], program, &mut state.pc);
+    } else if upper.starts_with("IF ") {
+        cmd_if(&stmt[3..], vars, program, state);
+    } else if upper.starts_with("FOR ") {
+        cmd_for(&stmt[4..], vars, state);
+    } else if upper.starts_with("NEXT") {
+        cmd_next(vars, state);
+    } else if upper.starts_with("INPUT ") {
+        cmd_input(&stmt[6..], vars);
+    } else if upper.starts_with("SLEEP ") {
+        if let Ok(ms) = stmt[6..].trim().parse::<u64>() {
+            cmd_sleep(ms, state);
         }
-    } else if upper.starts_with(b"CLS") {
+    } else if upper.starts_with("CLS") {
         super::commands::cls();
-    } else if upper.starts_with(b"END") {
-        *running = false;
-    } else if upper.starts_with(b"STOP") {
-        *running = false;
+    } else if upper.starts_with("END") {
+        state.running = false;
+    } else if upper.starts_with("STOP") {
+        state.running = false;
         println!("Program stopped");
     }
 }
@@ -1,54 +1,84 @@
 // types.rs - Core data structures and constants
 
-pub const MAX_LINES: usize = 256;
-pub const MAX_LINE_LEN: usize = 80;
-pub const MAX_PROGRAMS: usize = 8;
+use alloc::string::String;
+use alloc::vec::Vec;
+
 pub const MAX_INSTRUCTIONS: usize = 1_000_000;
 pub const MAX_ARRAYS: usize = 10;
 pub const MAX_ARRAY_SIZE: usize = 100;
+pub const MAX_FOR_DEPTH: usize = 8;
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct Line {
     pub number: u16,
-    pub data: [u8; MAX_LINE_LEN],
-    pub len: usize,
+    pub code: String,
 }
 
-impl Line {
+/// A stored program, with its lines kept sorted by line number.
+#[derive(Clone, Default)]
+pub struct Program {
+    pub lines: Vec<Line>,
+}
+
+impl Program {
     pub const fn new() -> Self {
-        Line {
-            number: 0,
-            data: [0; MAX_LINE_LEN],
-            len: 0,
-        }
+        Program { lines: Vec::new() }
     }
 
-    pub fn set(&mut self, number: u16, text: &str) {
-        self.number = number;
-        self.len = text.len().min(MAX_LINE_LEN);
-        self.data[..self.len].copy_from_slice(&text.as_bytes()[..self.len]);
+    /// Index of the line with the given number, or where it would be inserted.
+    pub fn position(&self, number: u16) -> Result<usize, usize> {
+        self.lines.binary_search_by_key(&number, |line| line.number)
     }
+}
 
-    pub fn as_str(&self) -> &str {
-        core::str::from_utf8(&self.data[..self.len]).unwrap_or("")
+/// Numeric variables A-Z, arrays A-J and string variables A$-Z$.
+/// An empty array has not been dimensioned yet.
+pub struct Variables {
+    pub numbers: [i32; 26],
+    pub arrays: [Vec<i32>; MAX_ARRAYS],
+    pub strings: [String; 26],
+}
+
+impl Variables {
+    pub const fn new() -> Self {
+        Variables {
+            numbers: [0; 26],
+            arrays: [const { Vec::new() }; MAX_ARRAYS],
+            strings: [const { String::new() }; 26],
+        }
     }
 }
 
-#[derive(Clone, Copy)]
-pub struct Program {
-    pub name: [u8; 16],
-    pub name_len: usize,
-    pub lines: [Line; MAX_LINES],
-    pub line_count: usize,
+impl Default for Variables {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl Program {
+/// Where a program is and what it's doing: the index of the current line,
+/// whether it's still running, the open FOR loops as
+/// (line index of the FOR, loop variable, end value), and a pending SLEEP
+/// in milliseconds.
+pub struct ExecState {
+    pub pc: usize,
+    pub running: bool,
+    pub for_stack: Vec<(usize, usize, i32)>,
+    pub sleep_ms: Option<u64>,
+}
+
+impl ExecState {
     pub const fn new() -> Self {
-        Program {
-            name: [0; 16],
-            name_len: 0,
-            lines: [Line::new(); MAX_LINES],
-            line_count: 0,
+        ExecState {
+            pc: 0,
+            running: false,
+            for_stack: Vec::new(),
+            sleep_ms: None,
         }
     }
 }
+
+impl Default for ExecState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,63 @@
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts;
+use crate::backtrace;
+use crate::vga_buffer::CrashScreen;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+/// Reports a kernel panic on a red crash screen and on COM1, then halts.
+///
+/// Takes none of the locks the rest of the kernel uses, since the panicking
+/// code may hold any of them.
+pub fn handle_panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    let rbp = backtrace::frame_pointer();
+
+    // A second panic while reporting the first one would only make things
+    // worse
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        halt();
+    }
+
+    // A port of our own instead of `SERIAL1`; initializing it again is
+    // harmless
+    let mut serial = crate::serial::raw_port();
+    serial.init();
+    let _ = report(&mut serial, info, rbp);
+
+    let mut screen = unsafe { CrashScreen::new() };
+    let _ = report(&mut screen, info, rbp);
+
+    halt();
+}
+
+fn report(out: &mut impl Write, info: &PanicInfo, rbp: u64) -> core::fmt::Result {
+    writeln!(out)?;
+    writeln!(out, "*** KERNEL PANIC ***")?;
+    writeln!(out)?;
+    writeln!(out, "{}", info.message())?;
+    writeln!(out)?;
+    match info.location() {
+        Some(location) => writeln!(
+            out,
+            "Location: {}:{}:{}",
+            location.file(),
+            location.line(),
+            location.column()
+        )?,
+        None => writeln!(out, "Location: unknown")?,
+    }
+    writeln!(out, "The system has been halted. Restart the machine to continue.")?;
+    // Last, so that a long backtrace only pushes itself off the screen
+    writeln!(out)?;
+    backtrace::write_backtrace(out, None, rbp)
+}
+
+fn halt() -> ! {
+    loop {
+        // Interrupts stay off; this only wakes up for an NMI
+        x86_64::instructions::hlt();
+    }
+}
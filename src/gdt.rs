@@ -0,0 +1,103 @@
+use alloc::boxed::Box;
+use alloc::vec;
+use x86_64::VirtAddr;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
+use lazy_static::lazy_static;
+
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+
+const STACK_SIZE: usize = 4096 * 5;
+
+// A plain static rather than lazy, because the kernel stack for user mode
+// (`rsp0`) is changed through `smp::PerCpu` while the TSS is loaded
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = {
+        // No allocator yet, so the double fault stack is a plain static array
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+        unsafe {
+            *core::ptr::addr_of_mut!(TSS) = new_tss(VirtAddr::from_ptr(core::ptr::addr_of!(STACK)));
+            new_gdt(&*core::ptr::addr_of!(TSS))
+        }
+    };
+}
+
+struct Selectors {
+    code_selector: SegmentSelector,
+    data_selector: SegmentSelector,
+    user_code_selector: SegmentSelector,
+    user_data_selector: SegmentSelector,
+    tss_selector: SegmentSelector,
+}
+
+fn new_tss(double_fault_stack: VirtAddr) -> TaskStateSegment {
+    let mut tss = TaskStateSegment::new();
+    // Stacks grow downwards, so the IST entry points at the end
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack + STACK_SIZE;
+    tss
+}
+
+fn new_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    // `sysret` expects user data right before user code
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    let selectors = Selectors {
+        code_selector,
+        data_selector,
+        user_code_selector,
+        user_data_selector,
+        tss_selector,
+    };
+    (gdt, selectors)
+}
+
+fn load(gdt: &'static (GlobalDescriptorTable, Selectors)) {
+    use x86_64::instructions::segmentation::{CS, SS, Segment};
+    use x86_64::instructions::tables::load_tss;
+
+    gdt.0.load();
+    unsafe {
+        CS::set_reg(gdt.1.code_selector);
+        // The bootloader's SS selector is meaningless in our GDT
+        SS::set_reg(gdt.1.data_selector);
+        load_tss(gdt.1.tss_selector);
+    }
+}
+
+/// Loads the GDT and TSS of the boot processor.
+pub fn init() {
+    load(&GDT);
+}
+
+/// Loads a new GDT and TSS, with a double fault stack of their own, on an
+/// application processor. A TSS can't be shared: loading it marks it busy.
+///
+/// Returns the new TSS, for `PerCpu::set_tss`.
+pub fn init_ap() -> *mut TaskStateSegment {
+    let stack = Box::leak(vec![0u8; STACK_SIZE].into_boxed_slice());
+    let tss: *mut TaskStateSegment = Box::into_raw(Box::new(new_tss(VirtAddr::from_ptr(stack.as_ptr()))));
+    load(Box::leak(Box::new(new_gdt(unsafe { &*tss }))));
+    tss
+}
+
+/// The TSS of the boot processor. Valid after `init`.
+pub(crate) fn bsp_tss() -> *mut TaskStateSegment {
+    core::ptr::addr_of_mut!(TSS)
+}
+
+/// Kernel code and data selectors. Every CPU's GDT has the same layout.
+pub fn kernel_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.code_selector, GDT.1.data_selector)
+}
+
+/// User code and data selectors, with privilege level 3.
+pub fn user_selectors() -> (SegmentSelector, SegmentSelector) {
+    (GDT.1.user_code_selector, GDT.1.user_data_selector)
+}
@@ -0,0 +1,97 @@
+/// The initial archive, built by `build.rs` from the programs in `user/`.
+static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+const BLOCK_SIZE: usize = 512;
+
+/// A file in the initial archive.
+#[derive(Debug, Clone, Copy)]
+pub struct File {
+    pub name: &'static str,
+    pub data: &'static [u8],
+}
+
+/// The files in the initial archive, in archive order.
+pub fn files() -> Files {
+    Files { archive: ARCHIVE, offset: 0 }
+}
+
+pub fn find(name: &str) -> Option<File> {
+    files().find(|file| file.name == name)
+}
+
+/// Iterator over the regular files of a ustar archive. Stops at the end
+/// marker or at the first header that doesn't make sense.
+pub struct Files {
+    archive: &'static [u8],
+    offset: usize,
+}
+
+impl Iterator for Files {
+    type Item = File;
+
+    fn next(&mut self) -> Option<File> {
+        loop {
+            let header = self.archive.get(self.offset..self.offset + BLOCK_SIZE)?;
+            if header.iter().all(|&b| b == 0) {
+                return None;
+            }
+
+            let name = field_str(&header[..100])?;
+            let size = parse_octal(&header[124..136])?;
+            let kind = header[156];
+            let start = self.offset + BLOCK_SIZE;
+            let data = self.archive.get(start..start + size)?;
+            self.offset = start + size.next_multiple_of(BLOCK_SIZE);
+
+            // Only regular files; directories and the like are skipped
+            if kind == b'0' || kind == 0 {
+                return Some(File { name, data });
+            }
+        }
+    }
+}
+
+/// A NUL-terminated (or full) text field.
+fn field_str(field: &[u8]) -> Option<&str> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).ok()
+}
+
+/// An octal number, padded with leading spaces or zeros and ended by a NUL
+/// or space.
+fn parse_octal(field: &[u8]) -> Option<usize> {
+    let digits = field_str(field)?.trim_matches(' ');
+    usize::from_str_radix(digits, 8).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_octal() {
+        assert_eq!(parse_octal(b"00000001750\0"), Some(1000));
+        assert_eq!(parse_octal(b"    17 \0"), Some(15));
+        assert_eq!(parse_octal(b"9\0"), None);
+    }
+
+    #[test_case]
+    fn test_archive_has_user_programs() {
+        let hello = find("hello").expect("hello missing from the initial archive");
+        assert_eq!(&hello.data[..4], b"\x7fELF");
+        assert!(files().count() >= 3);
+        assert!(find("nonexistent").is_none());
+    }
+
+    #[test_case]
+    fn test_stops_at_bad_header() {
+        static ARCHIVE: [u8; 1024] = {
+            let mut archive = [0; 1024];
+            archive[0] = b'x';
+            // A size that isn't octal
+            archive[124] = b'z';
+            archive
+        };
+        assert_eq!(Files { archive: &ARCHIVE, offset: 0 }.count(), 0);
+    }
+}
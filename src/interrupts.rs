@@ -1,17 +1,56 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, SelectorErrorCode,
+};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
 use lazy_static::lazy_static;
 use crate::println;
+use crate::backtrace;
+use crate::gdt;
 use crate::pic::{InterruptIndex, PICS};
-use spin;
+use crate::ps2::Input;
 
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
+        idt.divide_error.set_handler_fn(divide_error_handler);
+        idt.debug.set_handler_fn(debug_handler);
+        idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
         idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(overflow_handler);
+        idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt.device_not_available.set_handler_fn(device_not_available_handler);
+        unsafe {
+            idt.double_fault.set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+        idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+        idt.alignment_check.set_handler_fn(alignment_check_handler);
+        idt.machine_check.set_handler_fn(machine_check_handler);
+        idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+        idt.virtualization.set_handler_fn(virtualization_handler);
+        idt.cp_protection_exception.set_handler_fn(cp_protection_handler);
+        idt.hv_injection_exception.set_handler_fn(hv_injection_handler);
+        idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+        idt.security_exception.set_handler_fn(security_exception_handler);
         idt[InterruptIndex::Timer.as_usize()]
             .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
             .set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::SerialPort1.as_usize()]
+            .set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()]
+            .set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()]
+            .set_handler_fn(mouse_interrupt_handler);
+        idt[usize::from(crate::apic::SPURIOUS_VECTOR)]
+            .set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -20,67 +59,395 @@ pub fn init_idt() {
     IDT.load();
 }
 
+/// Loads a copy of the IDT on an application processor, so that CPUs can
+/// change their handlers independently.
+pub fn init_idt_ap() {
+    alloc::boxed::Box::leak(alloc::boxed::Box::new(IDT.clone())).load();
+}
+
+/// Acknowledges a hardware interrupt at whichever controller delivered it.
+fn end_of_interrupt(index: InterruptIndex) {
+    if crate::apic::is_enabled() {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(index.as_u8()) };
+    }
+}
+
+/// Prints the same line to the VGA screen and the serial port, through
+/// `ReportWriter`.
+macro_rules! report {
+    ($($arg:tt)*) => {{
+        let _ = core::fmt::Write::write_fmt(&mut ReportWriter, format_args!("{}\n", format_args!($($arg)*)));
+    }};
+}
+
+/// Writes to both the VGA screen and the serial port. The faulting code may
+/// hold `WRITER` or `SERIAL1`, and recovery only releases them after the
+/// report, so neither is waited for: the screen is skipped while `WRITER`
+/// is taken and serial output goes straight to the port, as in `crash`.
+struct ReportWriter;
+
+impl core::fmt::Write for ReportWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        if let Some(mut writer) = crate::vga_buffer::WRITER.try_lock() {
+            writer.write_string(s);
+        }
+        match crate::serial::SERIAL1.try_lock() {
+            Some(mut serial) => serial.write_str(s),
+            None => crate::serial::raw_port().write_str(s),
+        }
+    }
+}
+
+// Never inlined, so that its caller is always the exception handler
+#[inline(never)]
+fn report_exception(name: &str, stack_frame: &InterruptStackFrame) {
+    report!("EXCEPTION: {}", name);
+    report!("{:#?}", stack_frame);
+    report!("Flags: {:?}", RFlags::from_bits_truncate(stack_frame.cpu_flags));
+
+    // Our frame links to the handler's, which links to the interrupted code
+    let handler_rbp = unsafe { *(backtrace::frame_pointer() as *const u64) };
+    let interrupted_rbp = unsafe { *(handler_rbp as *const u64) };
+    let rip = stack_frame.instruction_pointer.as_u64();
+    let _ = backtrace::write_backtrace(&mut ReportWriter, Some(rip), interrupted_rbp);
+}
+
+fn report_selector_error(error_code: u64) {
+    report!("Error Code: {:#x}", error_code);
+    report!("{:?}", SelectorErrorCode::new_truncate(error_code));
+}
+
+/// Stack used to resume execution at the shell after a recoverable fault.
+/// The interrupted context is abandoned, so its own stack can't be trusted.
+const RECOVERY_STACK_SIZE: usize = 4096 * 5;
+
+#[repr(align(16))]
+struct RecoveryStack([u8; RECOVERY_STACK_SIZE]);
+
+static mut RECOVERY_STACK: RecoveryStack = RecoveryStack([0; RECOVERY_STACK_SIZE]);
+
+/// Rewrites the interrupt stack frame so that `iretq` lands in
+/// `shell_recovery` instead of re-executing the faulting instruction. A
+/// fault in a user program only ends that program.
+fn return_to_shell(stack_frame: &mut InterruptStackFrame) {
+    if is_user_mode(stack_frame) {
+        report!("Killing user program");
+        crate::usermode::kill_faulting(stack_frame);
+        return;
+    }
+
+    report!("Aborting current command, returning to carlsh");
+
+    let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(RECOVERY_STACK.0) });
+    // Enter as if called: the return address slot keeps the stack 16-byte aligned
+    let stack_pointer = stack_start + RECOVERY_STACK_SIZE - 8u64;
+
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::from_ptr(shell_recovery as *const ());
+            frame.stack_pointer = stack_pointer;
+            // Only the always-set reserved bit: interrupts stay off until
+            // the abandoned locks have been released
+            frame.cpu_flags = 0x2;
+        });
+    }
+}
+
+/// Entered through `iretq` after a recoverable fault. Whatever the faulting
+/// code held is released. A faulting kernel thread is ended; on the main
+/// thread the running BASIC program is stopped instead. The old
+/// executor and its tasks are abandoned (their memory is leaked) and a fresh
+/// one is started with a new shell task.
+extern "C" fn shell_recovery() -> ! {
+    // Taken with interrupts off, so if they are held, it is by the code
+    // that faulted, whichever thread that was
+    unsafe {
+        crate::vga_buffer::WRITER.force_unlock();
+        crate::serial::SERIAL1.force_unlock();
+        crate::keyboard_buffer::KEYBOARD_BUFFER.force_unlock();
+        PICS.force_unlock();
+        crate::thread::force_unlock();
+    }
+
+    // In case the fault hit an interrupt handler before its EOI
+    end_of_interrupt(InterruptIndex::Keyboard);
+
+    let thread = crate::thread::current();
+    if thread != crate::thread::MAIN_THREAD {
+        // Faults in system calls end the program that made them
+        crate::usermode::kill_current();
+        println!("Thread {} stopped", thread.as_u64());
+        crate::thread::exit();
+    }
+
+    // Plain locks of the executor's tasks, which only the main thread takes
+    unsafe {
+        crate::keyboard::KEYBOARD.force_unlock();
+        crate::mouse::MOUSE.force_unlock();
+        crate::task::SPAWN_QUEUE.force_unlock();
+        crate::BASIC.force_unlock();
+    }
+    crate::BASIC.lock().stop();
+    crate::task::SPAWN_QUEUE.lock().clear();
+    println!();
+
+    x86_64::instructions::interrupts::enable();
+    crate::run_tasks();
+}
+
+/// Whether the interrupted code ran in ring 3.
+fn is_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+fn halt() -> ! {
+    report!("System halted");
+    x86_64::instructions::interrupts::disable();
+    crate::hlt_loop();
+}
+
+extern "x86-interrupt" fn divide_error_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    report_exception("DIVIDE ERROR", &stack_frame);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn debug_handler(
+    stack_frame: InterruptStackFrame)
+{
+    report_exception("DEBUG", &stack_frame);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(
+    stack_frame: InterruptStackFrame)
+{
+    report_exception("NON-MASKABLE INTERRUPT", &stack_frame);
+}
+
 extern "x86-interrupt" fn breakpoint_handler(
     stack_frame: InterruptStackFrame)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    report_exception("BREAKPOINT", &stack_frame);
+}
+
+extern "x86-interrupt" fn overflow_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    report_exception("OVERFLOW", &stack_frame);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    report_exception("BOUND RANGE EXCEEDED", &stack_frame);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    report_exception("INVALID OPCODE", &stack_frame);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    report_exception("DEVICE NOT AVAILABLE", &stack_frame);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn double_fault_handler(
+    stack_frame: InterruptStackFrame, error_code: u64) -> !
+{
+    report_exception("DOUBLE FAULT", &stack_frame);
+    report!("Error Code: {:#x}", error_code);
+    halt();
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    report_exception("INVALID TSS", &stack_frame);
+    report_selector_error(error_code);
+    halt();
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    report_exception("SEGMENT NOT PRESENT", &stack_frame);
+    report_selector_error(error_code);
+    halt();
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(
+    mut stack_frame: InterruptStackFrame, error_code: u64)
+{
+    report_exception("STACK SEGMENT FAULT", &stack_frame);
+    report_selector_error(error_code);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(
+    mut stack_frame: InterruptStackFrame, error_code: u64)
+{
+    report_exception("GENERAL PROTECTION FAULT", &stack_frame);
+    report_selector_error(error_code);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode)
+{
+    use x86_64::registers::control::Cr2;
+
+    report_exception("PAGE FAULT", &stack_frame);
+    report!("Accessed Address: {:?}", Cr2::read());
+    report!("Error Code: {:?}", error_code);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    report_exception("x87 FLOATING POINT", &stack_frame);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(
+    mut stack_frame: InterruptStackFrame, error_code: u64)
+{
+    report_exception("ALIGNMENT CHECK", &stack_frame);
+    report!("Error Code: {:#x}", error_code);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn machine_check_handler(
+    stack_frame: InterruptStackFrame) -> !
+{
+    report_exception("MACHINE CHECK", &stack_frame);
+    halt();
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(
+    mut stack_frame: InterruptStackFrame)
+{
+    report_exception("SIMD FLOATING POINT", &stack_frame);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn virtualization_handler(
+    stack_frame: InterruptStackFrame)
+{
+    report_exception("VIRTUALIZATION", &stack_frame);
+    halt();
+}
+
+extern "x86-interrupt" fn cp_protection_handler(
+    mut stack_frame: InterruptStackFrame, error_code: u64)
+{
+    report_exception("CONTROL PROTECTION", &stack_frame);
+    report!("Error Code: {:#x}", error_code);
+    return_to_shell(&mut stack_frame);
+}
+
+extern "x86-interrupt" fn hv_injection_handler(
+    stack_frame: InterruptStackFrame)
+{
+    report_exception("HYPERVISOR INJECTION", &stack_frame);
+    halt();
+}
+
+extern "x86-interrupt" fn vmm_communication_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    report_exception("VMM COMMUNICATION", &stack_frame);
+    report!("Error Code: {:#x}", error_code);
+    halt();
+}
+
+extern "x86-interrupt" fn security_exception_handler(
+    stack_frame: InterruptStackFrame, error_code: u64)
+{
+    report_exception("SECURITY EXCEPTION", &stack_frame);
+    report!("Error Code: {:#x}", error_code);
+    halt();
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(
-    _stack_frame: InterruptStackFrame)
+    mut stack_frame: InterruptStackFrame)
 {
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+    // Switching threads needs the per-CPU data, so swap in the kernel's GS
+    // when a user program was interrupted, and back before returning to it
+    let from_user = is_user_mode(&stack_frame);
+    if from_user {
+        unsafe { x86_64::instructions::segmentation::GS::swap() };
+    }
+
+    end_of_interrupt(InterruptIndex::Timer);
+
+    crate::time::tick();
+    crate::thread::on_timer_tick();
+
+    if from_user {
+        // Back on the program's thread: a program killed while it loops in
+        // ring 3 ends here
+        crate::usermode::kill_if_killed(&mut stack_frame);
+        unsafe { x86_64::instructions::segmentation::GS::swap() };
     }
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(
     _stack_frame: InterruptStackFrame)
 {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1, KeyCode};
-    use x86_64::instructions::port::Port;
+    handle_ps2_input();
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
 
-    lazy_static! {
-        static ref KEYBOARD: spin::Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            spin::Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                HandleControl::Ignore
-            ));
-    }
+extern "x86-interrupt" fn mouse_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    handle_ps2_input();
+    end_of_interrupt(InterruptIndex::Mouse);
+}
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    // Add to keyboard buffer for INKEY()
-                    crate::keyboard_buffer::KEYBOARD_BUFFER.lock().push(character as u8);
-                    
-                    // Also send to shell
-                    crate::SHELL.lock().handle_key(character);
-                }
-                DecodedKey::RawKey(keycode) => {
-                    match keycode {
-                        KeyCode::ArrowUp => {
-                            crate::vga_buffer::scroll_up(1);
-                        }
-                        KeyCode::ArrowDown => {
-                            crate::vga_buffer::scroll_down(1);
-                        }
-                        _ => {}
-                    }
-                }
+/// The keyboard and the mouse share the controller's data port, so either
+/// IRQ takes whichever byte is waiting there to its driver.
+fn handle_ps2_input() {
+    match crate::ps2::read_input() {
+        Some(Input::Keyboard(scancode)) => {
+            if crate::keyboard::is_ctrl_alt_del(scancode) {
+                crate::power::reboot();
             }
+            crate::keyboard::add_scancode(scancode);
         }
+        Some(Input::Mouse(byte)) => crate::mouse::add_byte(byte),
+        None => {}
     }
+}
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+extern "x86-interrupt" fn serial_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    crate::serial::discard_input();
+    end_of_interrupt(InterruptIndex::SerialPort1);
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
+    crate::rtc::acknowledge_interrupt();
+    end_of_interrupt(InterruptIndex::Rtc);
+}
+
+/// The local APIC raises this instead of an interrupt that went away before
+/// it could be delivered. It must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(
+    _stack_frame: InterruptStackFrame)
+{
 }
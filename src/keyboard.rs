@@ -0,0 +1,330 @@
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use lazy_static::lazy_static;
+use pc_keyboard::layouts::{self, AnyLayout};
+use pc_keyboard::{
+    DecodedKey, HandleControl, KeyCode, Keyboard, KeyboardLayout, Modifiers, ScancodeSet1,
+};
+use spin::Mutex;
+use crate::ps2::Leds;
+
+const SCANCODE_QUEUE_SIZE: usize = 100;
+
+/// Scancodes read by the keyboard interrupt handler, waiting to be decoded.
+static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// The lock keys' state, shown on the keyboard's LEDs. Num Lock starts on,
+/// as in the decoder.
+static LOCK_LEDS: Mutex<Leds> = Mutex::new(Leds { scroll_lock: false, num_lock: true, caps_lock: false });
+/// Only used by the interrupt handler.
+static CTRL_ALT_DEL: Mutex<CtrlAltDel> = Mutex::new(CtrlAltDel::new());
+
+// Set 1 scancodes. Right Ctrl and Alt send the same codes after an 0xE0
+// prefix, and Delete shares its code with keypad '.'.
+const SCANCODE_CTRL: u8 = 0x1d;
+const SCANCODE_ALT: u8 = 0x38;
+const SCANCODE_DELETE: u8 = 0x53;
+const SCANCODE_RELEASED: u8 = 0x80;
+
+/// What Ctrl-C decodes to. Ctrl with any letter gives its ASCII control
+/// character.
+pub const CTRL_C: char = '\u{3}';
+
+lazy_static! {
+    pub(crate) static ref KEYBOARD: Mutex<Keyboard<ActiveLayout, ScancodeSet1>> =
+        Mutex::new(Keyboard::new(
+            ScancodeSet1::new(),
+            ActiveLayout,
+            HandleControl::MapLettersToUnicode
+        ));
+}
+
+/// A keyboard layout that `set_layout` can switch to.
+pub struct Layout {
+    pub name: &'static str,
+    pub description: &'static str,
+    layout: AnyLayout,
+}
+
+/// The layouts `pc-keyboard` ships, US first as the default.
+pub static LAYOUTS: [Layout; 8] = [
+    Layout {
+        name: "us",
+        description: "US 104-key",
+        layout: AnyLayout::Us104Key(layouts::Us104Key),
+    },
+    Layout {
+        name: "uk",
+        description: "UK 105-key",
+        layout: AnyLayout::Uk105Key(layouts::Uk105Key),
+    },
+    Layout {
+        name: "de",
+        description: "German 105-key (QWERTZ)",
+        layout: AnyLayout::De105Key(layouts::De105Key),
+    },
+    Layout {
+        name: "azerty",
+        description: "French AZERTY",
+        layout: AnyLayout::Azerty(layouts::Azerty),
+    },
+    Layout {
+        name: "dvorak",
+        description: "Dvorak 104-key",
+        layout: AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+    },
+    Layout {
+        name: "dvp",
+        description: "Programmer Dvorak 104-key",
+        layout: AnyLayout::DVP104Key(layouts::DVP104Key),
+    },
+    Layout {
+        name: "colemak",
+        description: "Colemak",
+        layout: AnyLayout::Colemak(layouts::Colemak),
+    },
+    Layout {
+        name: "jis",
+        description: "Japanese 109-key (JIS)",
+        layout: AnyLayout::Jis109Key(layouts::Jis109Key),
+    },
+];
+
+/// Index of the active layout in `LAYOUTS`.
+static ACTIVE_LAYOUT: AtomicUsize = AtomicUsize::new(0);
+
+/// Decodes keys with the layout chosen by `set_layout`. `Keyboard` takes
+/// its layout when it's created; this one looks it up on every key.
+pub(crate) struct ActiveLayout;
+
+impl KeyboardLayout for ActiveLayout {
+    fn map_keycode(
+        &self,
+        keycode: KeyCode,
+        modifiers: &Modifiers,
+        handle_ctrl: HandleControl,
+    ) -> DecodedKey {
+        layout().layout.map_keycode(keycode, modifiers, handle_ctrl)
+    }
+}
+
+/// Creates the scancode queue. Needs the heap, so call it after `init_heap`.
+pub fn init() {
+    SCANCODE_QUEUE
+        .try_init_once(|| ArrayQueue::new(SCANCODE_QUEUE_SIZE))
+        .expect("keyboard::init should only be called once");
+}
+
+/// Called by the keyboard interrupt handler.
+///
+/// Must not block or allocate. Scancodes arriving before `init` or while
+/// the queue is full are dropped.
+pub(crate) fn add_scancode(scancode: u8) {
+    if let Ok(queue) = SCANCODE_QUEUE.try_get() {
+        if queue.push(scancode).is_ok() {
+            WAKER.wake();
+        } else {
+            log::warn!("Scancode queue full, dropping input");
+        }
+    }
+}
+
+/// Called by the keyboard interrupt handler. Returns true when Delete is
+/// pressed while Ctrl and Alt are held.
+///
+/// Works on raw scancodes so it still reacts while nothing reads the queue.
+pub(crate) fn is_ctrl_alt_del(scancode: u8) -> bool {
+    CTRL_ALT_DEL.lock().update(scancode)
+}
+
+struct CtrlAltDel {
+    ctrl: bool,
+    alt: bool,
+}
+
+impl CtrlAltDel {
+    const fn new() -> Self {
+        CtrlAltDel {
+            ctrl: false,
+            alt: false,
+        }
+    }
+
+    fn update(&mut self, scancode: u8) -> bool {
+        let pressed = scancode & SCANCODE_RELEASED == 0;
+        match scancode & !SCANCODE_RELEASED {
+            SCANCODE_CTRL => self.ctrl = pressed,
+            SCANCODE_ALT => self.alt = pressed,
+            SCANCODE_DELETE => return pressed && self.ctrl && self.alt,
+            _ => {}
+        }
+        false
+    }
+}
+
+/// Async stream of the scancodes queued by the keyboard interrupt handler.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Default for ScancodeStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = SCANCODE_QUEUE
+            .try_get()
+            .expect("scancode queue not initialized");
+
+        // fast path
+        if let Some(scancode) = queue.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The layout keys are decoded with.
+pub fn layout() -> &'static Layout {
+    &LAYOUTS[ACTIVE_LAYOUT.load(Ordering::Relaxed)]
+}
+
+/// Decodes the following keys with the layout called `name`. Keys held
+/// down stay held. Returns `None` if there is no such layout.
+pub fn set_layout(name: &str) -> Option<&'static Layout> {
+    let index = LAYOUTS.iter().position(|layout| layout.name == name)?;
+    let layout = &LAYOUTS[index];
+    ACTIVE_LAYOUT.store(index, Ordering::Relaxed);
+    log::info!("Keyboard layout: {}", layout.name);
+    Some(layout)
+}
+
+/// The lock keys that are on.
+pub fn leds() -> Leds {
+    *LOCK_LEDS.lock()
+}
+
+fn toggle_lock(leds: &mut Leds, key: KeyCode) {
+    match key {
+        KeyCode::CapsLock => leds.caps_lock = !leds.caps_lock,
+        KeyCode::NumpadLock => leds.num_lock = !leds.num_lock,
+        KeyCode::ScrollLock => leds.scroll_lock = !leds.scroll_lock,
+        _ => {}
+    }
+}
+
+/// Decodes a scancode. Navigation keys are handled here; typed characters
+/// are returned to the caller.
+pub fn process_scancode(scancode: u8) -> Option<char> {
+    let key = {
+        let mut keyboard = KEYBOARD.lock();
+        match keyboard.add_byte(scancode) {
+            Ok(Some(key_event)) => keyboard.process_keyevent(key_event),
+            _ => None,
+        }
+    };
+
+    match key {
+        Some(DecodedKey::Unicode(character)) => Some(character),
+        Some(DecodedKey::RawKey(key @ (KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock))) => {
+            let leds = {
+                let mut leds = LOCK_LEDS.lock();
+                toggle_lock(&mut leds, key);
+                *leds
+            };
+            crate::ps2::set_leds(leds);
+            None
+        }
+        Some(DecodedKey::RawKey(KeyCode::ArrowUp)) => {
+            crate::vga_buffer::scroll_up(1);
+            None
+        }
+        Some(DecodedKey::RawKey(KeyCode::ArrowDown)) => {
+            crate::vga_buffer::scroll_down(1);
+            None
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_ctrl_alt_del() {
+        let mut keys = CtrlAltDel::new();
+        assert!(!keys.update(SCANCODE_DELETE));
+
+        keys.update(SCANCODE_CTRL);
+        keys.update(SCANCODE_ALT);
+        assert!(keys.update(SCANCODE_DELETE));
+        assert!(!keys.update(SCANCODE_DELETE | SCANCODE_RELEASED));
+    }
+
+    #[test_case]
+    fn test_set_layout() {
+        // The key right of T is Y on a US keyboard and Z on a German one
+        const SCANCODE_Y: u8 = 0x15;
+        assert_eq!(layout().name, "us");
+
+        set_layout("de").unwrap();
+        assert_eq!(layout().name, "de");
+        assert_eq!(process_scancode(SCANCODE_Y), Some('z'));
+        process_scancode(SCANCODE_Y | SCANCODE_RELEASED);
+
+        set_layout("us").unwrap();
+        assert_eq!(process_scancode(SCANCODE_Y), Some('y'));
+        process_scancode(SCANCODE_Y | SCANCODE_RELEASED);
+        assert!(set_layout("klingon").is_none());
+        assert_eq!(layout().name, "us");
+    }
+
+    #[test_case]
+    fn test_toggle_lock() {
+        let mut leds = Leds::default();
+        toggle_lock(&mut leds, KeyCode::CapsLock);
+        toggle_lock(&mut leds, KeyCode::ScrollLock);
+        toggle_lock(&mut leds, KeyCode::ScrollLock);
+        toggle_lock(&mut leds, KeyCode::A);
+        assert_eq!(leds, Leds { caps_lock: true, ..Leds::default() });
+    }
+
+    #[test_case]
+    fn test_ctrl_alt_del_needs_both_held() {
+        let mut keys = CtrlAltDel::new();
+        keys.update(SCANCODE_CTRL);
+        keys.update(SCANCODE_ALT);
+        keys.update(SCANCODE_ALT | SCANCODE_RELEASED);
+        assert!(!keys.update(SCANCODE_DELETE));
+
+        // Right Alt: 0xE0 prefix, then the same code
+        keys.update(0xe0);
+        keys.update(SCANCODE_ALT);
+        assert!(keys.update(SCANCODE_DELETE));
+    }
+}
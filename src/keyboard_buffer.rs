@@ -1,6 +1,6 @@
 // Add this file as src/keyboard_buffer.rs
 
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 use lazy_static::lazy_static;
 
 const BUFFER_SIZE: usize = 16;
@@ -40,7 +40,7 @@ impl KeyboardBuffer {
 }
 
 lazy_static! {
-    pub static ref KEYBOARD_BUFFER: Mutex<KeyboardBuffer> = Mutex::new(KeyboardBuffer::new());
+    pub static ref KEYBOARD_BUFFER: IrqSafeMutex<KeyboardBuffer> = IrqSafeMutex::named("KEYBOARD_BUFFER", KeyboardBuffer::new());
 }
 
 // Helper function for INKEY()
@@ -51,3 +51,49 @@ pub fn get_key() -> i32 {
         0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_pop_empty() {
+        let mut buffer = KeyboardBuffer::new();
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test_case]
+    fn test_push_pop_fifo_order() {
+        let mut buffer = KeyboardBuffer::new();
+        for key in b"abc" {
+            buffer.push(*key);
+        }
+        assert_eq!(buffer.pop(), Some(b'a'));
+        assert_eq!(buffer.pop(), Some(b'b'));
+        assert_eq!(buffer.pop(), Some(b'c'));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test_case]
+    fn test_full_buffer_drops_new_keys() {
+        let mut buffer = KeyboardBuffer::new();
+        // One slot stays free to tell a full buffer from an empty one
+        for key in 0..BUFFER_SIZE as u8 + 4 {
+            buffer.push(key);
+        }
+        for key in 0..BUFFER_SIZE as u8 - 1 {
+            assert_eq!(buffer.pop(), Some(key));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test_case]
+    fn test_wraps_around() {
+        let mut buffer = KeyboardBuffer::new();
+        for round in 0..3 * BUFFER_SIZE as u8 {
+            buffer.push(round);
+            assert_eq!(buffer.pop(), Some(round));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+}
@@ -0,0 +1,140 @@
+#![no_std]
+#![cfg_attr(test, no_main)]
+#![feature(custom_test_frameworks)]
+#![feature(abi_x86_interrupt)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use core::panic::PanicInfo;
+#[cfg(test)]
+use bootloader::{entry_point, BootInfo};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+pub mod vga_buffer;
+pub mod serial;
+pub mod gdt;
+pub mod memory;
+pub mod allocator;
+pub mod interrupts;
+pub mod pic;
+pub mod acpi;
+pub mod apic;
+pub mod power;
+pub mod crash;
+pub mod backtrace;
+pub mod rtc;
+pub mod shell;
+pub mod basic;
+pub mod keyboard;
+pub mod keyboard_buffer;
+pub mod ps2;
+pub mod mouse;
+pub mod task;
+pub mod thread;
+pub mod smp;
+pub mod usermode;
+pub mod initrd;
+pub mod time;
+pub mod sync;
+pub mod logger;
+
+lazy_static! {
+    /// A plain spin lock: only the executor's tasks use it, and it is held
+    /// while whole steps of a program run, which must not mask interrupts.
+    pub static ref BASIC: Mutex<basic::BasicInterpreter> = Mutex::new(basic::BasicInterpreter::new());
+}
+
+pub fn init() {
+    logger::init();
+    gdt::init();
+    interrupts::init_idt();
+    unsafe { pic::PICS.lock().initialize() };
+    time::init();
+    x86_64::instructions::interrupts::enable();
+    log::info!("Interrupts enabled, PIT at {} Hz", time::TICK_RATE);
+}
+
+/// Starts the shell task and runs the executor, sleeping until the next
+/// interrupt whenever no task is ready.
+pub fn run_tasks() -> ! {
+    let mut executor = task::executor::Executor::new();
+    executor.spawn(task::Task::new(shell::shell_task()));
+    executor.run();
+}
+
+pub fn hlt_loop() -> ! {
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+pub trait Testable {
+    fn run(&self);
+}
+
+impl<T> Testable for T
+where
+    T: Fn(),
+{
+    fn run(&self) {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+    exit_qemu(QemuExitCode::Success);
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    serial_println!("[failed]\n");
+    serial_println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed);
+    hlt_loop();
+}
+
+/// Exit codes understood by the `isa-debug-exit` device. QEMU reports
+/// `(code << 1) | 1`, so `Success` becomes 33 (see `test-success-exit-code`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11,
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) {
+    use x86_64::instructions::port::Port;
+
+    unsafe {
+        let mut port = Port::new(0xf4);
+        port.write(exit_code as u32);
+    }
+}
+
+#[cfg(test)]
+entry_point!(test_kernel_main);
+
+/// Entry point for `cargo test --lib`
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    init();
+    unsafe { memory::init(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    thread::init();
+    test_main();
+    hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    test_panic_handler(info)
+}
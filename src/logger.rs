@@ -0,0 +1,190 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use crate::serial_println;
+use crate::sync::IrqSafeMutex;
+use crate::time;
+
+/// Number of messages kept for `dmesg`. Older ones are dropped.
+const LOG_CAPACITY: usize = 256;
+/// Longer messages are cut off in the ring buffer, but not on serial.
+const MESSAGE_LENGTH: usize = 120;
+
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+static LOGGER: KernelLogger = KernelLogger;
+
+/// Writes log messages to `SERIAL1` and keeps them in a ring buffer.
+struct KernelLogger;
+
+/// A message in the ring buffer.
+#[derive(Clone, Copy)]
+pub struct LogEntry {
+    pub ticks: u64,
+    pub level: Level,
+    text: [u8; MESSAGE_LENGTH],
+    len: usize,
+}
+
+struct LogBuffer {
+    entries: [Option<LogEntry>; LOG_CAPACITY],
+    next: usize,
+}
+
+// Fixed-size and allocation-free, so it works before the heap is up and
+// from interrupt handlers. A plain static, since it is too big to be built
+// on a thread's stack first.
+static LOG_BUFFER: IrqSafeMutex<LogBuffer> = IrqSafeMutex::named("LOG_BUFFER", LogBuffer {
+    entries: [None; LOG_CAPACITY],
+    next: 0,
+});
+
+/// Installs the kernel logger. Messages logged before this are lost.
+pub fn init() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(DEFAULT_LEVEL);
+    }
+}
+
+/// Sets the most verbose level that is logged.
+pub fn set_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+pub fn level() -> LevelFilter {
+    log::max_level()
+}
+
+/// The buffered messages at `level` or more severe, oldest first.
+pub fn entries(level: LevelFilter) -> Vec<LogEntry> {
+    let buffer = LOG_BUFFER.lock();
+    (0..LOG_CAPACITY)
+        .filter_map(|i| buffer.entries[(buffer.next + i) % LOG_CAPACITY])
+        .filter(|entry| entry.level <= level)
+        .collect()
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let entry = LogEntry::new(time::ticks(), record);
+        serial_println!("{} {}", entry.header(), record.args());
+        LOG_BUFFER.lock().push(entry);
+    }
+
+    fn flush(&self) {}
+}
+
+impl LogEntry {
+    fn new(ticks: u64, record: &Record) -> Self {
+        let mut entry = LogEntry {
+            ticks,
+            level: record.level(),
+            text: [0; MESSAGE_LENGTH],
+            len: 0,
+        };
+        let _ = write!(entry, "{}: {}", record.target(), record.args());
+        entry
+    }
+
+    /// The target and message, possibly cut off.
+    pub fn text(&self) -> &str {
+        // Cutting off may have split a character
+        match core::str::from_utf8(&self.text[..self.len]) {
+            Ok(text) => text,
+            Err(error) => core::str::from_utf8(&self.text[..error.valid_up_to()]).unwrap(),
+        }
+    }
+
+    /// Timestamp and level, as in `[    1.234] INFO `.
+    pub fn header(&self) -> impl fmt::Display {
+        struct Header(u64, Level);
+
+        impl fmt::Display for Header {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                let ms = time::ticks_to_ms(self.0);
+                write!(f, "[{:5}.{:03}] {:<5}", ms / 1000, ms % 1000, self.1)
+            }
+        }
+
+        Header(self.ticks, self.level)
+    }
+}
+
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.header(), self.text())
+    }
+}
+
+impl Write for LogEntry {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(MESSAGE_LENGTH - self.len);
+        self.text[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+impl LogBuffer {
+    fn push(&mut self, entry: LogEntry) {
+        self.entries[self.next] = Some(entry);
+        self.next = (self.next + 1) % LOG_CAPACITY;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use alloc::string::ToString;
+
+    fn entry(level: Level, message: &str) -> LogEntry {
+        LogEntry::new(
+            1500,
+            &Record::builder()
+                .level(level)
+                .target("test")
+                .args(format_args!("{}", message))
+                .build(),
+        )
+    }
+
+    #[test_case]
+    fn test_entry_format() {
+        let entry = entry(Level::Warn, "disk on fire");
+        assert_eq!(entry.text(), "test: disk on fire");
+        assert!(entry.to_string().ends_with("] WARN  test: disk on fire"));
+    }
+
+    #[test_case]
+    fn test_long_message_is_cut_off() {
+        let message = format!("x{}", "é".repeat(MESSAGE_LENGTH));
+        let entry = entry(Level::Info, &message);
+        // "test: x" is 7 bytes, leaving an odd number for two-byte characters
+        assert_eq!(entry.text().len(), MESSAGE_LENGTH - 1);
+    }
+
+    #[test_case]
+    fn test_log_reaches_buffer_with_level_filter() {
+        let old = level();
+        set_level(LevelFilter::Debug);
+        log::debug!("logger test {}", 42);
+        log::trace!("logger test hidden");
+        set_level(old);
+
+        let debug = entries(LevelFilter::Debug);
+        let last = debug.last().unwrap();
+        assert_eq!(last.level, Level::Debug);
+        assert_eq!(last.text(), format!("{}: logger test 42", module_path!()));
+        assert!(entries(LevelFilter::Info).iter().all(|entry| entry.level <= Level::Info));
+        assert!(!debug.iter().any(|entry| entry.text().ends_with("hidden")));
+    }
+}
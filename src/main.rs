@@ -1,24 +1,14 @@
 #![no_std]
 #![no_main]
-#![feature(abi_x86_interrupt)]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 use core::panic::PanicInfo;
-use lazy_static::lazy_static;
-use spin::Mutex;
+use bootloader::{entry_point, BootInfo};
+use rust_kernel::{acpi, allocator, apic, keyboard, memory, mouse, println, ps2, smp, thread, usermode, vga_buffer};
 
-mod vga_buffer;
-mod serial;
-mod interrupts;
-mod pic;
-mod shell;
-mod basic;
-
-pub mod keyboard_buffer;
-
-lazy_static! {
-    pub static ref SHELL: Mutex<shell::Shell> = Mutex::new(shell::Shell::new());
-    pub static ref BASIC: Mutex<basic::BasicInterpreter> = Mutex::new(basic::BasicInterpreter::new());
-}
+entry_point!(kernel_main);
 
 fn print_logo() {
     println!(r"      /\_/\  ");
@@ -29,12 +19,11 @@ fn print_logo() {
     println!();
 }
 
-#[no_mangle]
-pub extern "C" fn _start() -> ! {
+fn kernel_main(boot_info: &'static BootInfo) -> ! {
     vga_buffer::clear_screen();
-    
+
     print_logo();
-    
+
     println!("   _____           _  ____   _____ ");
     println!("  / ____|         | |/ __ \\ / ____|");
     println!(" | |     __ _ _ __| | |  | | (___  ");
@@ -50,20 +39,33 @@ pub extern "C" fn _start() -> ! {
     println!("Type 'help' for commands or 'basic' for BASIC mode");
     println!();
 
-    interrupts::init_idt();
-    
-    unsafe { pic::PICS.lock().initialize() };
-    x86_64::instructions::interrupts::enable();
+    rust_kernel::init();
+    unsafe { memory::init(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    keyboard::init();
+    mouse::init();
+    ps2::init();
+    thread::init();
+    if acpi::init() {
+        apic::init();
+    }
+    smp::init();
+    usermode::init();
 
-    SHELL.lock().print_prompt();
+    #[cfg(test)]
+    test_main();
 
-    loop {
-        x86_64::instructions::hlt();
-    }
+    rust_kernel::run_tasks();
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::crash::handle_panic(info)
 }
 
+#[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    loop {}
+    rust_kernel::test_panic_handler(info)
 }
@@ -0,0 +1,512 @@
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use alloc::vec::Vec;
+use bootloader::BootInfo;
+use spin::{Mutex, MutexGuard, Once};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::{MapToError, TranslateResult};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// End of the memory that real mode can address.
+const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// Virtual addresses reserved for user programs: level 4 entries 192 to
+/// 255, which neither the bootloader nor the kernel use. Every
+/// `AddressSpace` has page tables of its own here.
+pub const USER_START: u64 = 0x0000_6000_0000_0000;
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+static BOOT_INFO: Once<&'static BootInfo> = Once::new();
+static KERNEL_PAGE_TABLE: Once<PhysFrame> = Once::new();
+static MAPPER: Once<Mutex<OffsetPageTable<'static>>> = Once::new();
+static FRAME_ALLOCATOR: Once<Mutex<BootInfoFrameAllocator>> = Once::new();
+
+/// Sets up paging on top of the complete physical memory mapping that the
+/// bootloader created at `boot_info.physical_memory_offset`.
+///
+/// # Safety
+///
+/// The bootloader must have mapped all physical memory at that offset, and
+/// this must only be called once to avoid aliasing `&mut` page tables.
+pub unsafe fn init(boot_info: &'static BootInfo) {
+    BOOT_INFO.call_once(|| boot_info);
+    KERNEL_PAGE_TABLE.call_once(|| Cr3::read().0);
+
+    let physical_memory_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let level_4_table = active_level_4_table(physical_memory_offset);
+    MAPPER.call_once(|| Mutex::new(OffsetPageTable::new(level_4_table, physical_memory_offset)));
+    FRAME_ALLOCATOR.call_once(|| Mutex::new(BootInfoFrameAllocator::init(&boot_info.memory_map)));
+}
+
+/// Returns a mutable reference to the active level 4 table.
+unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let phys = level_4_table_frame.start_address();
+    let virt = physical_memory_offset + phys.as_u64();
+    let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
+
+    &mut *page_table_ptr
+}
+
+pub fn boot_info() -> Option<&'static BootInfo> {
+    BOOT_INFO.wait().copied()
+}
+
+/// Virtual address at which the bootloader mapped physical address 0.
+pub fn physical_memory_offset() -> VirtAddr {
+    let boot_info = boot_info().expect("memory::init has not been called");
+    VirtAddr::new(boot_info.physical_memory_offset)
+}
+
+/// The level 4 table the kernel booted with, used by kernel threads.
+pub fn kernel_page_table() -> PhysFrame {
+    *KERNEL_PAGE_TABLE.wait().expect("memory::init has not been called")
+}
+
+/// The page table mapper for the kernel's address space.
+pub fn mapper() -> MutexGuard<'static, OffsetPageTable<'static>> {
+    MAPPER.wait().expect("memory::init has not been called").lock()
+}
+
+/// The physical frame allocator for all usable memory.
+pub fn frame_allocator() -> MutexGuard<'static, BootInfoFrameAllocator> {
+    FRAME_ALLOCATOR.wait().expect("memory::init has not been called").lock()
+}
+
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    mapper().translate_addr(addr)
+}
+
+/// Maps `page` to `frame` in the active address space, taking any frames
+/// needed for new page tables from `frame_allocator`.
+///
+/// # Safety
+///
+/// The caller must make sure that the frame isn't in use elsewhere, since
+/// mapping it a second time creates aliasing memory.
+pub unsafe fn map_page(
+    page: Page,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    mapper().map_to(page, frame, flags, frame_allocator)?.flush();
+    Ok(())
+}
+
+/// Maps the 4 KiB page at `virt` to the device registers at `phys`, with
+/// caching disabled.
+///
+/// # Safety
+///
+/// `phys` must be a memory-mapped device and `virt` an unused address.
+pub unsafe fn map_mmio(virt: VirtAddr, phys: PhysAddr) -> Result<(), MapToError<Size4KiB>> {
+    let page = Page::containing_address(virt);
+    let frame = PhysFrame::containing_address(phys);
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+
+    let mut frame_allocator = frame_allocator();
+    unsafe { map_page(page, frame, flags, &mut *frame_allocator) }
+}
+
+/// Checks that `len` bytes at `addr` are in the user region and mapped
+/// user-accessible in the active address space, so a system call can
+/// touch them for a user program.
+pub fn is_user_accessible(addr: VirtAddr, len: u64) -> bool {
+    let start = addr.as_u64();
+    let end = match start.checked_add(len) {
+        Some(end) if start >= USER_START && end <= USER_END => end,
+        _ => return false,
+    };
+    let pages = Page::<Size4KiB>::range_inclusive(
+        Page::containing_address(addr),
+        Page::containing_address(VirtAddr::new(end.max(start + 1) - 1)),
+    );
+    pages.into_iter().all(|page| is_user_page(page.start_address()))
+}
+
+/// Walks the active page tables, which all have to allow user access.
+fn is_user_page(addr: VirtAddr) -> bool {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let mut table = Cr3::read().0;
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()];
+    for (level, index) in indices.into_iter().enumerate() {
+        let entry = &unsafe { table_at(table) }[index];
+        if !entry.flags().contains(flags) {
+            return false;
+        }
+        // User pages are only ever mapped with 4 KiB pages
+        if level < 3 && entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return false;
+        }
+        table = PhysFrame::containing_address(entry.addr());
+    }
+    true
+}
+
+/// The page table in `frame`, through the physical memory mapping.
+unsafe fn table_at(frame: PhysFrame) -> &'static mut PageTable {
+    let virt = physical_memory_offset() + frame.start_address().as_u64();
+    unsafe { &mut *virt.as_mut_ptr() }
+}
+
+/// The page tables of a user program: the kernel's mappings plus pages of
+/// its own in the user region.
+///
+/// The level 4 entries outside the user region are copied from the
+/// kernel's table when the address space is created, so the kernel stays
+/// mapped (without user access) while the program runs.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+    /// User pages and the page tables below the user region, freed together
+    /// with the address space.
+    frames: Vec<PhysFrame>,
+}
+
+impl AddressSpace {
+    pub fn new() -> Result<Self, MapToError<Size4KiB>> {
+        let level_4_frame = frame_allocator()
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let table = unsafe { table_at(level_4_frame) };
+        table.zero();
+
+        let user = (USER_START >> 39) as usize..(USER_END >> 39) as usize;
+        let kernel = unsafe { table_at(kernel_page_table()) };
+        for (index, entry) in kernel.iter().enumerate() {
+            if !entry.is_unused() && !user.contains(&index) {
+                table[index].set_addr(entry.addr(), entry.flags());
+            }
+        }
+
+        Ok(AddressSpace { level_4_frame, frames: Vec::new() })
+    }
+
+    /// The level 4 table, for `Cr3`.
+    pub fn page_table(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Maps `page` to a fresh zeroed frame with user access and `flags`.
+    /// A page that is already mapped keeps its frame and gets the more
+    /// permissive combination of both, for segments that share a page.
+    pub fn map_user(&mut self, page: Page, flags: PageTableFlags) -> Result<(), MapToError<Size4KiB>> {
+        let address = page.start_address().as_u64();
+        assert!((USER_START..USER_END).contains(&address), "user page outside the user region");
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let mut mapper = unsafe { self.mapper() };
+        if let TranslateResult::Mapped { flags: old, .. } = mapper.translate(page.start_address()) {
+            let no_execute = old & flags & PageTableFlags::NO_EXECUTE;
+            let merged = ((old | flags) - PageTableFlags::NO_EXECUTE) | no_execute;
+            unsafe { mapper.update_flags(page, merged) }.expect("mapped page vanished").flush();
+            return Ok(());
+        }
+
+        let mut allocator = frame_allocator();
+        let frame = allocator.allocate_frame().ok_or(MapToError::FrameAllocationFailed)?;
+        self.frames.push(frame);
+        unsafe { table_at(frame) }.zero();
+
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let mut tables = RecordingAllocator { inner: &mut allocator, frames: &mut self.frames };
+        unsafe { mapper.map_to_with_table_flags(page, frame, flags, table_flags, &mut tables) }?
+            .flush();
+        Ok(())
+    }
+
+    /// Copies `bytes` to `addr` in this address space, which doesn't have
+    /// to be the active one. Returns false if part of the range isn't
+    /// mapped.
+    pub fn write(&mut self, mut addr: VirtAddr, mut bytes: &[u8]) -> bool {
+        let mapper = unsafe { self.mapper() };
+        while !bytes.is_empty() {
+            let Some(phys) = mapper.translate_addr(addr) else {
+                return false;
+            };
+            let count = bytes.len().min(4096 - usize::from(addr.page_offset()));
+            let target = (physical_memory_offset() + phys.as_u64()).as_mut_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(bytes.as_ptr(), target, count) };
+            addr += count as u64;
+            bytes = &bytes[count..];
+        }
+        true
+    }
+
+    /// A mapper for this address space's tables.
+    ///
+    /// # Safety
+    ///
+    /// Only one may exist at a time.
+    unsafe fn mapper(&self) -> OffsetPageTable<'static> {
+        unsafe { OffsetPageTable::new(table_at(self.level_4_frame), physical_memory_offset()) }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        debug_assert_ne!(Cr3::read().0, self.level_4_frame, "dropping the active address space");
+        let mut allocator = frame_allocator();
+        for frame in self.frames.drain(..).chain(Some(self.level_4_frame)) {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+    }
+}
+
+/// Hands out frames for new page tables and remembers them.
+struct RecordingAllocator<'a> {
+    inner: &'a mut BootInfoFrameAllocator,
+    frames: &'a mut Vec<PhysFrame>,
+}
+
+unsafe impl FrameAllocator<Size4KiB> for RecordingAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.inner.allocate_frame()?;
+        self.frames.push(frame);
+        Some(frame)
+    }
+}
+
+/// Frame allocator that hands out the usable regions of the bootloader's
+/// memory map in order and reuses frames after they are deallocated.
+///
+/// Freed frames form a linked list: the first eight bytes of each free frame
+/// hold the physical address of the next one (0 ends the list, frame zero is
+/// never usable). The frames are accessed through the physical memory mapping.
+pub struct BootInfoFrameAllocator {
+    memory_map: &'static MemoryMap,
+    low_frame: Option<PhysFrame>,
+    region: usize,
+    next: u64,
+    free_list: Option<PhysFrame>,
+    free_count: usize,
+    total_frames: usize,
+    allocated_frames: usize,
+}
+
+impl BootInfoFrameAllocator {
+    /// Create a FrameAllocator from the passed memory map.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that all frames marked as `USABLE` in the
+    /// memory map are really unused.
+    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let total_frames = memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| (r.range.end_frame_number - r.range.start_frame_number) as usize)
+            .sum();
+
+        let mut allocator = BootInfoFrameAllocator {
+            memory_map,
+            low_frame: None,
+            region: 0,
+            next: 0,
+            free_list: None,
+            free_count: 0,
+            total_frames,
+            allocated_frames: 0,
+        };
+        allocator.enter_region(0);
+
+        // Keep the first frame for `take_low_frame` if it is below 1 MiB,
+        // since nothing else will ever give it back
+        if allocator.next + 4096 <= LOW_MEMORY_END {
+            allocator.low_frame = allocator.next_unused_frame();
+            allocator.allocated_frames += 1;
+        }
+        allocator
+    }
+
+    /// Hands out the frame set aside below 1 MiB, for code that a
+    /// processor runs in real mode. `None` if it is taken or there was none.
+    pub fn take_low_frame(&mut self) -> Option<PhysFrame> {
+        self.low_frame.take()
+    }
+
+    /// Moves the bump pointer to the first usable region at or after `index`.
+    fn enter_region(&mut self, index: usize) {
+        self.region = index;
+        while let Some(region) = self.memory_map.get(self.region) {
+            if region.region_type == MemoryRegionType::Usable {
+                self.next = region.range.start_addr();
+                return;
+            }
+            self.region += 1;
+        }
+    }
+
+    /// Returns the next frame that has never been handed out before.
+    fn next_unused_frame(&mut self) -> Option<PhysFrame> {
+        loop {
+            let region = self.memory_map.get(self.region)?;
+            if self.next < region.range.end_addr() {
+                let frame = PhysFrame::containing_address(PhysAddr::new(self.next));
+                self.next += frame.size();
+                return Some(frame);
+            }
+            self.enter_region(self.region + 1);
+        }
+    }
+
+    fn pop_free_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.free_list?;
+        let link: *const u64 = (physical_memory_offset() + frame.start_address().as_u64()).as_ptr();
+        let next = unsafe { link.read() };
+        self.free_list = match next {
+            0 => None,
+            addr => Some(PhysFrame::containing_address(PhysAddr::new(addr))),
+        };
+        self.free_count -= 1;
+        Some(frame)
+    }
+
+    /// Number of usable frames in the memory map.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Number of frames currently handed out.
+    pub fn allocated_frames(&self) -> usize {
+        self.allocated_frames
+    }
+
+    /// Number of frames that were freed and are waiting to be reused.
+    pub fn reusable_frames(&self) -> usize {
+        self.free_count
+    }
+
+    /// Number of usable frames not currently handed out.
+    pub fn free_frames(&self) -> usize {
+        self.total_frames - self.allocated_frames
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        let frame = self.pop_free_frame().or_else(|| self.next_unused_frame())?;
+        self.allocated_frames += 1;
+        Some(frame)
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let link: *mut u64 = (physical_memory_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        let next = self.free_list.map_or(0, |f| f.start_address().as_u64());
+        link.write(next);
+
+        self.free_list = Some(frame);
+        self.free_count += 1;
+        self.allocated_frames -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_translate_vga_buffer_is_identity_mapped() {
+        let addr = VirtAddr::new(0xb8000);
+        assert_eq!(translate(addr), Some(PhysAddr::new(0xb8000)));
+    }
+
+    #[test_case]
+    fn test_translate_physical_memory_offset() {
+        let addr = physical_memory_offset() + 0x1234u64;
+        assert_eq!(translate(addr), Some(PhysAddr::new(0x1234)));
+    }
+
+    #[test_case]
+    fn test_translate_unmapped_address() {
+        assert_eq!(translate(VirtAddr::new(0xdead_beef_0000)), None);
+    }
+
+    fn is_usable(frame: PhysFrame) -> bool {
+        let addr = frame.start_address().as_u64();
+        boot_info().unwrap().memory_map.iter().any(|r| {
+            r.region_type == MemoryRegionType::Usable
+                && r.range.start_addr() <= addr
+                && addr < r.range.end_addr()
+        })
+    }
+
+    #[test_case]
+    fn test_allocate_frame_from_usable_region() {
+        let mut allocator = frame_allocator();
+        let frame = allocator.allocate_frame().expect("out of frames");
+        assert!(is_usable(frame));
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn test_allocated_frames_are_distinct() {
+        let mut allocator = frame_allocator();
+        let first = allocator.allocate_frame().expect("out of frames");
+        let second = allocator.allocate_frame().expect("out of frames");
+        assert_ne!(first, second);
+        unsafe {
+            allocator.deallocate_frame(second);
+            allocator.deallocate_frame(first);
+        }
+    }
+
+    #[test_case]
+    fn test_freed_frame_is_reused() {
+        let mut allocator = frame_allocator();
+        let frame = allocator.allocate_frame().expect("out of frames");
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.allocate_frame(), Some(frame));
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+
+    #[test_case]
+    fn test_address_space_maps_user_pages() {
+        let allocated = frame_allocator().allocated_frames();
+        let mut address_space = AddressSpace::new().expect("out of frames");
+        let page = Page::containing_address(VirtAddr::new(USER_START));
+        address_space.map_user(page, PageTableFlags::WRITABLE).unwrap();
+        assert!(address_space.write(page.start_address() + 4090u64, b"spans"));
+        assert!(!address_space.write(page.start_address() + 4090u64, b"too far"));
+
+        // Not mapped for the kernel, which has no tables in the user region
+        assert_eq!(translate(page.start_address()), None);
+        assert!(!is_user_accessible(page.start_address(), 1));
+
+        // The page and three page tables, freed with the level 4 table
+        assert_eq!(frame_allocator().allocated_frames(), allocated + 5);
+        drop(address_space);
+        assert_eq!(frame_allocator().allocated_frames(), allocated);
+    }
+
+    #[test_case]
+    fn test_kernel_memory_is_not_user_accessible() {
+        assert!(!is_user_accessible(VirtAddr::new(0xb8000), 1));
+        assert!(!is_user_accessible(VirtAddr::new(USER_END - 1), 2));
+    }
+
+    #[test_case]
+    fn test_frame_counts() {
+        let mut allocator = frame_allocator();
+        let allocated = allocator.allocated_frames();
+        let free = allocator.free_frames();
+        assert_eq!(allocated + free, allocator.total_frames());
+
+        let frame = allocator.allocate_frame().expect("out of frames");
+        assert_eq!(allocator.allocated_frames(), allocated + 1);
+        assert_eq!(allocator.free_frames(), free - 1);
+
+        unsafe { allocator.deallocate_frame(frame) };
+        assert_eq!(allocator.allocated_frames(), allocated);
+        assert!(allocator.reusable_frames() > 0);
+    }
+}
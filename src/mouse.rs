@@ -0,0 +1,305 @@
+use alloc::string::String;
+use conquer_once::spin::OnceCell;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+use spin::Mutex;
+use crate::vga_buffer::{self, Position, BUFFER_HEIGHT, BUFFER_WIDTH};
+
+const BYTE_QUEUE_SIZE: usize = 256;
+
+/// Packet bytes read by the mouse interrupt handler, waiting to be decoded.
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// Whether packets have a fourth byte for the wheel. Set by `ps2::init`.
+static WHEEL: AtomicBool = AtomicBool::new(false);
+
+pub(crate) static MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+
+// First packet byte
+const LEFT_BUTTON: u8 = 0x01;
+const RIGHT_BUTTON: u8 = 0x02;
+const MIDDLE_BUTTON: u8 = 0x04;
+/// Always set in the first byte, which is how lost bytes are noticed.
+const ALWAYS_SET: u8 = 0x08;
+const X_SIGN: u8 = 0x10;
+const Y_SIGN: u8 = 0x20;
+const X_OVERFLOW: u8 = 0x40;
+const Y_OVERFLOW: u8 = 0x80;
+
+/// Movement counts per text cell. At 4 counts per millimetre a column is
+/// 2 mm of hand movement and a row 4 mm.
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+/// Lines scrolled per notch of the wheel.
+const WHEEL_LINES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Buttons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+/// One movement report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Packet {
+    /// Rightwards.
+    pub dx: i16,
+    /// Upwards, as the mouse counts it.
+    pub dy: i16,
+    /// Notches towards the user, negative away from them.
+    pub wheel: i8,
+    pub buttons: Buttons,
+}
+
+impl Packet {
+    fn from_bytes(bytes: &[u8; 4], wheel: bool) -> Self {
+        let flags = bytes[0];
+        let axis = |byte: u8, sign: u8, overflow: u8| {
+            // An overflowed count is garbage; drop it rather than jump
+            if flags & overflow != 0 {
+                0
+            } else if flags & sign != 0 {
+                i16::from(byte) - 0x100
+            } else {
+                i16::from(byte)
+            }
+        };
+        Packet {
+            dx: axis(bytes[1], X_SIGN, X_OVERFLOW),
+            dy: axis(bytes[2], Y_SIGN, Y_OVERFLOW),
+            // A 4-bit signed count; the high bits are extra buttons on
+            // 5-button mice
+            wheel: if wheel { ((bytes[3] << 4) as i8) >> 4 } else { 0 },
+            buttons: Buttons {
+                left: flags & LEFT_BUTTON != 0,
+                right: flags & RIGHT_BUTTON != 0,
+                middle: flags & MIDDLE_BUTTON != 0,
+            },
+        }
+    }
+}
+
+/// Puts the bytes of a packet back together.
+pub struct PacketDecoder {
+    bytes: [u8; 4],
+    len: usize,
+}
+
+impl PacketDecoder {
+    pub const fn new() -> Self {
+        PacketDecoder { bytes: [0; 4], len: 0 }
+    }
+
+    /// Returns the packet `byte` completes. Packets are 4 bytes with a
+    /// wheel and 3 without.
+    pub fn add_byte(&mut self, byte: u8, wheel: bool) -> Option<Packet> {
+        if self.len == 0 && byte & ALWAYS_SET == 0 {
+            // Not a first byte: skip until the stream is in step again
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < if wheel { 4 } else { 3 } {
+            return None;
+        }
+        self.len = 0;
+        Some(Packet::from_bytes(&self.bytes, wheel))
+    }
+}
+
+impl Default for PacketDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The pointer and the selection made with it.
+pub(crate) struct Mouse {
+    decoder: PacketDecoder,
+    /// The pointer position in counts, so that slow movement adds up.
+    x: i32,
+    y: i32,
+    /// The cell the pointer was last drawn at.
+    pointer: Option<Position>,
+    buttons: Buttons,
+    /// Where the left button went down, while it's held.
+    anchor: Option<Position>,
+    /// The text last selected, pasted with the right or middle button.
+    clipboard: String,
+}
+
+impl Mouse {
+    const fn new() -> Self {
+        Mouse {
+            decoder: PacketDecoder::new(),
+            x: BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN / 2,
+            y: BUFFER_HEIGHT as i32 * COUNTS_PER_ROW / 2,
+            pointer: None,
+            buttons: Buttons { left: false, right: false, middle: false },
+            anchor: None,
+            clipboard: String::new(),
+        }
+    }
+
+    fn handle(&mut self, packet: Packet) -> Option<String> {
+        self.x = (self.x + i32::from(packet.dx)).clamp(0, BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN - 1);
+        self.y = (self.y - i32::from(packet.dy)).clamp(0, BUFFER_HEIGHT as i32 * COUNTS_PER_ROW - 1);
+        let pointer = ((self.y / COUNTS_PER_ROW) as usize, (self.x / COUNTS_PER_COLUMN) as usize);
+        let moved = self.pointer != Some(pointer);
+        if moved {
+            self.pointer = Some(pointer);
+            vga_buffer::set_pointer(self.pointer);
+        }
+
+        match packet.wheel {
+            0 => {}
+            notches if notches > 0 => vga_buffer::scroll_down(WHEEL_LINES * usize::from(notches.unsigned_abs())),
+            notches => vga_buffer::scroll_up(WHEEL_LINES * usize::from(notches.unsigned_abs())),
+        }
+
+        let (before, now) = (self.buttons, packet.buttons);
+        self.buttons = now;
+        if now.left && !before.left {
+            self.anchor = Some(pointer);
+            vga_buffer::set_selection(None);
+        } else if now.left && moved {
+            // Dragging. The anchor is gone if the wheel scrolled meanwhile.
+            if let Some(anchor) = self.anchor {
+                vga_buffer::set_selection(Some((anchor, pointer)));
+            }
+        } else if !now.left && before.left {
+            self.anchor = None;
+            let text = vga_buffer::selected_text();
+            if !text.is_empty() {
+                self.clipboard = text;
+            }
+        }
+        if packet.wheel != 0 {
+            self.anchor = None;
+        }
+
+        let paste = (now.right && !before.right) || (now.middle && !before.middle);
+        (paste && !self.clipboard.is_empty()).then(|| self.clipboard.clone())
+    }
+}
+
+/// Creates the byte queue. Needs the heap, so call it after `init_heap`.
+pub fn init() {
+    BYTE_QUEUE
+        .try_init_once(|| ArrayQueue::new(BYTE_QUEUE_SIZE))
+        .expect("mouse::init should only be called once");
+}
+
+/// Tells the decoder whether packets carry the wheel movement.
+pub(crate) fn set_wheel(wheel: bool) {
+    WHEEL.store(wheel, Ordering::Relaxed);
+}
+
+/// Called by the mouse interrupt handler.
+///
+/// Must not block or allocate. Bytes arriving before `init` or while the
+/// queue is full are dropped; the decoder finds the next packet again.
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        } else {
+            log::warn!("Mouse queue full, dropping input");
+        }
+    }
+}
+
+/// Async stream of the bytes queued by the mouse interrupt handler.
+pub struct MouseStream {
+    _private: (),
+}
+
+impl MouseStream {
+    pub fn new() -> Self {
+        MouseStream { _private: () }
+    }
+}
+
+impl Default for MouseStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        let queue = BYTE_QUEUE
+            .try_get()
+            .expect("mouse queue not initialized");
+
+        // fast path
+        if let Some(byte) = queue.pop() {
+            return Poll::Ready(Some(byte));
+        }
+
+        WAKER.register(cx.waker());
+        match queue.pop() {
+            Some(byte) => {
+                WAKER.take();
+                Poll::Ready(Some(byte))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Moves the pointer, scrolls with the wheel and selects text while the
+/// left button is dragged. Returns the selected text when the right or
+/// middle button is clicked, for the caller to paste.
+pub fn process_byte(byte: u8) -> Option<String> {
+    let mut mouse = MOUSE.lock();
+    let packet = mouse.decoder.add_byte(byte, WHEEL.load(Ordering::Relaxed))?;
+    mouse.handle(packet)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_decode_packet() {
+        let mut decoder = PacketDecoder::new();
+        // Left button, 5 right and 3 down
+        assert_eq!(decoder.add_byte(ALWAYS_SET | LEFT_BUTTON | Y_SIGN, false), None);
+        assert_eq!(decoder.add_byte(5, false), None);
+        let packet = decoder.add_byte(0xfd, false).expect("three bytes make a packet");
+        assert_eq!(packet.dx, 5);
+        assert_eq!(packet.dy, -3);
+        assert_eq!(packet.wheel, 0);
+        assert_eq!(packet.buttons, Buttons { left: true, ..Buttons::default() });
+    }
+
+    #[test_case]
+    fn test_decode_wheel_packet() {
+        let mut decoder = PacketDecoder::new();
+        for byte in [ALWAYS_SET | X_OVERFLOW, 0x12, 0x00] {
+            assert_eq!(decoder.add_byte(byte, true), None);
+        }
+        // One notch away from the user, with a 5th button's bit above it
+        let packet = decoder.add_byte(0x2f, true).expect("four bytes make a packet");
+        assert_eq!(packet.dx, 0);
+        assert_eq!(packet.wheel, -1);
+    }
+
+    #[test_case]
+    fn test_decoder_resyncs() {
+        let mut decoder = PacketDecoder::new();
+        // A stray movement byte without the always-set bit is skipped
+        assert_eq!(decoder.add_byte(0x12, false), None);
+        assert_eq!(decoder.add_byte(ALWAYS_SET, false), None);
+        assert_eq!(decoder.add_byte(1, false), None);
+        assert!(decoder.add_byte(1, false).is_some());
+    }
+}
@@ -1,5 +1,4 @@
 use pic8259::ChainedPics;
-use spin;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
@@ -7,14 +6,24 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Vectors of the hardware interrupts. They stay the same when the APICs
+/// take over, so the IDT doesn't change.
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    SerialPort1 = PIC_1_OFFSET + 4,
+    Rtc = PIC_2_OFFSET,
+    Mouse = PIC_2_OFFSET + 4,
 }
 
 impl InterruptIndex {
+    /// The legacy ISA IRQ line.
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
+
     pub fn as_u8(self) -> u8 {
         self as u8
     }
@@ -23,3 +32,19 @@ impl InterruptIndex {
         usize::from(self.as_u8())
     }
 }
+
+/// Lets IRQ `index` through the PICs, which `initialize` leaves masked the
+/// way the BIOS had them. Lines on the secondary PIC also need the cascade
+/// line 2 open on the primary.
+pub fn unmask(index: InterruptIndex) {
+    let mut pics = PICS.lock();
+    let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+    let irq = index.irq();
+    if irq < 8 {
+        primary &= !(1 << irq);
+    } else {
+        primary &= !(1 << 2);
+        secondary &= !(1 << (irq - 8));
+    }
+    unsafe { pics.write_masks(primary, secondary) };
+}
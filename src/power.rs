@@ -0,0 +1,212 @@
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use crate::acpi;
+use crate::println;
+
+const KBC_STATUS: u16 = 0x64;
+const KBC_COMMAND: u16 = 0x64;
+/// Status bit: the controller hasn't read the last command yet.
+const KBC_INPUT_FULL: u8 = 0x02;
+/// Pulses the CPU reset line.
+const KBC_RESET: u8 = 0xfe;
+
+// FADT field offsets
+const FADT_DSDT: usize = 40;
+const FADT_SMI_COMMAND: usize = 48;
+const FADT_ACPI_ENABLE: usize = 52;
+const FADT_PM1A_CONTROL: usize = 64;
+const FADT_PM1B_CONTROL: usize = 68;
+const FADT_X_DSDT: usize = 140;
+
+/// PM1 control bit: the chipset is in ACPI mode.
+const SCI_ENABLE: u16 = 1 << 0;
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_ENABLE: u16 = 1 << 13;
+
+// AML opcodes around the `_S5_` package
+const AML_NAME_OP: u8 = 0x08;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+
+/// Shutdown ports of emulators without ACPI S5, with the value to write:
+/// QEMU, Bochs and older QEMU, VirtualBox.
+const EMULATOR_SHUTDOWN_PORTS: [(u16, u16); 3] = [(0x604, 0x2000), (0xb004, 0x2000), (0x4004, 0x3400)];
+
+/// Resets the machine through the keyboard controller, or with a triple
+/// fault if that doesn't work.
+pub fn reboot() -> ! {
+    interrupts::disable();
+
+    let mut status = Port::<u8>::new(KBC_STATUS);
+    let mut command = Port::<u8>::new(KBC_COMMAND);
+    unsafe {
+        while status.read() & KBC_INPUT_FULL != 0 {}
+        command.write(KBC_RESET);
+    }
+
+    // Give the reset a moment, then fault with no usable IDT
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+    triple_fault();
+}
+
+fn triple_fault() -> ! {
+    use x86_64::instructions::tables::lidt;
+    use x86_64::structures::DescriptorTablePointer;
+    use x86_64::VirtAddr;
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty);
+        core::arch::asm!("int3");
+    }
+    crate::hlt_loop();
+}
+
+/// Turns the machine off through ACPI sleep state S5, falling back to the
+/// shutdown ports of common emulators. Halts if nothing worked.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+
+    if let Some(fadt) = fadt() {
+        unsafe { acpi_shutdown(fadt) };
+    }
+
+    for (port, value) in EMULATOR_SHUTDOWN_PORTS {
+        unsafe { Port::<u16>::new(port).write(value) };
+    }
+
+    println!("It is now safe to turn off your computer.");
+    crate::hlt_loop();
+}
+
+fn fadt() -> Option<&'static [u8]> {
+    let address = acpi::get()?.find_table(b"FACP")?;
+    unsafe { acpi::table_bytes(address) }
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+unsafe fn acpi_shutdown(fadt: &[u8]) {
+    let dsdt = read_u64(fadt, FADT_X_DSDT)
+        .filter(|&address| address != 0)
+        .or_else(|| read_u32(fadt, FADT_DSDT).map(u64::from));
+    let sleep_types = dsdt
+        .and_then(|address| unsafe { acpi::table_bytes(address) })
+        .and_then(find_s5);
+    let (sleep_type_a, sleep_type_b) = match sleep_types {
+        Some(sleep_types) => sleep_types,
+        None => return,
+    };
+
+    let pm1a = read_u32(fadt, FADT_PM1A_CONTROL).unwrap_or(0) as u16;
+    let pm1b = read_u32(fadt, FADT_PM1B_CONTROL).unwrap_or(0) as u16;
+    if pm1a == 0 {
+        return;
+    }
+
+    unsafe {
+        enable_acpi_mode(fadt, pm1a);
+
+        Port::<u16>::new(pm1a).write(u16::from(sleep_type_a) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+        if pm1b != 0 {
+            Port::<u16>::new(pm1b).write(u16::from(sleep_type_b) << SLEEP_TYPE_SHIFT | SLEEP_ENABLE);
+        }
+    }
+}
+
+/// Asks the firmware to hand power management over to the OS, if it
+/// hasn't already.
+unsafe fn enable_acpi_mode(fadt: &[u8], pm1a: u16) {
+    let mut control = Port::<u16>::new(pm1a);
+    if unsafe { control.read() } & SCI_ENABLE != 0 {
+        return;
+    }
+
+    let smi_command = read_u32(fadt, FADT_SMI_COMMAND).unwrap_or(0) as u16;
+    let acpi_enable = fadt.get(FADT_ACPI_ENABLE).copied().unwrap_or(0);
+    if smi_command == 0 || acpi_enable == 0 {
+        return;
+    }
+
+    unsafe {
+        Port::<u8>::new(smi_command).write(acpi_enable);
+        for _ in 0..1_000_000 {
+            if control.read() & SCI_ENABLE != 0 {
+                break;
+            }
+        }
+    }
+}
+
+/// Finds the `_S5_` package in the DSDT's AML and returns its first two
+/// elements, the SLP_TYPa and SLP_TYPb values for PM1a and PM1b.
+fn find_s5(dsdt: &[u8]) -> Option<(u8, u8)> {
+    let position = dsdt.windows(4).position(|window| window == b"_S5_")?;
+
+    // Must be the name of a Name(), optionally as a root path
+    let is_name = (position >= 1 && dsdt[position - 1] == AML_NAME_OP)
+        || (position >= 2 && dsdt[position - 2] == AML_NAME_OP && dsdt[position - 1] == b'\\');
+    if !is_name {
+        return None;
+    }
+
+    let mut bytes = dsdt.get(position + 4..)?.iter().copied();
+    if bytes.next()? != AML_PACKAGE_OP {
+        return None;
+    }
+
+    // PkgLength: the top two bits of the first byte count the extra bytes
+    let lead = bytes.next()?;
+    for _ in 0..(lead >> 6) {
+        bytes.next()?;
+    }
+    let _element_count = bytes.next()?;
+
+    let mut element = || -> Option<u8> {
+        match bytes.next()? {
+            AML_BYTE_PREFIX => bytes.next(),
+            value => Some(value),
+        }
+    };
+    let sleep_type_a = element()?;
+    let sleep_type_b = element()?;
+    Some((sleep_type_a, sleep_type_b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_find_s5_byte_prefixed() {
+        // Name(_S5_, Package(4) { 5, 5, 0, 0 })
+        let aml = [0x10, AML_NAME_OP, b'_', b'S', b'5', b'_', AML_PACKAGE_OP, 0x0a, 0x04,
+            AML_BYTE_PREFIX, 0x05, AML_BYTE_PREFIX, 0x05, 0x00, 0x00];
+        assert_eq!(find_s5(&aml), Some((5, 5)));
+    }
+
+    #[test_case]
+    fn test_find_s5_zero_ops_and_root_path() {
+        // Name(\\_S5_, Package(2) { Zero, Zero }) with a two-byte PkgLength
+        let aml = [AML_NAME_OP, b'\\', b'_', b'S', b'5', b'_', AML_PACKAGE_OP, 0x45, 0x00, 0x02,
+            0x00, 0x00];
+        assert_eq!(find_s5(&aml), Some((0, 0)));
+    }
+
+    #[test_case]
+    fn test_find_s5_missing_or_not_a_name() {
+        assert_eq!(find_s5(b"no sleep states here"), None);
+        assert_eq!(find_s5(&[0x00, b'_', b'S', b'5', b'_', AML_PACKAGE_OP, 0x04, 0x02, 0x05, 0x05]), None);
+    }
+}
@@ -0,0 +1,595 @@
+use core::fmt;
+use x86_64::instructions::port::Port;
+use crate::sync::IrqSafeMutex;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+const COMMAND_PORT: u16 = 0x64;
+
+/// Status bit: a byte is waiting in the data port.
+const OUTPUT_FULL: u8 = 0x01;
+/// Status bit: the controller hasn't taken the last byte written yet.
+const INPUT_FULL: u8 = 0x02;
+/// Status bit: the waiting byte came from the second port.
+const AUX_DATA: u8 = 0x20;
+
+// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_PORT2: u8 = 0xa7;
+const ENABLE_PORT2: u8 = 0xa8;
+const TEST_PORT2: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_PORT1: u8 = 0xab;
+const DISABLE_PORT1: u8 = 0xad;
+const ENABLE_PORT1: u8 = 0xae;
+/// The next byte written to the data port goes to the second port.
+const WRITE_PORT2: u8 = 0xd4;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// Configuration byte
+const CONFIG_PORT1_INTERRUPT: u8 = 0x01;
+const CONFIG_PORT2_INTERRUPT: u8 = 0x02;
+const CONFIG_PORT1_CLOCK_OFF: u8 = 0x10;
+const CONFIG_PORT2_CLOCK_OFF: u8 = 0x20;
+const CONFIG_TRANSLATION: u8 = 0x40;
+
+// Device commands and replies
+const DEVICE_SET_LEDS: u8 = 0xed;
+const DEVICE_RESOLUTION: u8 = 0xe8;
+const DEVICE_SCANCODE_SET: u8 = 0xf0;
+const DEVICE_IDENTIFY: u8 = 0xf2;
+const DEVICE_TYPEMATIC: u8 = 0xf3;
+/// The typematic command, as mice understand it.
+const DEVICE_SAMPLE_RATE: u8 = 0xf3;
+const DEVICE_ENABLE_SCANNING: u8 = 0xf4;
+const DEVICE_DISABLE_SCANNING: u8 = 0xf5;
+const DEVICE_RESET: u8 = 0xff;
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+const DEVICE_TEST_PASSED: u8 = 0xaa;
+
+/// The keyboard sends set 2, which the controller translates to the set 1
+/// codes `keyboard` decodes.
+pub const SCANCODE_SET: u8 = 2;
+/// Typematic byte: bits 5-6 are the delay in steps of 250 ms after the
+/// first, bits 0-4 the repeat rate, 0x04 being 20 repeats a second.
+const TYPEMATIC: u8 = 0x04;
+pub const TYPEMATIC_DELAY_MS: u32 = 250;
+pub const TYPEMATIC_RATE: u32 = 20;
+
+/// Status reads before giving up on a byte. Interrupts are off while the
+/// controller is polled, so time can't be measured in ticks; a read takes
+/// about a microsecond.
+const POLLS: u32 = 100_000;
+/// Devices take up to a second for their self-test after a reset.
+const RESET_POLLS: u32 = 1_000_000;
+const RESENDS: usize = 3;
+
+/// Sample rates that, set in this order, turn on an IntelliMouse's wheel
+/// and make it identify as ID 3.
+const SCROLL_WHEEL_KNOCK: [u8; 3] = [200, 100, 80];
+/// Movement packets a second.
+pub const MOUSE_SAMPLE_RATE: u8 = 100;
+/// Resolution code: 0x02 is 4 counts per millimetre.
+const MOUSE_RESOLUTION: u8 = 0x02;
+pub const MOUSE_COUNTS_PER_MM: u32 = 4;
+
+static CONTROLLER: IrqSafeMutex<Controller> = IrqSafeMutex::named("PS/2", Controller::new());
+
+/// The lock LEDs of a keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Leds {
+    pub scroll_lock: bool,
+    pub num_lock: bool,
+    pub caps_lock: bool,
+}
+
+impl Leds {
+    fn bits(self) -> u8 {
+        u8::from(self.scroll_lock) | u8::from(self.num_lock) << 1 | u8::from(self.caps_lock) << 2
+    }
+}
+
+/// A device as told by its reply to the identify command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// Old AT keyboards don't answer with an ID.
+    AtKeyboard,
+    Mf2Keyboard,
+    Mouse,
+    /// IntelliMouse with a scroll wheel.
+    ScrollMouse,
+    FiveButtonMouse,
+    Unknown,
+}
+
+impl DeviceKind {
+    fn from_id(id: &[u8]) -> Self {
+        match id {
+            [] => DeviceKind::AtKeyboard,
+            [0xab, 0x83 | 0x41 | 0xc1] => DeviceKind::Mf2Keyboard,
+            [0x00] => DeviceKind::Mouse,
+            [0x03] => DeviceKind::ScrollMouse,
+            [0x04] => DeviceKind::FiveButtonMouse,
+            _ => DeviceKind::Unknown,
+        }
+    }
+
+    pub fn is_keyboard(&self) -> bool {
+        matches!(self, DeviceKind::AtKeyboard | DeviceKind::Mf2Keyboard)
+    }
+
+    pub fn is_mouse(&self) -> bool {
+        matches!(self, DeviceKind::Mouse | DeviceKind::ScrollMouse | DeviceKind::FiveButtonMouse)
+    }
+
+    /// Whether its packets carry a fourth byte with the wheel movement.
+    pub fn has_wheel(&self) -> bool {
+        matches!(self, DeviceKind::ScrollMouse | DeviceKind::FiveButtonMouse)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeviceKind::AtKeyboard => "AT keyboard",
+            DeviceKind::Mf2Keyboard => "MF2 keyboard",
+            DeviceKind::Mouse => "mouse",
+            DeviceKind::ScrollMouse => "mouse with scroll wheel",
+            DeviceKind::FiveButtonMouse => "5-button mouse",
+            DeviceKind::Unknown => "unknown device",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub kind: DeviceKind,
+    id: [u8; 2],
+    id_len: usize,
+}
+
+impl Device {
+    /// The bytes it sent for the identify command.
+    pub fn id(&self) -> &[u8] {
+        &self.id[..self.id_len]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    /// The controller has only one port.
+    Absent,
+    /// The interface test failed with this code.
+    Failed(u8),
+    /// Nothing answered a reset.
+    Empty,
+    Device(Device),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerInfo {
+    pub ports: [PortState; 2],
+    /// The keyboard on the first port took the scancode set and typematic
+    /// rate.
+    pub keyboard_configured: bool,
+    /// The mouse on the second port took the sample rate and reports
+    /// movement.
+    pub mouse_configured: bool,
+}
+
+impl ControllerInfo {
+    /// The device on port `index` (0 or 1), if one answered.
+    pub fn device(&self, index: usize) -> Option<Device> {
+        match self.ports[index] {
+            PortState::Device(device) => Some(device),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for PortState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PortState::Absent => f.write_str("not present"),
+            PortState::Failed(code) => write!(f, "failed its test ({:#04x})", code),
+            PortState::Empty => f.write_str("no device"),
+            PortState::Device(device) => {
+                f.write_str(device.kind.as_str())?;
+                if !device.id().is_empty() {
+                    f.write_str(" (ID")?;
+                    for byte in device.id() {
+                        write!(f, " {:02x}", byte)?;
+                    }
+                    f.write_str(")")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// A byte from the data port, by the device that sent it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Input {
+    Keyboard(u8),
+    Mouse(u8),
+}
+
+impl Input {
+    fn new(status: u8, byte: u8) -> Self {
+        if status & AUX_DATA == 0 {
+            Input::Keyboard(byte)
+        } else {
+            Input::Mouse(byte)
+        }
+    }
+
+    fn port(self) -> usize {
+        match self {
+            Input::Keyboard(_) => 0,
+            Input::Mouse(_) => 1,
+        }
+    }
+
+    fn byte(self) -> u8 {
+        match self {
+            Input::Keyboard(byte) | Input::Mouse(byte) => byte,
+        }
+    }
+
+    /// Passes it on to its driver, as the interrupt handlers do.
+    fn forward(self) {
+        match self {
+            Input::Keyboard(scancode) => crate::keyboard::add_scancode(scancode),
+            Input::Mouse(byte) => crate::mouse::add_byte(byte),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Error {
+    /// The controller or the device didn't answer in time.
+    Timeout,
+    /// The device kept asking for the byte again.
+    Resend,
+    /// The controller's self-test failed with this code.
+    SelfTest(u8),
+}
+
+struct Controller {
+    data: Port<u8>,
+    status: Port<u8>,
+    command: Port<u8>,
+    info: Option<ControllerInfo>,
+}
+
+impl Controller {
+    const fn new() -> Self {
+        Controller {
+            data: Port::new(DATA_PORT),
+            status: Port::new(STATUS_PORT),
+            command: Port::new(COMMAND_PORT),
+            info: None,
+        }
+    }
+
+    fn read_status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn wait_for_input(&mut self) -> Result<(), Error> {
+        for _ in 0..POLLS {
+            if self.read_status() & INPUT_FULL == 0 {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn read(&mut self, polls: u32) -> Result<u8, Error> {
+        for _ in 0..polls {
+            if self.read_status() & OUTPUT_FULL != 0 {
+                return Ok(unsafe { self.data.read() });
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn read_input(&mut self, polls: u32) -> Result<Input, Error> {
+        for _ in 0..polls {
+            let status = self.read_status();
+            if status & OUTPUT_FULL != 0 {
+                return Ok(Input::new(status, unsafe { self.data.read() }));
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Reads the next byte from the device on `port`. Bytes the other
+    /// device sends meanwhile are passed on to its driver.
+    fn read_from(&mut self, port: usize, polls: u32) -> Result<u8, Error> {
+        loop {
+            let input = self.read_input(polls)?;
+            if input.port() == port {
+                return Ok(input.byte());
+            }
+            input.forward();
+        }
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), Error> {
+        self.wait_for_input()?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    fn command_with_reply(&mut self, command: u8) -> Result<u8, Error> {
+        self.command(command)?;
+        self.read(POLLS)
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Error> {
+        self.command(WRITE_CONFIG)?;
+        self.wait_for_input()?;
+        unsafe { self.data.write(config) };
+        Ok(())
+    }
+
+    /// Drops whatever the devices sent so far.
+    fn flush(&mut self) {
+        for _ in 0..16 {
+            if self.read_status() & OUTPUT_FULL == 0 {
+                return;
+            }
+            unsafe { self.data.read() };
+        }
+    }
+
+    /// Sends `byte` to the device on `port` and waits for its ACK. Keys and
+    /// movement arriving meanwhile are passed on as usual.
+    fn send(&mut self, port: usize, byte: u8) -> Result<(), Error> {
+        for _ in 0..RESENDS {
+            if port == 1 {
+                self.command(WRITE_PORT2)?;
+            }
+            self.wait_for_input()?;
+            unsafe { self.data.write(byte) };
+
+            loop {
+                let input = self.read_input(POLLS)?;
+                match input.byte() {
+                    ACK if input.port() == port => return Ok(()),
+                    RESEND if input.port() == port => break,
+                    _ => input.forward(),
+                }
+            }
+        }
+        Err(Error::Resend)
+    }
+
+    fn send_with_argument(&mut self, port: usize, command: u8, argument: u8) -> Result<(), Error> {
+        self.send(port, command)?;
+        self.send(port, argument)
+    }
+
+    /// Resets the device on `port` and asks what it is. Leaves it with
+    /// scanning disabled.
+    fn identify(&mut self, port: usize) -> PortState {
+        if self.send(port, DEVICE_RESET).is_err() {
+            return PortState::Empty;
+        }
+        if self.read_from(port, RESET_POLLS) != Ok(DEVICE_TEST_PASSED) {
+            return PortState::Empty;
+        }
+        // Mice send their ID after the self-test result. Keyboards send
+        // nothing more, so for them this runs into the timeout.
+        let _ = self.read_from(port, POLLS);
+
+        if self.send(port, DEVICE_DISABLE_SCANNING).is_err() || self.send(port, DEVICE_IDENTIFY).is_err() {
+            return PortState::Empty;
+        }
+        let mut id = [0; 2];
+        let mut id_len = 0;
+        while id_len < id.len() {
+            match self.read_from(port, POLLS) {
+                Ok(byte) => {
+                    id[id_len] = byte;
+                    id_len += 1;
+                }
+                Err(_) => break,
+            }
+        }
+        PortState::Device(Device { kind: DeviceKind::from_id(&id[..id_len]), id, id_len })
+    }
+
+    /// Sets the scancode set, the typematic rate and the LEDs, and lets it
+    /// send keys again.
+    fn configure_keyboard(&mut self) -> Result<(), Error> {
+        self.send_with_argument(0, DEVICE_SCANCODE_SET, SCANCODE_SET)?;
+        self.send_with_argument(0, DEVICE_TYPEMATIC, TYPEMATIC)?;
+        self.send_with_argument(0, DEVICE_SET_LEDS, crate::keyboard::leds().bits())?;
+        self.send(0, DEVICE_ENABLE_SCANNING)
+    }
+
+    /// Turns on the scroll wheel if the mouse has one, sets the sample rate
+    /// and resolution and lets it report movement. Returns the mouse as it
+    /// identifies afterwards.
+    fn configure_mouse(&mut self) -> Result<Device, Error> {
+        for rate in SCROLL_WHEEL_KNOCK {
+            self.send_with_argument(1, DEVICE_SAMPLE_RATE, rate)?;
+        }
+        self.send(1, DEVICE_IDENTIFY)?;
+        let id = self.read_from(1, POLLS)?;
+        self.send_with_argument(1, DEVICE_SAMPLE_RATE, MOUSE_SAMPLE_RATE)?;
+        self.send_with_argument(1, DEVICE_RESOLUTION, MOUSE_RESOLUTION)?;
+        self.send(1, DEVICE_ENABLE_SCANNING)?;
+        Ok(Device { kind: DeviceKind::from_id(&[id]), id: [id, 0], id_len: 1 })
+    }
+
+    fn initialize(&mut self) -> Result<ControllerInfo, Error> {
+        self.command(DISABLE_PORT1)?;
+        self.command(DISABLE_PORT2)?;
+        self.flush();
+
+        // No interrupts or translation while the devices are set up
+        let config = self.command_with_reply(READ_CONFIG)?;
+        let config = config & !(CONFIG_PORT1_INTERRUPT | CONFIG_PORT2_INTERRUPT | CONFIG_TRANSLATION);
+        self.write_config(config)?;
+
+        let result = self.command_with_reply(SELF_TEST)?;
+        if result != SELF_TEST_PASSED {
+            return Err(Error::SelfTest(result));
+        }
+        // Some controllers reset the configuration in the self-test
+        self.write_config(config)?;
+
+        // The second port's clock stays off after DISABLE_PORT2 only if the
+        // controller has no second port to enable
+        let mut dual = false;
+        if config & CONFIG_PORT2_CLOCK_OFF != 0 {
+            self.command(ENABLE_PORT2)?;
+            dual = self.command_with_reply(READ_CONFIG)? & CONFIG_PORT2_CLOCK_OFF == 0;
+            self.command(DISABLE_PORT2)?;
+        }
+
+        let mut ports = [PortState::Empty, PortState::Absent];
+        match self.command_with_reply(TEST_PORT1)? {
+            PORT_TEST_PASSED => {}
+            code => ports[0] = PortState::Failed(code),
+        }
+        if dual {
+            ports[1] = match self.command_with_reply(TEST_PORT2)? {
+                PORT_TEST_PASSED => PortState::Empty,
+                code => PortState::Failed(code),
+            };
+        }
+
+        for (index, enable) in [ENABLE_PORT1, ENABLE_PORT2].into_iter().enumerate() {
+            if ports[index] == PortState::Empty {
+                self.command(enable)?;
+                ports[index] = self.identify(index);
+            }
+        }
+
+        let mut info = ControllerInfo { ports, keyboard_configured: false, mouse_configured: false };
+        if info.device(0).is_some_and(|device| device.kind.is_keyboard()) {
+            match self.configure_keyboard() {
+                Ok(()) => info.keyboard_configured = true,
+                Err(error) => log::warn!("PS/2 keyboard setup failed: {:?}", error),
+            }
+        }
+
+        if info.device(1).is_some_and(|device| device.kind.is_mouse()) {
+            match self.configure_mouse() {
+                Ok(device) => {
+                    info.ports[1] = PortState::Device(device);
+                    info.mouse_configured = true;
+                }
+                Err(error) => log::warn!("PS/2 mouse setup failed: {:?}", error),
+            }
+        }
+
+        // The second port stays off unless the mouse is on it
+        // The configuration was read with both ports disabled
+        let mut config = config | CONFIG_PORT1_INTERRUPT | CONFIG_TRANSLATION;
+        if !matches!(info.ports[0], PortState::Failed(_)) {
+            config &= !CONFIG_PORT1_CLOCK_OFF;
+        }
+        if info.mouse_configured {
+            config = (config & !CONFIG_PORT2_CLOCK_OFF) | CONFIG_PORT2_INTERRUPT;
+        } else if dual {
+            self.command(DISABLE_PORT2)?;
+        }
+        self.flush();
+        self.write_config(config)?;
+        Ok(info)
+    }
+}
+
+/// Resets the controller and the devices on its ports and sets up the
+/// keyboard and the mouse. Replaces whatever the firmware configured; keys
+/// reach the keyboard interrupt handler translated to set 1 as before, and
+/// mouse packets come in on IRQ 12.
+pub fn init() {
+    let mut controller = CONTROLLER.lock();
+    match controller.initialize() {
+        Ok(info) => {
+            for (index, port) in info.ports.iter().enumerate() {
+                log::info!("PS/2 port {}: {}", index + 1, port);
+            }
+            if info.mouse_configured {
+                let wheel = info.device(1).is_some_and(|device| device.kind.has_wheel());
+                crate::mouse::set_wheel(wheel);
+                crate::pic::unmask(crate::pic::InterruptIndex::Mouse);
+            }
+            controller.info = Some(info);
+        }
+        Err(error) => log::warn!("PS/2 controller not usable: {:?}", error),
+    }
+}
+
+/// What `init` found, or `None` before it ran or if it failed.
+pub fn info() -> Option<ControllerInfo> {
+    CONTROLLER.lock().info
+}
+
+/// Called by the keyboard and mouse interrupt handlers. Returns the waiting
+/// byte, or `None` if the interrupt had nothing for them.
+pub(crate) fn read_input() -> Option<Input> {
+    // Not through CONTROLLER: code holding it has interrupts disabled and
+    // polls the ports itself
+    let mut status = Port::<u8>::new(STATUS_PORT);
+    let mut data = Port::<u8>::new(DATA_PORT);
+    unsafe {
+        let status = status.read();
+        if status & OUTPUT_FULL == 0 {
+            return None;
+        }
+        Some(Input::new(status, data.read()))
+    }
+}
+
+/// Turns the keyboard's lock LEDs on or off. Does nothing if `init` didn't
+/// find a keyboard.
+pub fn set_leds(leds: Leds) {
+    let mut controller = CONTROLLER.lock();
+    if !controller.info.is_some_and(|info| info.keyboard_configured) {
+        return;
+    }
+    if let Err(error) = controller.send_with_argument(0, DEVICE_SET_LEDS, leds.bits()) {
+        log::warn!("Setting the keyboard LEDs failed: {:?}", error);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_device_kind_from_id() {
+        assert_eq!(DeviceKind::from_id(&[]), DeviceKind::AtKeyboard);
+        assert_eq!(DeviceKind::from_id(&[0xab, 0x83]), DeviceKind::Mf2Keyboard);
+        // As the keyboard answers through translation
+        assert_eq!(DeviceKind::from_id(&[0xab, 0x41]), DeviceKind::Mf2Keyboard);
+        assert_eq!(DeviceKind::from_id(&[0x03]), DeviceKind::ScrollMouse);
+        assert_eq!(DeviceKind::from_id(&[0x42]), DeviceKind::Unknown);
+        assert!(DeviceKind::ScrollMouse.has_wheel());
+        assert!(!DeviceKind::Mouse.has_wheel());
+    }
+
+    #[test_case]
+    fn test_input_port() {
+        assert_eq!(Input::new(OUTPUT_FULL, 0x1e), Input::Keyboard(0x1e));
+        assert_eq!(Input::new(OUTPUT_FULL | AUX_DATA, 0x08), Input::Mouse(0x08));
+        assert_eq!(Input::Mouse(0x08).port(), 1);
+    }
+
+    #[test_case]
+    fn test_led_bits() {
+        assert_eq!(Leds::default().bits(), 0);
+        assert_eq!(Leds { caps_lock: true, ..Leds::default() }.bits(), 0b100);
+        assert_eq!(Leds { scroll_lock: true, num_lock: true, caps_lock: false }.bits(), 0b011);
+    }
+}
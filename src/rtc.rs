@@ -0,0 +1,236 @@
+use alloc::format;
+use alloc::string::String;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+/// Keeps NMIs off while a register is selected, so one arriving between
+/// the two port accesses can't leave the CMOS in an undefined state.
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+/// Status A: the clock is updating its registers right now.
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+/// Status B: hours are 0-23 instead of 1-12 with a PM flag.
+const HOURS_24: u8 = 0x02;
+/// Status B: values are binary instead of BCD.
+const BINARY_MODE: u8 = 0x04;
+/// Set in the hours register for PM times in 12-hour mode.
+const HOUR_PM: u8 = 0x80;
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos::new());
+
+struct Cmos {
+    address: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    const fn new() -> Self {
+        Cmos {
+            address: Port::new(CMOS_ADDRESS),
+            data: Port::new(CMOS_DATA),
+        }
+    }
+
+    fn read(&mut self, register: u8) -> u8 {
+        unsafe {
+            self.address.write(NMI_DISABLE | register);
+            let value = self.data.read();
+            self.address.write(register);
+            value
+        }
+    }
+
+    fn update_in_progress(&mut self) -> bool {
+        self.read(REG_STATUS_A) & UPDATE_IN_PROGRESS != 0
+    }
+
+    fn read_raw(&mut self) -> RawTime {
+        while self.update_in_progress() {}
+
+        RawTime {
+            second: self.read(REG_SECONDS),
+            minute: self.read(REG_MINUTES),
+            hour: self.read(REG_HOURS),
+            day: self.read(REG_DAY),
+            month: self.read(REG_MONTH),
+            year: self.read(REG_YEAR),
+        }
+    }
+}
+
+/// Called by the RTC interrupt handler. The RTC raises no further
+/// interrupts until status register C has been read.
+pub(crate) fn acknowledge_interrupt() {
+    CMOS.lock().read(REG_STATUS_C);
+}
+
+/// Clock registers exactly as the CMOS reports them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Reads the current date and time from the real-time clock.
+    pub fn now() -> DateTime {
+        // The RTC interrupt handler takes the lock too
+        x86_64::instructions::interrupts::without_interrupts(Self::read)
+    }
+
+    fn read() -> DateTime {
+        let mut cmos = CMOS.lock();
+
+        // The clock can tick over between reading two registers, so read
+        // until two reads in a row agree
+        let mut raw = cmos.read_raw();
+        loop {
+            let again = cmos.read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+
+        decode(raw, cmos.read(REG_STATUS_B))
+    }
+
+    /// `MM-DD-YYYY`, as BASIC `DATE$` shows it.
+    pub fn date_string(&self) -> String {
+        format!("{:02}-{:02}-{:04}", self.month, self.day, self.year)
+    }
+
+    /// `HH:MM:SS`, as BASIC `TIME$` shows it.
+    pub fn time_string(&self) -> String {
+        format!("{:02}:{:02}:{:02}", self.hour, self.minute, self.second)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let convert = |value: u8| {
+        if status_b & BINARY_MODE != 0 {
+            value
+        } else {
+            bcd_to_binary(value)
+        }
+    };
+
+    let pm = raw.hour & HOUR_PM != 0;
+    let mut hour = convert(raw.hour & !HOUR_PM);
+    if status_b & HOURS_24 == 0 {
+        // 12 AM is midnight, 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    // The century register's location comes from ACPI and isn't always
+    // there, so assume we're in the 2000s
+    DateTime {
+        year: 2000 + u16::from(convert(raw.year)),
+        month: convert(raw.month),
+        day: convert(raw.day),
+        hour,
+        minute: convert(raw.minute),
+        second: convert(raw.second),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RAW: RawTime = RawTime {
+        second: 0x45,
+        minute: 0x30,
+        hour: 0x09,
+        day: 0x17,
+        month: 0x10,
+        year: 0x26,
+    };
+
+    #[test_case]
+    fn test_bcd_to_binary() {
+        assert_eq!(bcd_to_binary(0x00), 0);
+        assert_eq!(bcd_to_binary(0x09), 9);
+        assert_eq!(bcd_to_binary(0x59), 59);
+    }
+
+    #[test_case]
+    fn test_decode_bcd_24_hour() {
+        let time = decode(RAW, HOURS_24);
+        assert_eq!(time.year, 2026);
+        assert_eq!((time.month, time.day), (10, 17));
+        assert_eq!((time.hour, time.minute, time.second), (9, 30, 45));
+    }
+
+    #[test_case]
+    fn test_decode_binary() {
+        let raw = RawTime { second: 45, minute: 30, hour: 21, day: 17, month: 10, year: 26 };
+        let time = decode(raw, HOURS_24 | BINARY_MODE);
+        assert_eq!(time.date_string(), "10-17-2026");
+        assert_eq!(time.time_string(), "21:30:45");
+    }
+
+    #[test_case]
+    fn test_decode_12_hour() {
+        let pm = RawTime { hour: HOUR_PM | 0x09, ..RAW };
+        assert_eq!(decode(pm, 0).hour, 21);
+
+        let midnight = RawTime { hour: 0x12, ..RAW };
+        assert_eq!(decode(midnight, 0).hour, 0);
+
+        let noon = RawTime { hour: HOUR_PM | 0x12, ..RAW };
+        assert_eq!(decode(noon, 0).hour, 12);
+    }
+
+    #[test_case]
+    fn test_now_is_plausible() {
+        let now = DateTime::now();
+        assert!((1..=12).contains(&now.month));
+        assert!((1..=31).contains(&now.day));
+        assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+    }
+}
@@ -1,16 +1,44 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+/// Line status register bit: a received byte is waiting.
+const DATA_READY: u8 = 0x01;
 
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+    pub static ref SERIAL1: IrqSafeMutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(COM1) };
         serial_port.init();
-        Mutex::new(serial_port)
+        IrqSafeMutex::named("SERIAL1", serial_port)
     };
 }
 
+/// Reads and drops whatever was received on COM1, which clears its
+/// interrupt. Nothing consumes serial input yet.
+///
+/// Uses the ports directly rather than `SERIAL1`, so it is safe to call from
+/// the interrupt handler; reading the receive buffer doesn't disturb output.
+pub(crate) fn discard_input() {
+    let mut line_status = Port::<u8>::new(COM1 + 5);
+    let mut data = Port::<u8>::new(COM1);
+
+    unsafe {
+        while line_status.read() & DATA_READY != 0 {
+            data.read();
+        }
+    }
+}
+
+/// A handle on COM1 that doesn't go through `SERIAL1`, for reporting
+/// problems while `SERIAL1` may be locked. `SERIAL1` set the port up.
+pub(crate) fn raw_port() -> SerialPort {
+    unsafe { SerialPort::new(COM1) }
+}
+
 #[doc(hidden)]
+#[track_caller]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
     SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
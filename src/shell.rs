@@ -1,83 +1,264 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use futures_util::stream::{self, StreamExt};
+use crate::keyboard::{self, ScancodeStream};
+use crate::keyboard_buffer::KEYBOARD_BUFFER;
+use crate::mouse::{self, MouseStream};
+use crate::task::{self, Task};
+use crate::usermode::{self, ExitStatus, Pid, State};
 use crate::{print, println};
+use log::LevelFilter;
 
-const MAX_BUFFER_LEN: usize = 128;
+/// The process carlsh waits for before it shows the prompt again, or 0.
+static WAITING_FOR: AtomicU64 = AtomicU64::new(0);
+
+enum Input {
+    Scancode(u8),
+    Mouse(u8),
+}
+
+/// Reads keys from the keyboard and runs the commands typed at the prompt.
+/// Text selected with the mouse is pasted as if typed.
+pub async fn shell_task() {
+    let mut input = stream::select(
+        ScancodeStream::new().map(Input::Scancode),
+        MouseStream::new().map(Input::Mouse),
+    );
+    let mut shell = Shell::new();
+    // A shell restarted after a fault has lost the task that was waiting
+    WAITING_FOR.store(0, Ordering::Relaxed);
+    shell.print_prompt();
+
+    while let Some(input) = input.next().await {
+        match input {
+            Input::Scancode(scancode) => {
+                if let Some(character) = keyboard::process_scancode(scancode) {
+                    if character == keyboard::CTRL_C && interrupt_programs() {
+                        continue;
+                    }
+                    type_character(&mut shell, character);
+                }
+            }
+            Input::Mouse(byte) => {
+                // Selected lines are pasted as one, so a paste never runs
+                // a command by itself
+                let Some(text) = mouse::process_byte(byte) else {
+                    continue;
+                };
+                for character in text.chars() {
+                    match character {
+                        '\n' => type_character(&mut shell, ' '),
+                        ' '..='~' => type_character(&mut shell, character),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // Waited out here so that other tasks keep running meanwhile. Keys
+        // typed in the meantime stay queued for after it.
+        if let Some(ms) = shell.take_sleep() {
+            crate::time::sleep(ms).await;
+            shell.print_prompt();
+        }
+    }
+}
+
+fn type_character(shell: &mut Shell, character: char) {
+    // Add to keyboard buffer for INKEY()
+    KEYBOARD_BUFFER.lock().push(character as u8);
+
+    // Keys typed while a program runs are only meant for INKEY()
+    // or the user program's read_key
+    let waiting = WAITING_FOR.load(Ordering::Relaxed) != 0;
+    if !crate::BASIC.lock().is_running() && !waiting {
+        shell.handle_key(character);
+    }
+}
+
+/// Runs the program started by `RUN` and shows the prompt once it ends.
+async fn basic_program_task() {
+    crate::basic::run_program().await;
+    print_prompt(true);
+}
+
+/// Waits for process `pid`, started in the foreground or named by `wait`,
+/// and shows the prompt once it has exited.
+async fn user_program_task(pid: Pid) {
+    let status = loop {
+        match usermode::try_wait(pid) {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) => crate::time::sleep(10).await,
+            Err(_) => break None,
+        }
+    };
+
+    match status {
+        Some(ExitStatus::Exited(0)) | None => {}
+        Some(ExitStatus::Exited(code)) => println!("Exited with code {}", code),
+        Some(ExitStatus::Killed) => println!("Killed"),
+        Some(ExitStatus::LoadFailed(error)) => println!("Couldn't load the program: {}", error),
+    }
+    WAITING_FOR.store(0, Ordering::Relaxed);
+    print_prompt(false);
+}
+
+/// Ctrl-C: kills the programs in the foreground and the one carlsh waits
+/// for. Returns false if there were none.
+fn interrupt_programs() -> bool {
+    let mut killed = false;
+    while let Some(pid) = usermode::foreground() {
+        killed |= usermode::kill(pid).is_ok();
+    }
+    let waiting = WAITING_FOR.load(Ordering::Relaxed);
+    if waiting != 0 {
+        killed |= usermode::kill(Pid::new(waiting)).is_ok();
+    }
+    killed
+}
+
+fn print_prompt(basic_mode: bool) {
+    if basic_mode {
+        print!("BASIC> ");
+    } else {
+        print!("> ");
+    }
+}
 
 pub struct Shell {
-    buffer: [u8; MAX_BUFFER_LEN],
-    len: usize,
+    buffer: String,
     basic_mode: bool,
+    /// The process the last command wants the shell to wait for.
+    wait_for: Option<Pid>,
+    /// How long the last command wants the shell to sleep, in milliseconds.
+    sleep_ms: Option<u64>,
+}
+
+impl Default for Shell {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Shell {
     pub const fn new() -> Self {
         Shell {
-            buffer: [0; MAX_BUFFER_LEN],
-            len: 0,
+            buffer: String::new(),
             basic_mode: false,
+            wait_for: None,
+            sleep_ms: None,
         }
     }
 
+    /// Returns how long the last command asked to sleep, if it did. The
+    /// prompt is left to be shown afterwards.
+    pub fn take_sleep(&mut self) -> Option<u64> {
+        self.sleep_ms.take()
+    }
+
     pub fn handle_key(&mut self, character: char) {
         match character {
             '\n' => {
                 println!();
-                self.execute_command();
-                self.len = 0;
-                if !self.basic_mode {
-                    print!("> ");
-                } else {
-                    print!("BASIC> ");
+                let input = core::mem::take(&mut self.buffer);
+                self.execute_command(&input);
+
+                // A program started by RUN prints the prompt when it ends
+                if self.basic_mode && crate::BASIC.lock().is_running() {
+                    task::spawn(Task::new(basic_program_task()));
+                } else if let Some(pid) = self.wait_for.take() {
+                    WAITING_FOR.store(pid.as_u64(), Ordering::Relaxed);
+                    task::spawn(Task::new(user_program_task(pid)));
+                } else if self.sleep_ms.is_none() {
+                    self.print_prompt();
                 }
             }
             '\u{0008}' => {
                 // Backspace
-                if self.len > 0 {
-                    self.len -= 1;
+                if self.buffer.pop().is_some() {
                     print!("\u{0008} \u{0008}");
                 }
             }
+            // Other control characters, such as Ctrl with a letter
+            _ if character.is_control() && character != '\t' => {}
             _ => {
-                if self.len < MAX_BUFFER_LEN {
-                    self.buffer[self.len] = character as u8;
-                    self.len += 1;
-                    print!("{}", character);
-                }
+                self.buffer.push(character);
+                print!("{}", character);
             }
         }
     }
 
-    fn execute_command(&mut self) {
-        if self.len == 0 {
+    fn execute_command(&mut self, input: &str) {
+        let cmd = input.trim();
+        if cmd.is_empty() {
             return;
         }
 
-        let cmd = core::str::from_utf8(&self.buffer[..self.len]).unwrap_or("");
-        let cmd = cmd.trim();
-
         if self.basic_mode {
-            let cmd_upper = self.to_upper_bytes(cmd);
-            if self.bytes_eq(&cmd_upper, b"EXIT") {
+            let cmd_upper = cmd.to_ascii_uppercase();
+            if cmd_upper == "EXIT" || cmd_upper.starts_with("EXIT ") {
                 self.basic_mode = false;
                 println!("Exiting BASIC mode");
             } else {
-                crate::BASIC.lock().execute(cmd);
+                let mut basic = crate::BASIC.lock();
+                basic.execute(cmd);
+                self.sleep_ms = basic.take_sleep();
             }
             return;
         }
 
-        if cmd.starts_with("echo ") {
-            println!("{}", &cmd[5..]);
+        if let Some(text) = cmd.strip_prefix("echo ") {
+            println!("{}", text);
+        } else if let Some(ms) = cmd.strip_prefix("sleep ") {
+            match ms.trim().parse::<u64>() {
+                Ok(ms) => self.sleep_ms = Some(ms),
+                Err(_) => println!("Usage: sleep <milliseconds>"),
+            }
+        } else if let Some(level) = arguments(cmd, "dmesg") {
+            print_dmesg(level);
+        } else if let Some(level) = arguments(cmd, "loglevel") {
+            set_loglevel(level);
+        } else if let Some(name) = arguments(cmd, "kbdlayout") {
+            set_keyboard_layout(name);
+        } else if let Some(name) = arguments(cmd, "run") {
+            self.wait_for = run_program(name);
+        } else if let Some(args) = arguments(cmd, "exec") {
+            self.wait_for = exec_program(args);
+        } else if let Some(pid) = arguments(cmd, "kill") {
+            kill_process(pid);
+        } else if let Some(pid) = arguments(cmd, "wait") {
+            self.wait_for = wait_process(pid);
         } else {
             match cmd {
                 "help" => {
                     println!("Available commands:");
-                    println!("  help     - Show this help message");
-                    println!("  echo     - Echo back the arguments");
-                    println!("  clear    - Clear the screen");
-                    println!("  car      - Prints a car");
-                    println!("  hello    - Print a greeting");
-                    println!("  about    - About this OS");
-                    println!("  basic    - Enter BASIC programming mode");
+                    println!("  help      - Show this help message");
+                    println!("  echo      - Echo back the arguments");
+                    println!("  clear     - Clear the screen");
+                    println!("  car       - Prints a car");
+                    println!("  hello     - Print a greeting");
+                    println!("  about     - About this OS");
+                    println!("  bootinfo  - Show boot information and memory map");
+                    println!("  meminfo   - Show physical memory usage");
+                    println!("  ps        - List processes");
+                    println!("  threads   - List kernel threads");
+                    println!("  kill      - Kill a process <pid> (Ctrl-C: the foreground one)");
+                    println!("  wait      - Wait for a process to exit <pid>");
+                    println!("  cpus      - List processors");
+                    println!("  devices   - List PS/2 devices");
+                    println!("  dmesg     - Show kernel log messages [level]");
+                    println!("  loglevel  - Show or set the log level [level]");
+                    println!("  kbdlayout - List or set the keyboard layout [name]");
+                    println!("  uptime    - Show time since boot");
+                    println!("  date      - Show the current date and time");
+                    println!("  sleep     - Wait for the given milliseconds");
+                    println!("  run       - Run a user program (lists them without a name) [&]");
+                    println!("  exec      - Run an ELF program from the initrd [args] [&]");
+                    println!("  basic     - Enter BASIC programming mode");
+                    println!("  reboot    - Restart the machine");
+                    println!("  shutdown  - Turn the machine off");
                 }
                 "clear" => {
                     crate::vga_buffer::clear_screen();
@@ -97,6 +278,43 @@ impl Shell {
                     println!("CarlOS v0.1.0");
                     println!("A simple operating system written in Rust");
                     println!("Running on x86_64 architecture");
+                    if crate::apic::is_enabled() {
+                        println!("Interrupts: local APIC and I/O APIC");
+                    } else {
+                        println!("Interrupts: 8259 PIC");
+                    }
+                }
+                "bootinfo" => {
+                    print_bootinfo();
+                }
+                "meminfo" => {
+                    print_meminfo();
+                }
+                "date" => {
+                    println!("{}", crate::rtc::DateTime::now());
+                }
+                "uptime" => {
+                    print_uptime();
+                }
+                "ps" => {
+                    print_processes();
+                }
+                "threads" => {
+                    print_threads();
+                }
+                "cpus" => {
+                    print_cpus();
+                }
+                "devices" => {
+                    print_devices();
+                }
+                "reboot" => {
+                    println!("Rebooting...");
+                    crate::power::reboot();
+                }
+                "shutdown" => {
+                    println!("Shutting down...");
+                    crate::power::shutdown();
                 }
                 "basic" => {
                     self.basic_mode = true;
@@ -115,38 +333,306 @@ impl Shell {
     }
 
     pub fn print_prompt(&self) {
-        if self.basic_mode {
-            print!("BASIC> ");
-        } else {
-            print!("> ");
+        print_prompt(self.basic_mode);
+    }
+}
+
+fn print_bootinfo() {
+    use bootloader::bootinfo::MemoryRegionType;
+
+    let boot_info = match crate::memory::boot_info() {
+        Some(boot_info) => boot_info,
+        None => {
+            println!("Boot information not available");
+            return;
+        }
+    };
+
+    println!("Physical memory offset: {:#x}", boot_info.physical_memory_offset);
+    println!("Memory map:");
+
+    let mut usable = 0;
+    for region in boot_info.memory_map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        println!("  {:#012x} - {:#012x}  {:?}", start, end, region.region_type);
+
+        if region.region_type == MemoryRegionType::Usable {
+            usable += end - start;
+        }
+    }
+    println!("Usable memory: {} KiB", usable / 1024);
+}
+
+fn print_uptime() {
+    let ms = crate::time::uptime_ms();
+    let seconds = ms / 1000;
+    println!(
+        "Up {}:{:02}:{:02}.{:03} ({} ticks at {} Hz)",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        ms % 1000,
+        crate::time::ticks(),
+        crate::time::TICK_RATE
+    );
+}
+
+/// The arguments of `cmd` if it is the command `name`, with or without
+/// arguments.
+fn arguments<'a>(cmd: &'a str, name: &str) -> Option<&'a str> {
+    let rest = cmd.strip_prefix(name)?;
+    (rest.is_empty() || rest.starts_with(' ')).then(|| rest.trim())
+}
+
+fn print_dmesg(level: &str) {
+    let level = if level.is_empty() {
+        LevelFilter::Trace
+    } else {
+        match level.parse::<LevelFilter>() {
+            Ok(level) => level,
+            Err(_) => {
+                println!("Usage: dmesg [error|warn|info|debug|trace]");
+                return;
+            }
         }
+    };
+
+    for entry in crate::logger::entries(level) {
+        println!("{}", entry);
     }
+}
 
-    fn to_upper_bytes(&self, s: &str) -> [u8; MAX_BUFFER_LEN] {
-        let mut result = [0u8; MAX_BUFFER_LEN];
-        let bytes = s.as_bytes();
-        let len = bytes.len().min(MAX_BUFFER_LEN);
+fn set_loglevel(level: &str) {
+    if level.is_empty() {
+        println!("Log level: {}", crate::logger::level());
+        return;
+    }
 
-        for i in 0..len {
-            result[i] = if bytes[i] >= b'a' && bytes[i] <= b'z' {
-                bytes[i] - 32
-            } else {
-                    bytes[i]
-                };
+    match level.parse::<LevelFilter>() {
+        Ok(level) => {
+            crate::logger::set_level(level);
+            println!("Log level set to {}", level);
         }
-        result
+        Err(_) => println!("Usage: loglevel [off|error|warn|info|debug|trace]"),
     }
+}
 
-    fn bytes_eq(&self, a: &[u8], b: &[u8]) -> bool {
-        let len = b.len();
-        if a.len() < len {
-            return false;
+fn set_keyboard_layout(name: &str) {
+    if name.is_empty() {
+        let active = keyboard::layout().name;
+        println!("Keyboard layouts:");
+        for layout in &keyboard::LAYOUTS {
+            let marker = if layout.name == active { "*" } else { " " };
+            println!("{} {:<8} - {}", marker, layout.name, layout.description);
         }
-        for i in 0..len {
-            if a[i] != b[i] {
-                return false;
-            }
+        return;
+    }
+
+    match keyboard::set_layout(name) {
+        Some(layout) => println!("Keyboard layout set to {} ({})", layout.name, layout.description),
+        None => println!("Unknown layout: '{}'. Type 'kbdlayout' to list them.", name),
+    }
+}
+
+/// Starts a built-in program. Returns its PID if the shell should wait for
+/// it, which it does unless the command ends in `&`.
+fn run_program(args: &str) -> Option<Pid> {
+    let (name, background) = background(args);
+    if name.is_empty() {
+        println!("User programs:");
+        for program in &usermode::PROGRAMS {
+            println!("  {:<8} - {}", program.name, program.description);
+        }
+        return None;
+    }
+
+    let Some(program) = usermode::find(name) else {
+        println!("No such program: '{}'. Type 'run' to list them.", name);
+        return None;
+    };
+    // Keys typed before the program started aren't meant for it
+    while KEYBOARD_BUFFER.lock().pop().is_some() {}
+    let handles = if background { usermode::BACKGROUND } else { usermode::FOREGROUND };
+    started(name, usermode::run(program, handles), background)
+}
+
+/// Starts a program from the initrd, like `run_program`.
+fn exec_program(args: &str) -> Option<Pid> {
+    let (args, background) = background(args);
+    if args.is_empty() {
+        println!("Programs in the initrd:");
+        for file in crate::initrd::files() {
+            println!("  {:<8} {:>7} bytes", file.name, file.data.len());
+        }
+        return None;
+    }
+
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let Some(file) = crate::initrd::find(args[0]) else {
+        println!("No such program: '{}'. Type 'exec' to list them.", args[0]);
+        return None;
+    };
+    while KEYBOARD_BUFFER.lock().pop().is_some() {}
+    let handles = if background { usermode::BACKGROUND } else { usermode::FOREGROUND };
+    started(args[0], usermode::exec(file.data, &args, handles), background)
+}
+
+/// The arguments without a trailing `&`, and whether there was one.
+fn background(args: &str) -> (&str, bool) {
+    match args.strip_suffix('&') {
+        Some(args) => (args.trim_end(), true),
+        None => (args, false),
+    }
+}
+
+fn started(name: &str, result: Result<Pid, usermode::RunError>, background: bool) -> Option<Pid> {
+    match result {
+        Ok(pid) if background => {
+            println!("[{}] {}", pid, name);
+            None
         }
-        a[len] == 0 || a[len] == b' '
+        Ok(pid) => Some(pid),
+        Err(error) => {
+            println!("Can't run {}: {}", name, error);
+            None
+        }
+    }
+}
+
+fn parse_pid(pid: &str, command: &str) -> Option<Pid> {
+    match pid.parse::<u64>() {
+        Ok(pid) => Some(Pid::new(pid)),
+        Err(_) => {
+            println!("Usage: {} <pid>", command);
+            None
+        }
+    }
+}
+
+fn kill_process(pid: &str) {
+    let Some(pid) = parse_pid(pid, "kill") else {
+        return;
+    };
+    if let Err(error) = usermode::kill(pid) {
+        println!("kill {}: {}", pid, error);
+    }
+}
+
+fn wait_process(pid: &str) -> Option<Pid> {
+    let pid = parse_pid(pid, "wait")?;
+    if !usermode::list().iter().any(|process| process.pid == pid) {
+        println!("wait {}: {}", pid, usermode::ProcessError::NoSuchProcess);
+        return None;
+    }
+    Some(pid)
+}
+
+fn print_processes() {
+    println!(" PID  PPID  STATE      THREAD  HANDLES           NAME");
+    for process in usermode::list() {
+        let parent = match process.parent {
+            Some(parent) => parent.to_string(),
+            None => "-".to_string(),
+        };
+        let state = match process.state {
+            State::Exited(ExitStatus::Exited(code)) => format!("Exited({})", code),
+            state => state.as_str().to_string(),
+        };
+        let thread = match process.thread {
+            Some(thread) => thread.as_u64().to_string(),
+            None => "-".to_string(),
+        };
+        let handles: Vec<String> =
+            process.handles.iter().map(|handle| format!("{:?}", handle).to_ascii_lowercase()).collect();
+        println!(
+            "{:>4}  {:>4}  {:<9}  {:>6}  {:<16}  {}",
+            process.pid,
+            parent,
+            state,
+            thread,
+            handles.join(","),
+            process.name
+        );
+    }
+}
+
+fn print_devices() {
+    let Some(info) = crate::ps2::info() else {
+        println!("No PS/2 controller");
+        return;
+    };
+    for (index, port) in info.ports.iter().enumerate() {
+        println!("PS/2 port {}: {}", index + 1, port);
+    }
+    if info.keyboard_configured {
+        let leds = keyboard::leds();
+        println!(
+            "Keyboard: scancode set {} (translated to set 1), repeat after {} ms at {}/s",
+            crate::ps2::SCANCODE_SET,
+            crate::ps2::TYPEMATIC_DELAY_MS,
+            crate::ps2::TYPEMATIC_RATE
+        );
+        println!(
+            "Locks: Caps {}, Num {}, Scroll {}",
+            on_off(leds.caps_lock),
+            on_off(leds.num_lock),
+            on_off(leds.scroll_lock)
+        );
+    }
+    if info.mouse_configured {
+        let wheel = info.device(1).is_some_and(|device| device.kind.has_wheel());
+        println!(
+            "Mouse: {} samples/s at {} counts/mm, wheel {}",
+            crate::ps2::MOUSE_SAMPLE_RATE,
+            crate::ps2::MOUSE_COUNTS_PER_MM,
+            on_off(wheel)
+        );
+    }
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+fn print_cpus() {
+    let current = crate::smp::current().map(|cpu| cpu.index);
+    println!(" CPU  APIC ID  STATE");
+    for cpu in crate::smp::cpus() {
+        let state = if cpu.is_online() { "online" } else { "offline" };
+        let role = if cpu.is_bsp { " (boot)" } else { "" };
+        let marker = if Some(cpu.index) == current { " *" } else { "" };
+        println!("{:>4}  {:>7}  {}{}{}", cpu.index, cpu.apic_id, state, role, marker);
     }
 }
+
+fn print_threads() {
+    println!("  ID  PRIORITY  STATE     NAME");
+    for thread in crate::thread::list() {
+        println!(
+            "{:>4}  {:<8}  {:<8}  {}",
+            thread.id.as_u64(),
+            thread.priority.as_str(),
+            thread.state.as_str(),
+            thread.name
+        );
+    }
+}
+
+fn print_meminfo() {
+    if crate::memory::boot_info().is_none() {
+        println!("Memory information not available");
+        return;
+    }
+
+    let allocator = crate::memory::frame_allocator();
+    let total = allocator.total_frames();
+    let allocated = allocator.allocated_frames();
+    let free = allocator.free_frames();
+
+    // Frames are 4 KiB each
+    println!("Total usable: {:>8} KiB ({} frames)", total * 4, total);
+    println!("Allocated:    {:>8} KiB ({} frames)", allocated * 4, allocated);
+    println!("Free:         {:>8} KiB ({} frames)", free * 4, free);
+    println!("  of which freed and reusable: {} frames", allocator.reusable_frames());
+}
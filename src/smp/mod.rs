@@ -0,0 +1,128 @@
+// mod.rs - Starting the application processors
+
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Once;
+
+mod percpu;
+mod trampoline;
+
+pub use percpu::{current, PerCpu};
+pub(crate) use percpu::{KERNEL_STACK_OFFSET, USER_STACK_OFFSET};
+
+use trampoline::Trampoline;
+
+const AP_STACK_SIZE: usize = 64 * 1024;
+
+/// Time between the INIT IPI and the first startup IPI.
+const INIT_DELAY_MS: u64 = 10;
+/// Time to wait before repeating the startup IPI.
+const STARTUP_DELAY_MS: u64 = 1;
+/// Time an application processor gets to come online.
+const ONLINE_TIMEOUT_MS: u64 = 100;
+
+static CPUS: Once<Vec<&'static PerCpu>> = Once::new();
+
+/// Sets up per-CPU data for every enabled processor in the ACPI MADT and
+/// starts the application processors with INIT-SIPI-SIPI. Without the
+/// APICs, only the boot processor is set up.
+///
+/// Needs the heap and the timer. Call after `apic::init`.
+pub fn init() {
+    CPUS.call_once(|| {
+        let bsp_apic_id = if crate::apic::is_enabled() {
+            crate::apic::local_apic_id()
+        } else {
+            0
+        };
+
+        let mut cpus = vec![new_cpu(0, bsp_apic_id, true)];
+        let processors = crate::acpi::get()
+            .and_then(|acpi| acpi.madt())
+            .filter(|_| crate::apic::is_enabled())
+            .map(|madt| madt.processors.as_slice())
+            .unwrap_or_default();
+        for processor in processors {
+            if processor.enabled && processor.apic_id != bsp_apic_id {
+                cpus.push(new_cpu(cpus.len(), processor.apic_id, false));
+            }
+        }
+
+        cpus[0].set_tss(crate::gdt::bsp_tss());
+        percpu::set_current(cpus[0]);
+        cpus[0].set_online();
+
+        if cpus.len() > 1 {
+            match Trampoline::install() {
+                Some(trampoline) => {
+                    for cpu in &cpus[1..] {
+                        start_ap(&trampoline, cpu);
+                    }
+                }
+                None => log::warn!("No low memory for the AP trampoline, running on one CPU"),
+            }
+        }
+
+        let online = cpus.iter().filter(|cpu| cpu.is_online()).count();
+        log::info!("{} of {} CPUs online", online, cpus.len());
+        cpus
+    });
+}
+
+fn new_cpu(index: usize, apic_id: u8, is_bsp: bool) -> &'static PerCpu {
+    Box::leak(Box::new(PerCpu::new(index, apic_id, is_bsp)))
+}
+
+/// All processors found, the boot processor first. Empty before `init`.
+pub fn cpus() -> &'static [&'static PerCpu] {
+    CPUS.wait().map_or(&[], |cpus| cpus.as_slice())
+}
+
+fn start_ap(trampoline: &Trampoline, cpu: &'static PerCpu) {
+    let stack = Box::leak(vec![0u8; AP_STACK_SIZE].into_boxed_slice());
+    let stack_end = (stack.as_ptr() as u64 + AP_STACK_SIZE as u64) & !0xf;
+    trampoline.prepare(stack_end, ap_main, cpu);
+
+    crate::apic::send_init(cpu.apic_id);
+    wait_ms(INIT_DELAY_MS, || false);
+
+    // The second startup IPI is only for processors that missed the first
+    for _ in 0..2 {
+        crate::apic::send_startup(cpu.apic_id, trampoline.startup_page());
+        if wait_ms(STARTUP_DELAY_MS, || cpu.is_online()) {
+            break;
+        }
+    }
+
+    if !wait_ms(ONLINE_TIMEOUT_MS, || cpu.is_online()) {
+        log::warn!("CPU {} (APIC ID {}) did not start", cpu.index, cpu.apic_id);
+    }
+}
+
+/// Spins until `done` returns true or `ms` milliseconds have passed.
+/// Returns the last result of `done`.
+fn wait_ms(ms: u64, done: impl Fn() -> bool) -> bool {
+    let deadline = crate::time::ticks().saturating_add(crate::time::ms_to_ticks(ms));
+    while crate::time::ticks() < deadline {
+        if done() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    done()
+}
+
+/// Where the trampoline sends each application processor, on its own stack.
+extern "C" fn ap_main(cpu: &'static PerCpu) -> ! {
+    cpu.set_tss(crate::gdt::init_ap());
+    crate::interrupts::init_idt_ap();
+    percpu::set_current(cpu);
+    crate::apic::init_ap();
+    cpu.set_online();
+    log::info!("CPU {} (APIC ID {}) online", cpu.index, cpu.apic_id);
+
+    // Nothing is scheduled on the application processors yet
+    x86_64::instructions::interrupts::enable();
+    crate::hlt_loop();
+}
@@ -0,0 +1,83 @@
+// percpu.rs - Per-CPU data, found through the GS base register
+
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering};
+use x86_64::registers::model_specific::GsBase;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+/// Offset of `PerCpu::kernel_stack`, for the `syscall` entry code.
+pub const KERNEL_STACK_OFFSET: usize = core::mem::offset_of!(PerCpu, kernel_stack);
+/// Offset of `PerCpu::user_stack`, for the `syscall` entry code.
+pub const USER_STACK_OFFSET: usize = core::mem::offset_of!(PerCpu, user_stack);
+
+/// Data that every processor has its own copy of.
+#[repr(C)]
+pub struct PerCpu {
+    /// Stack that `syscall` switches to: the top of the running user
+    /// program's kernel stack.
+    kernel_stack: AtomicU64,
+    /// Scratch slot where `syscall` parks the user stack pointer.
+    user_stack: AtomicU64,
+    tss: AtomicPtr<TaskStateSegment>,
+    /// Position in `smp::cpus()`; the boot processor is 0.
+    pub index: usize,
+    pub apic_id: u8,
+    pub is_bsp: bool,
+    online: AtomicBool,
+}
+
+impl PerCpu {
+    pub(super) fn new(index: usize, apic_id: u8, is_bsp: bool) -> Self {
+        PerCpu {
+            kernel_stack: AtomicU64::new(0),
+            user_stack: AtomicU64::new(0),
+            tss: AtomicPtr::new(core::ptr::null_mut()),
+            index,
+            apic_id,
+            is_bsp,
+            online: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the processor has finished starting up.
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub(super) fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    pub(super) fn set_tss(&self, tss: *mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::Relaxed);
+    }
+
+    /// Sets the stack that this processor enters the kernel on from user
+    /// mode, both through `syscall` and through interrupts (the TSS `rsp0`).
+    pub(crate) fn set_kernel_stack(&self, top: VirtAddr) {
+        self.kernel_stack.store(top.as_u64(), Ordering::Relaxed);
+        let tss = self.tss.load(Ordering::Relaxed);
+        if !tss.is_null() {
+            unsafe { (*tss).privilege_stack_table[0] = top };
+        }
+    }
+}
+
+/// Makes `cpu` the per-CPU data of the processor this runs on.
+pub(super) fn set_current(cpu: &'static PerCpu) {
+    GsBase::write(VirtAddr::from_ptr(cpu));
+}
+
+/// The per-CPU data of the processor this runs on, or `None` before
+/// `smp::init`.
+///
+/// Only valid in the kernel's GS: code entered from user mode must `swapgs`
+/// before calling this.
+pub fn current() -> Option<&'static PerCpu> {
+    let base = GsBase::read();
+    if base.is_null() {
+        None
+    } else {
+        Some(unsafe { &*base.as_ptr() })
+    }
+}
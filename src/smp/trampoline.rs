@@ -0,0 +1,186 @@
+// trampoline.rs - Real-mode startup code for the application processors
+
+use core::arch::global_asm;
+use core::mem::{offset_of, size_of};
+use core::ptr;
+use x86_64::registers::control::{Cr0, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+use super::PerCpu;
+use crate::memory;
+
+/// Parameters for the trampoline, filled in by the boot processor. The
+/// trampoline code reads them at the offsets given by this layout.
+#[repr(C)]
+struct TrampolineData {
+    gdt: [u64; 3],
+    _padding: [u16; 3],
+    // Operand of `lgdt`
+    gdt_limit: u16,
+    gdt_base: u32,
+    // Far pointer to the 64-bit code
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    cr0: u64,
+    cr3: u64,
+    cr4: u64,
+    efer: u64,
+    stack: u64,
+    entry: u64,
+    argument: u64,
+}
+
+const CODE_SELECTOR: u16 = 0x08;
+const DATA_SELECTOR: u16 = 0x10;
+
+// An application processor starts in real mode at the start of the page it
+// was sent in the startup IPI, with CS pointing to that page. The code is
+// copied there, so it only addresses things relative to its start. It
+// loads a temporary GDT and enters long mode directly, with the kernel's
+// page tables; the page is identity mapped so execution can carry on after
+// paging is enabled. The 64-bit part switches to the stack it was given and
+// calls the kernel entry point.
+global_asm!(
+    r#"
+.pushsection .text.ap_trampoline, "ax"
+.balign 16
+.code16
+.global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    lgdtl (ap_trampoline_data - ap_trampoline_start + {gdt_pointer})
+
+    mov (ap_trampoline_data - ap_trampoline_start + {cr4}), %eax
+    mov %eax, %cr4
+    mov (ap_trampoline_data - ap_trampoline_start + {cr3}), %eax
+    mov %eax, %cr3
+    mov $0xc0000080, %ecx
+    mov (ap_trampoline_data - ap_trampoline_start + {efer}), %eax
+    xor %edx, %edx
+    wrmsr
+    // Protection and paging at once, which activates long mode
+    mov (ap_trampoline_data - ap_trampoline_start + {cr0}), %eax
+    mov %eax, %cr0
+    ljmpl *(ap_trampoline_data - ap_trampoline_start + {long_mode_pointer})
+
+.code64
+.global ap_trampoline_long_mode
+ap_trampoline_long_mode:
+    mov ${data_selector}, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov ap_trampoline_data + {stack}(%rip), %rsp
+    mov ap_trampoline_data + {argument}(%rip), %rdi
+    mov ap_trampoline_data + {entry}(%rip), %rax
+    call *%rax
+    ud2
+
+.balign 16
+ap_trampoline_data:
+    .skip {data_size}
+.global ap_trampoline_end
+ap_trampoline_end:
+.popsection
+"#,
+    gdt_pointer = const offset_of!(TrampolineData, gdt_limit),
+    long_mode_pointer = const offset_of!(TrampolineData, long_mode_offset),
+    cr0 = const offset_of!(TrampolineData, cr0),
+    cr3 = const offset_of!(TrampolineData, cr3),
+    cr4 = const offset_of!(TrampolineData, cr4),
+    efer = const offset_of!(TrampolineData, efer),
+    stack = const offset_of!(TrampolineData, stack),
+    entry = const offset_of!(TrampolineData, entry),
+    argument = const offset_of!(TrampolineData, argument),
+    data_selector = const DATA_SELECTOR,
+    data_size = const size_of::<TrampolineData>(),
+    options(att_syntax)
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Offset of a trampoline symbol from its start.
+fn offset_of_symbol(symbol: *const u8) -> u64 {
+    symbol as u64 - ptr::addr_of!(ap_trampoline_start) as u64
+}
+
+/// The trampoline, copied to a page below 1 MiB.
+pub struct Trampoline {
+    frame: PhysFrame,
+}
+
+impl Trampoline {
+    /// Copies the trampoline to the low frame the frame allocator set aside
+    /// and identity maps it. `None` if that isn't possible.
+    pub fn install() -> Option<Trampoline> {
+        // The trampoline can only load a 32-bit CR3
+        if Cr3::read().0.start_address() >= PhysAddr::new(1 << 32) {
+            return None;
+        }
+
+        let frame = memory::frame_allocator().take_low_frame()?;
+        let address = frame.start_address();
+
+        // Enabling paging must not pull the code out from under the CPU
+        let page = Page::containing_address(VirtAddr::new(address.as_u64()));
+        match memory::translate(page.start_address()) {
+            Some(mapped) if mapped == address => {}
+            Some(_) => return None,
+            None => {
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                let mut frame_allocator = memory::frame_allocator();
+                unsafe { memory::map_page(page, frame, flags, &mut *frame_allocator).ok()? };
+            }
+        }
+
+        let trampoline = Trampoline { frame };
+        let start = ptr::addr_of!(ap_trampoline_start);
+        let size = offset_of_symbol(ptr::addr_of!(ap_trampoline_end)) as usize;
+        unsafe { ptr::copy_nonoverlapping(start, trampoline.virt(0).as_mut_ptr(), size) };
+        Some(trampoline)
+    }
+
+    /// Where `offset` into the trampoline is in the physical memory mapping,
+    /// which is writable whatever the identity mapping is.
+    fn virt(&self, offset: u64) -> VirtAddr {
+        memory::physical_memory_offset() + self.frame.start_address().as_u64() + offset
+    }
+
+    /// Page number to send in the startup IPI.
+    pub fn startup_page(&self) -> u8 {
+        (self.frame.start_address().as_u64() >> 12) as u8
+    }
+
+    /// Prepares the trampoline to call `entry(cpu)` on the given stack.
+    pub fn prepare(&self, stack: u64, entry: extern "C" fn(&'static PerCpu) -> !, cpu: &'static PerCpu) {
+        let base = self.frame.start_address().as_u64();
+        let data_offset = offset_of_symbol(ptr::addr_of!(ap_trampoline_end)) - size_of::<TrampolineData>() as u64;
+
+        let data = TrampolineData {
+            gdt: [0, 0x0020_9a00_0000_0000, 0x0000_9200_0000_0000],
+            _padding: [0; 3],
+            gdt_limit: 3 * 8 - 1,
+            gdt_base: (base + data_offset) as u32,
+            long_mode_offset: (base + offset_of_symbol(ptr::addr_of!(ap_trampoline_long_mode))) as u32,
+            long_mode_selector: CODE_SELECTOR,
+            cr0: Cr0::read_raw(),
+            cr3: Cr3::read().0.start_address().as_u64(),
+            // PCIDs can only be enabled in long mode
+            cr4: Cr4::read_raw() & !Cr4Flags::PCID.bits(),
+            // LMA is set by the processor
+            efer: Efer::read_raw() & !EferFlags::LONG_MODE_ACTIVE.bits(),
+            stack,
+            entry: entry as usize as u64,
+            argument: cpu as *const PerCpu as u64,
+        };
+        unsafe { ptr::write_volatile(self.virt(data_offset).as_mut_ptr(), data) };
+    }
+}
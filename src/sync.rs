@@ -0,0 +1,252 @@
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::panic::Location;
+use spin::{Mutex, MutexGuard};
+use x86_64::instructions::interrupts;
+
+#[cfg(debug_assertions)]
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+
+/// In debug builds, spins on a lock before it is reported as a possible
+/// deadlock.
+#[cfg(debug_assertions)]
+const DEADLOCK_SPINS: u64 = 50_000_000;
+
+/// A spin lock that keeps interrupts disabled while it is held.
+///
+/// A plain `spin::Mutex` deadlocks when an interrupt handler tries to take
+/// it while the interrupted code holds it. With interrupts off, the holder
+/// can't be interrupted (or preempted by the scheduler) until it releases
+/// the lock. Dropping the guard restores the interrupt flag to what it was
+/// before locking, so guards nest.
+///
+/// Debug builds also remember where and on which CPU the lock was taken. A
+/// lock that can't be taken for a long time is reported on COM1 as a
+/// possible deadlock, together with that call site.
+pub struct IrqSafeMutex<T> {
+    inner: Mutex<T>,
+    name: &'static str,
+    #[cfg(debug_assertions)]
+    owner: AtomicPtr<Location<'static>>,
+    #[cfg(debug_assertions)]
+    owner_cpu: AtomicU32,
+}
+
+/// Guard returned by [`IrqSafeMutex::lock`].
+pub struct IrqSafeMutexGuard<'a, T> {
+    // `None` only while dropping, so the lock is released before interrupts
+    // are enabled again
+    guard: Option<MutexGuard<'a, T>>,
+    interrupts_were_enabled: bool,
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self::named("unnamed lock", value)
+    }
+
+    /// A lock with a name for deadlock reports.
+    pub const fn named(name: &'static str, value: T) -> Self {
+        IrqSafeMutex {
+            inner: Mutex::new(value),
+            name,
+            #[cfg(debug_assertions)]
+            owner: AtomicPtr::new(core::ptr::null_mut()),
+            #[cfg(debug_assertions)]
+            owner_cpu: AtomicU32::new(0),
+        }
+    }
+
+    /// Disables interrupts and spins until the lock is free.
+    #[track_caller]
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        IrqSafeMutexGuard {
+            guard: Some(self.acquire(Location::caller())),
+            interrupts_were_enabled,
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    fn acquire(&self, _caller: &'static Location<'static>) -> MutexGuard<'_, T> {
+        self.inner.lock()
+    }
+
+    #[cfg(debug_assertions)]
+    fn acquire(&self, caller: &'static Location<'static>) -> MutexGuard<'_, T> {
+        let mut spins = 0u64;
+        let guard = loop {
+            if let Some(guard) = self.inner.try_lock() {
+                break guard;
+            }
+            spins += 1;
+            if spins == DEADLOCK_SPINS {
+                self.report_deadlock(caller);
+            }
+            core::hint::spin_loop();
+        };
+        self.set_owner(caller);
+        guard
+    }
+
+    #[cfg(debug_assertions)]
+    fn set_owner(&self, caller: &'static Location<'static>) {
+        self.owner.store(caller as *const _ as *mut _, Ordering::Relaxed);
+        self.owner_cpu.store(current_cpu(), Ordering::Relaxed);
+    }
+
+    /// Writes straight to COM1, since the contended lock may be `SERIAL1`.
+    #[cfg(debug_assertions)]
+    fn report_deadlock(&self, caller: &'static Location<'static>) {
+        use core::fmt::Write;
+
+        let mut serial = crate::serial::raw_port();
+        let _ = write!(serial, "possible deadlock on {}", self.name);
+        let owner = self.owner.load(Ordering::Relaxed);
+        if !owner.is_null() {
+            let owner = unsafe { &*owner };
+            let _ = write!(
+                serial,
+                " held since {}:{} on CPU {}",
+                owner.file(),
+                owner.line(),
+                self.owner_cpu.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(
+            serial,
+            ", wanted at {}:{} on CPU {}",
+            caller.file(),
+            caller.line(),
+            current_cpu()
+        );
+    }
+
+    /// Takes the lock if it is free. Interrupts are left as they are if it
+    /// isn't.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let interrupts_were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(debug_assertions)]
+                self.set_owner(Location::caller());
+                Some(IrqSafeMutexGuard {
+                    guard: Some(guard),
+                    interrupts_were_enabled,
+                })
+            }
+            None => {
+                if interrupts_were_enabled {
+                    interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// # Safety
+    ///
+    /// Only for recovering from a fault in code that held the lock. Whoever
+    /// held it must never touch the data again.
+    pub unsafe fn force_unlock(&self) {
+        unsafe { self.inner.force_unlock() };
+    }
+}
+
+/// The CPU recorded as the owner of a lock.
+#[cfg(debug_assertions)]
+fn current_cpu() -> u32 {
+    if crate::apic::is_enabled() {
+        crate::apic::local_apic_id() as u32
+    } else {
+        0
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for IrqSafeMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IrqSafeMutex")
+            .field("name", &self.name)
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl<T> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<T> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<T> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.guard = None;
+        if self.interrupts_were_enabled {
+            interrupts::enable();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_lock_disables_interrupts() {
+        let mutex = IrqSafeMutex::new(0);
+        assert!(interrupts::are_enabled());
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(!interrupts::are_enabled());
+        }
+        assert!(interrupts::are_enabled());
+        assert_eq!(*mutex.lock(), 1);
+    }
+
+    #[test_case]
+    fn test_nested_guards_restore_interrupts_last() {
+        let first = IrqSafeMutex::new(());
+        let second = IrqSafeMutex::new(());
+        let outer = first.lock();
+        let inner = second.lock();
+        drop(inner);
+        assert!(!interrupts::are_enabled());
+        drop(outer);
+        assert!(interrupts::are_enabled());
+    }
+
+    #[test_case]
+    fn test_lock_records_owner() {
+        let mutex = IrqSafeMutex::named("test", ());
+        let line = line!() + 1;
+        let guard = mutex.lock();
+        let owner = unsafe { &*mutex.owner.load(Ordering::Relaxed) };
+        assert_eq!((owner.file(), owner.line()), (file!(), line));
+        drop(guard);
+    }
+
+    #[test_case]
+    fn test_try_lock_when_locked() {
+        let mutex = IrqSafeMutex::new(());
+        let guard = mutex.try_lock().unwrap();
+        assert!(mutex.try_lock().is_none());
+        // The failed attempt must not enable interrupts under the guard
+        assert!(!interrupts::are_enabled());
+        drop(guard);
+        assert!(interrupts::are_enabled());
+    }
+}
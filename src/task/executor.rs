@@ -0,0 +1,163 @@
+// executor.rs - Task executor that sleeps with hlt while idle
+
+use super::{Task, TaskId, SPAWN_QUEUE};
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::task::{Context, Poll, Waker};
+use crossbeam_queue::ArrayQueue;
+
+const TASK_QUEUE_SIZE: usize = 100;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+    waker_cache: BTreeMap<TaskId, Waker>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(ArrayQueue::new(TASK_QUEUE_SIZE)),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let task_id = task.id;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        self.task_queue.push(task_id).expect("task queue full");
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.spawn_queued_tasks();
+            crate::time::wake_sleepers();
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn spawn_queued_tasks(&mut self) {
+        while let Some(task) = SPAWN_QUEUE.lock().pop_front() {
+            self.spawn(task);
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        // destructure `self` to avoid borrow checker errors
+        let Self {
+            tasks,
+            task_queue,
+            waker_cache,
+        } = self;
+
+        while let Some(task_id) = task_queue.pop() {
+            let task = match tasks.get_mut(&task_id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+            let waker = waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::new_waker(task_id, task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+            match task.poll(&mut context) {
+                Poll::Ready(()) => {
+                    // task done -> remove it and its cached waker
+                    tasks.remove(&task_id);
+                    waker_cache.remove(&task_id);
+                }
+                Poll::Pending => {}
+            }
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        use x86_64::instructions::interrupts;
+
+        // Check again with interrupts off so a wakeup from an interrupt
+        // handler can't be missed while halted
+        interrupts::disable();
+        if self.task_queue.is_empty() && SPAWN_QUEUE.lock().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: Arc<ArrayQueue<TaskId>>,
+}
+
+impl TaskWaker {
+    fn new_waker(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+        Waker::from(Arc::new(TaskWaker {
+            task_id,
+            task_queue,
+        }))
+    }
+
+    fn wake_task(&self) {
+        self.task_queue.push(self.task_id).expect("task queue full");
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test_case]
+    fn test_tasks_run_to_completion() {
+        static STEPS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            STEPS.fetch_add(1, Ordering::Relaxed);
+            crate::task::yield_now().await;
+            STEPS.fetch_add(1, Ordering::Relaxed);
+        }));
+        executor.run_ready_tasks();
+
+        assert_eq!(STEPS.load(Ordering::Relaxed), 2);
+        assert!(executor.tasks.is_empty());
+    }
+
+    #[test_case]
+    fn test_spawn_from_task() {
+        static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+
+        let mut executor = Executor::new();
+        executor.spawn(Task::new(async {
+            crate::task::spawn(Task::new(async {
+                SPAWNED.fetch_add(1, Ordering::Relaxed);
+            }));
+        }));
+        executor.run_ready_tasks();
+        executor.spawn_queued_tasks();
+        executor.run_ready_tasks();
+
+        assert_eq!(SPAWNED.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,73 @@
+// mod.rs - Cooperative async tasks
+
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+pub mod executor;
+
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()> + Send>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + Send + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+lazy_static! {
+    /// Tasks spawned from inside other tasks, picked up by the executor.
+    pub(crate) static ref SPAWN_QUEUE: Mutex<VecDeque<Task>> = Mutex::new(VecDeque::new());
+}
+
+/// Hands a task to the running executor. Can be called from any task.
+pub fn spawn(task: Task) {
+    SPAWN_QUEUE.lock().push_back(task);
+}
+
+/// Gives other tasks a chance to run before continuing.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if self.yielded {
+            Poll::Ready(())
+        } else {
+            self.yielded = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
@@ -0,0 +1,64 @@
+// context.rs - Stack setup and context switching between kernel threads
+
+use core::arch::global_asm;
+
+// Saves the callee-saved registers on the current stack, stores the stack
+// pointer in `*old_rsp` and resumes the thread whose stack pointer is
+// `new_rsp`. Everything else was already saved by the caller, which is
+// either `schedule` or the timer interrupt handler below it.
+global_asm!(
+    r#"
+.global switch_context
+switch_context:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rdi], rsp
+    mov rsp, rsi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#
+);
+
+extern "C" {
+    /// Must be called with interrupts disabled.
+    pub fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+}
+
+/// Registers popped by `switch_context` before it returns.
+const SAVED_REGISTERS: usize = 6;
+
+/// Prepares a fresh stack so that switching to it "returns" into `entry`.
+/// Returns the initial stack pointer.
+///
+/// # Safety
+///
+/// `stack` must stay allocated for as long as the thread can run.
+pub unsafe fn init_stack(stack: &mut [u8], entry: extern "C" fn() -> !) -> u64 {
+    let top = (stack.as_mut_ptr() as u64 + stack.len() as u64) & !0xf;
+    let mut sp = top as *mut u64;
+
+    unsafe {
+        // Fake return address, so `entry` sees the usual call alignment
+        sp = sp.sub(1);
+        sp.write(0);
+
+        sp = sp.sub(1);
+        sp.write(entry as *const () as u64);
+
+        for _ in 0..SAVED_REGISTERS {
+            sp = sp.sub(1);
+            sp.write(0);
+        }
+    }
+
+    sp as u64
+}
@@ -0,0 +1,347 @@
+// mod.rs - Preemptive kernel threads
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::{Mutex, Once};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+mod context;
+mod scheduler;
+
+use scheduler::Scheduler;
+
+const STACK_SIZE: usize = 16 * 1024;
+
+/// The thread the kernel booted on. It runs the task executor.
+pub const MAIN_THREAD: ThreadId = ThreadId(0);
+
+static SCHEDULER: Once<Mutex<Scheduler>> = Once::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Ready threads of a higher priority always run first; threads of the
+/// same priority take turns.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl Priority {
+    const LEVELS: usize = 3;
+
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::High => "High",
+            Priority::Normal => "Normal",
+            Priority::Low => "Low",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Sleeping { until: u64 },
+    Joining(ThreadId),
+    Finished,
+}
+
+impl ThreadState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Sleeping { .. } => "sleeping",
+            ThreadState::Joining(_) => "joining",
+            ThreadState::Finished => "finished",
+        }
+    }
+}
+
+/// What a thread running a user program needs whenever it is switched to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserContext {
+    /// The program's level 4 table.
+    pub page_table: PhysFrame,
+    /// Where the thread enters the kernel from user mode.
+    pub kernel_stack: VirtAddr,
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    priority: Priority,
+    state: ThreadState,
+    rsp: u64,
+    stack: Option<Box<[u8]>>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    user: Option<UserContext>,
+}
+
+impl Thread {
+    fn new(name: &str, priority: Priority, entry: Option<Box<dyn FnOnce() + Send>>) -> Box<Self> {
+        let mut stack = vec![0u8; STACK_SIZE].into_boxed_slice();
+        let rsp = unsafe { context::init_stack(&mut stack, thread_entry) };
+
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name: name.to_string(),
+            priority,
+            state: ThreadState::Ready,
+            rsp,
+            stack: Some(stack),
+            entry,
+            user: None,
+        })
+    }
+}
+
+/// A snapshot of one thread, as shown by `ps`.
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub priority: Priority,
+    pub state: ThreadState,
+}
+
+/// Turns the running context into the main thread and starts the idle
+/// thread. Needs the heap.
+pub fn init() {
+    SCHEDULER.call_once(|| {
+        let idle = Thread::new("idle", Priority::Low, Some(Box::new(idle_loop)));
+        Mutex::new(Scheduler::new(idle))
+    });
+}
+
+fn scheduler() -> &'static Mutex<Scheduler> {
+    SCHEDULER.wait().expect("thread::init not called")
+}
+
+/// Starts a new kernel thread running `entry`.
+pub fn spawn(name: &str, priority: Priority, entry: impl FnOnce() + Send + 'static) -> ThreadId {
+    reap();
+    let thread = Thread::new(name, priority, Some(Box::new(entry)));
+    let id = thread.id;
+    interrupts::without_interrupts(|| scheduler().lock().add(thread));
+    log::debug!("Spawned thread {} ({})", id.as_u64(), name);
+    id
+}
+
+pub fn current() -> ThreadId {
+    match SCHEDULER.wait() {
+        Some(scheduler) => interrupts::without_interrupts(|| scheduler.lock().current()),
+        None => MAIN_THREAD,
+    }
+}
+
+/// Lets other ready threads of the same or higher priority run first.
+pub fn yield_now() {
+    interrupts::without_interrupts(schedule);
+}
+
+/// Blocks the calling thread for at least `ticks` timer ticks.
+pub fn sleep(ticks: u64) {
+    interrupts::without_interrupts(|| {
+        {
+            let mut scheduler = scheduler().lock();
+            let until = crate::time::ticks().saturating_add(ticks);
+            scheduler.current_mut().state = ThreadState::Sleeping { until };
+        }
+        schedule();
+    });
+}
+
+/// Waits until thread `id` has finished. Returns immediately if there is
+/// no such thread.
+pub fn join(id: ThreadId) {
+    loop {
+        let finished = interrupts::without_interrupts(|| {
+            {
+                let mut scheduler = scheduler().lock();
+                match scheduler.state(id) {
+                    None => return Some(None),
+                    Some(ThreadState::Finished) => return Some(scheduler.remove(id)),
+                    Some(_) => scheduler.current_mut().state = ThreadState::Joining(id),
+                }
+            }
+            schedule();
+            None
+        });
+
+        // Freed here rather than in the scheduler, which runs in the timer
+        // interrupt handler
+        if let Some(thread) = finished {
+            drop(thread);
+            return;
+        }
+    }
+}
+
+/// Frees the stacks of threads that finished without being joined.
+fn reap() {
+    if let Some(scheduler) = SCHEDULER.wait() {
+        let stacks = interrupts::without_interrupts(|| scheduler.lock().take_finished_stacks());
+        drop(stacks);
+    }
+}
+
+/// Ends the calling thread. Its stack is freed when it is joined or the
+/// next thread is spawned.
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = scheduler().lock();
+        let id = scheduler.current();
+        assert_ne!(id, MAIN_THREAD, "the main thread cannot exit");
+        scheduler.current_mut().state = ThreadState::Finished;
+        scheduler.wake_joiners(id);
+    }
+    schedule();
+    unreachable!("finished thread was scheduled again");
+}
+
+/// Makes the calling thread run a user program in `context`, or back in
+/// the kernel's address space with `None`. Takes effect immediately and
+/// again whenever the thread is switched to.
+pub fn set_user_context(context: Option<UserContext>) {
+    interrupts::without_interrupts(|| {
+        scheduler().lock().current_mut().user = context;
+        enter_address_space(context);
+    });
+}
+
+/// The user context of the calling thread, if it runs a user program.
+pub fn user_context() -> Option<UserContext> {
+    interrupts::without_interrupts(|| scheduler().lock().current_mut().user)
+}
+
+pub fn list() -> Vec<ThreadInfo> {
+    interrupts::without_interrupts(|| scheduler().lock().list())
+}
+
+/// Called by the timer interrupt handler after its EOI. Switches to the
+/// next thread, which is what makes threads preemptive.
+pub(crate) fn on_timer_tick() {
+    if let Some(scheduler) = SCHEDULER.wait() {
+        scheduler.lock().wake_sleeping(crate::time::ticks());
+        schedule();
+    }
+}
+
+/// Releases the scheduler lock after a fault hit while it was held.
+///
+/// # Safety
+///
+/// Only for fault recovery, when the holder will never run again.
+pub(crate) unsafe fn force_unlock() {
+    if let Some(scheduler) = SCHEDULER.wait() {
+        unsafe { scheduler.force_unlock() };
+    }
+}
+
+/// Switches to the next thread to run, if it isn't the current one. Must
+/// be called with interrupts disabled.
+fn schedule() {
+    let (switch, user) = {
+        let mut scheduler = scheduler().lock();
+        let switch = scheduler.switch_next();
+        (switch, scheduler.current_mut().user)
+    };
+    if let Some((old_rsp, new_rsp)) = switch {
+        enter_address_space(user);
+        unsafe { context::switch_context(old_rsp, new_rsp) };
+    }
+}
+
+/// Loads the page table for `user` (the kernel's for `None`) and points
+/// the kernel stack for user mode at the thread's.
+fn enter_address_space(user: Option<UserContext>) {
+    let page_table = user.map_or_else(crate::memory::kernel_page_table, |user| user.page_table);
+    let (active, flags) = Cr3::read();
+    if active != page_table {
+        unsafe { Cr3::write(page_table, flags) };
+    }
+    if let (Some(user), Some(cpu)) = (user, crate::smp::current()) {
+        cpu.set_kernel_stack(user.kernel_stack);
+    }
+}
+
+/// First code run by every new thread, entered from `switch_context`.
+extern "C" fn thread_entry() -> ! {
+    let entry = scheduler().lock().current_mut().entry.take();
+    interrupts::enable();
+
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+fn idle_loop() {
+    crate::hlt_loop();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicBool;
+
+    #[test_case]
+    fn test_spawn_and_join() {
+        static RAN: AtomicBool = AtomicBool::new(false);
+
+        let id = spawn("test", Priority::Normal, || RAN.store(true, Ordering::Relaxed));
+        join(id);
+
+        assert!(RAN.load(Ordering::Relaxed));
+        assert!(list().iter().all(|thread| thread.id != id));
+    }
+
+    #[test_case]
+    fn test_threads_take_turns() {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+        let id = spawn("test", Priority::Normal, || {
+            for _ in 0..3 {
+                COUNTER.fetch_add(1, Ordering::Relaxed);
+                yield_now();
+            }
+        });
+        yield_now();
+        assert!(COUNTER.load(Ordering::Relaxed) >= 1);
+
+        join(id);
+        assert_eq!(COUNTER.load(Ordering::Relaxed), 3);
+    }
+
+    #[test_case]
+    fn test_sleep() {
+        sleep(1);
+        assert_eq!(current(), MAIN_THREAD);
+    }
+}
@@ -0,0 +1,211 @@
+// scheduler.rs - Priority round-robin scheduler
+
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use super::{Priority, Thread, ThreadId, ThreadInfo, ThreadState, MAIN_THREAD};
+
+pub struct Scheduler {
+    // Boxed so a thread's saved stack pointer keeps its address while
+    // `switch_context` writes to it
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    ready: [VecDeque<ThreadId>; Priority::LEVELS],
+    current: ThreadId,
+    idle: ThreadId,
+}
+
+impl Scheduler {
+    /// Creates a scheduler with the calling context as the main thread.
+    pub fn new(idle: Box<Thread>) -> Self {
+        let main = Box::new(Thread {
+            id: MAIN_THREAD,
+            name: String::from("main"),
+            priority: Priority::Normal,
+            state: ThreadState::Running,
+            rsp: 0,
+            stack: None,
+            entry: None,
+            user: None,
+        });
+
+        let mut threads = BTreeMap::new();
+        let idle_id = idle.id;
+        threads.insert(MAIN_THREAD, main);
+        threads.insert(idle_id, idle);
+
+        let mut scheduler = Scheduler {
+            threads,
+            ready: [VecDeque::new(), VecDeque::new(), VecDeque::new()],
+            current: MAIN_THREAD,
+            idle: idle_id,
+        };
+        scheduler.reserve_queues();
+        scheduler
+    }
+
+    pub fn add(&mut self, thread: Box<Thread>) {
+        let (id, queue) = (thread.id, thread.priority.index());
+        self.threads.insert(id, thread);
+        self.reserve_queues();
+        self.ready[queue].push_back(id);
+    }
+
+    /// Makes room in every ready queue for all threads, each of which is
+    /// queued at most once. The timer interrupt handler switches threads
+    /// and must not allocate: the heap's lock may be held by the code it
+    /// interrupted.
+    fn reserve_queues(&mut self) {
+        let threads = self.threads.len();
+        for queue in &mut self.ready {
+            queue.reserve(threads.saturating_sub(queue.len()));
+        }
+    }
+
+    pub fn current(&self) -> ThreadId {
+        self.current
+    }
+
+    pub fn current_mut(&mut self) -> &mut Thread {
+        self.threads
+            .get_mut(&self.current)
+            .expect("current thread missing")
+    }
+
+    pub fn state(&self, id: ThreadId) -> Option<ThreadState> {
+        self.threads.get(&id).map(|thread| thread.state)
+    }
+
+    /// Takes thread `id` out of the scheduler, for the caller to free
+    /// outside the lock.
+    pub fn remove(&mut self, id: ThreadId) -> Option<Box<Thread>> {
+        self.threads.remove(&id)
+    }
+
+    /// Takes the stacks of finished threads, which nothing runs on any
+    /// more, for the caller to free outside the lock.
+    pub fn take_finished_stacks(&mut self) -> Vec<Box<[u8]>> {
+        let current = self.current;
+        self.threads
+            .values_mut()
+            .filter(|thread| thread.state == ThreadState::Finished && thread.id != current)
+            .filter_map(|thread| thread.stack.take())
+            .collect()
+    }
+
+    /// Wakes the threads whose sleep is over at tick `now`.
+    pub fn wake_sleeping(&mut self, now: u64) {
+        let ready = &mut self.ready;
+        for thread in self.threads.values_mut() {
+            if let ThreadState::Sleeping { until } = thread.state {
+                if until <= now {
+                    thread.state = ThreadState::Ready;
+                    ready[thread.priority.index()].push_back(thread.id);
+                }
+            }
+        }
+    }
+
+    /// Moves the threads joining `id` back to their ready queues.
+    pub fn wake_joiners(&mut self, id: ThreadId) {
+        let ready = &mut self.ready;
+        for thread in self.threads.values_mut() {
+            if thread.state == ThreadState::Joining(id) {
+                thread.state = ThreadState::Ready;
+                ready[thread.priority.index()].push_back(thread.id);
+            }
+        }
+    }
+
+    /// Picks the next thread to run. Returns where to save the current
+    /// stack pointer and the stack pointer to switch to, or `None` if the
+    /// current thread keeps running.
+    pub fn switch_next(&mut self) -> Option<(*mut u64, u64)> {
+        let current = self.current;
+        let current_thread = self.threads.get_mut(&current)?;
+        if current_thread.state == ThreadState::Running {
+            current_thread.state = ThreadState::Ready;
+            if current != self.idle {
+                self.ready[current_thread.priority.index()].push_back(current);
+            }
+        }
+
+        let next = self
+            .ready
+            .iter_mut()
+            .find_map(|queue| queue.pop_front())
+            .unwrap_or(self.idle);
+
+        self.threads.get_mut(&next)?.state = ThreadState::Running;
+        if next == current {
+            return None;
+        }
+        self.current = next;
+
+        let new_rsp = self.threads[&next].rsp;
+        let old_rsp = &mut self.threads.get_mut(&current)?.rsp as *mut u64;
+        Some((old_rsp, new_rsp))
+    }
+
+    pub fn list(&self) -> Vec<ThreadInfo> {
+        self.threads
+            .values()
+            .map(|thread| ThreadInfo {
+                id: thread.id,
+                name: thread.name.clone(),
+                priority: thread.priority,
+                state: thread.state,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_thread(name: &str, priority: Priority) -> Box<Thread> {
+        Thread::new(name, priority, None)
+    }
+
+    #[test_case]
+    fn test_higher_priority_runs_first() {
+        let mut scheduler = Scheduler::new(test_thread("idle", Priority::Low));
+        let high = test_thread("high", Priority::High);
+        let high_id = high.id;
+        scheduler.add(test_thread("low", Priority::Low));
+        scheduler.add(high);
+
+        assert!(scheduler.switch_next().is_some());
+        assert_eq!(scheduler.current(), high_id);
+
+        // Nothing else at high priority, so it keeps running
+        assert!(scheduler.switch_next().is_none());
+        assert_eq!(scheduler.current(), high_id);
+
+        scheduler.current_mut().state = ThreadState::Finished;
+        assert!(scheduler.switch_next().is_some());
+        assert_eq!(scheduler.current(), MAIN_THREAD);
+
+        // Its stack stays until it is taken outside the timer interrupt
+        assert!(scheduler.threads[&high_id].stack.is_some());
+        assert_eq!(scheduler.take_finished_stacks().len(), 1);
+        assert!(scheduler.threads[&high_id].stack.is_none());
+    }
+
+    #[test_case]
+    fn test_sleeping_thread_wakes_after_ticks() {
+        let mut scheduler = Scheduler::new(test_thread("idle", Priority::Low));
+        scheduler.current_mut().state = ThreadState::Sleeping { until: 2 };
+
+        assert!(scheduler.switch_next().is_some());
+        assert_eq!(scheduler.state(MAIN_THREAD), Some(ThreadState::Sleeping { until: 2 }));
+
+        scheduler.wake_sleeping(1);
+        assert_eq!(scheduler.state(MAIN_THREAD), Some(ThreadState::Sleeping { until: 2 }));
+        scheduler.wake_sleeping(2);
+        assert_eq!(scheduler.state(MAIN_THREAD), Some(ThreadState::Ready));
+        assert!(scheduler.switch_next().is_some());
+        assert_eq!(scheduler.current(), MAIN_THREAD);
+    }
+}
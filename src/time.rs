@@ -0,0 +1,137 @@
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+/// Input clock of the programmable interval timer, in Hz.
+const PIT_BASE_FREQUENCY: u64 = 1_193_182;
+/// Timer interrupts per second we ask the PIT for.
+pub const TICK_RATE: u64 = 1000;
+const PIT_DIVISOR: u64 = PIT_BASE_FREQUENCY / TICK_RATE;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+/// Channel 0, low byte then high byte, mode 3 (square wave), binary.
+const PIT_MODE: u8 = 0x36;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Async sleepers as (deadline tick, waker), checked by the executor.
+static SLEEPERS: Mutex<Vec<(u64, Waker)>> = Mutex::new(Vec::new());
+
+/// Programs PIT channel 0 to fire the timer interrupt `TICK_RATE` times a
+/// second.
+pub fn init() {
+    let mut command = Port::<u8>::new(PIT_COMMAND);
+    let mut channel0 = Port::<u8>::new(PIT_CHANNEL0);
+
+    unsafe {
+        command.write(PIT_MODE);
+        channel0.write((PIT_DIVISOR & 0xff) as u8);
+        channel0.write((PIT_DIVISOR >> 8) as u8);
+    }
+}
+
+/// Called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Timer ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn ticks_to_ms(ticks: u64) -> u64 {
+    // The divisor is rounded, so a tick isn't exactly 1 ms
+    ticks * PIT_DIVISOR * 1000 / PIT_BASE_FREQUENCY
+}
+
+/// Rounds up, so sleeping never ends early. Saturates at `u64::MAX`, which
+/// is as good as forever.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let ticks = (u128::from(ms) * u128::from(PIT_BASE_FREQUENCY)).div_ceil(u128::from(1000 * PIT_DIVISOR));
+    u64::try_from(ticks).unwrap_or(u64::MAX)
+}
+
+/// Milliseconds since boot.
+pub fn uptime_ms() -> u64 {
+    ticks_to_ms(ticks())
+}
+
+/// Blocks the calling thread for at least `ms` milliseconds, letting the
+/// CPU halt or run other threads meanwhile.
+pub fn sleep_ms(ms: u64) {
+    // Sleeping from one tick boundary to the next could take less than a
+    // full tick, so wait one extra
+    crate::thread::sleep(ms_to_ticks(ms).saturating_add(1));
+}
+
+/// Future that completes after at least `ms` milliseconds, without
+/// blocking the other tasks.
+pub fn sleep(ms: u64) -> Sleep {
+    Sleep {
+        deadline: ticks().saturating_add(ms_to_ticks(ms)).saturating_add(1),
+        registered: false,
+    }
+}
+
+pub struct Sleep {
+    deadline: u64,
+    registered: bool,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+
+        if !self.registered {
+            SLEEPERS.lock().push((self.deadline, cx.waker().clone()));
+            self.registered = true;
+        }
+        Poll::Pending
+    }
+}
+
+/// Wakes the tasks whose sleep is over. Called by the executor, which the
+/// timer interrupt gets out of `hlt` on every tick.
+pub(crate) fn wake_sleepers() {
+    let now = ticks();
+    SLEEPERS.lock().retain(|(deadline, waker)| {
+        if *deadline <= now {
+            waker.wake_by_ref();
+            false
+        } else {
+            true
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_ms_tick_conversion() {
+        assert_eq!(ms_to_ticks(0), 0);
+        assert_eq!(ticks_to_ms(TICK_RATE), 999);
+        for ms in [1, 10, 999, 1500, 60_000] {
+            assert!(ticks_to_ms(ms_to_ticks(ms)) >= ms);
+        }
+        assert_eq!(ms_to_ticks(u64::MAX), u64::MAX);
+    }
+
+    #[test_case]
+    fn test_sleep_ms_waits() {
+        let start = uptime_ms();
+        sleep_ms(20);
+        assert!(uptime_ms() - start >= 20);
+    }
+}
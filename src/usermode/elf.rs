@@ -0,0 +1,298 @@
+// elf.rs - Loading ELF64 executables into an address space
+
+use core::fmt;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory::{AddressSpace, USER_END, USER_START};
+use super::LoadError;
+
+const MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const LITTLE_ENDIAN: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+const MACHINE_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const DYNAMIC_SIZE: usize = 16;
+const RELA_SIZE: u64 = 24;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+
+const R_X86_64_NONE: u64 = 0;
+const R_X86_64_RELATIVE: u64 = 8;
+
+/// Why a file can't be loaded as an ELF executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    /// Not a 64-bit little-endian x86_64 executable.
+    Unsupported,
+    /// A header or segment points past the end of the file.
+    Truncated,
+    /// A segment or the entry point is outside the user region.
+    BadAddress,
+    /// A relocation other than `R_X86_64_RELATIVE`.
+    BadRelocation,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = match self {
+            ElfError::NotElf => "not an ELF file",
+            ElfError::Unsupported => "not a 64-bit x86_64 executable",
+            ElfError::Truncated => "file is truncated",
+            ElfError::BadAddress => "address outside user memory",
+            ElfError::BadRelocation => "unsupported relocation",
+        };
+        f.write_str(message)
+    }
+}
+
+/// A checked ELF64 executable. Static executables (`ET_EXEC`) are loaded
+/// where they were linked; position-independent ones (`ET_DYN`, as built
+/// for `x86_64-unknown-none`) at the start of the user region, with their
+/// relative relocations applied.
+pub struct Elf<'a> {
+    data: &'a [u8],
+    base: u64,
+    entry: u64,
+    program_headers: usize,
+    program_header_size: usize,
+    program_header_count: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    address: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+impl<'a> Elf<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.get(..4) != Some(MAGIC) {
+            return Err(ElfError::NotElf);
+        }
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[4] != CLASS_64 || data[5] != LITTLE_ENDIAN || read_u16(data, 18) != Some(MACHINE_X86_64) {
+            return Err(ElfError::Unsupported);
+        }
+        let base = match read_u16(data, 16) {
+            Some(TYPE_EXEC) => 0,
+            Some(TYPE_DYN) => USER_START,
+            _ => return Err(ElfError::Unsupported),
+        };
+
+        let elf = Elf {
+            data,
+            base,
+            entry: read_u64(data, 24).ok_or(ElfError::Truncated)?,
+            program_headers: read_u64(data, 32).ok_or(ElfError::Truncated)? as usize,
+            program_header_size: read_u16(data, 54).ok_or(ElfError::Truncated)? as usize,
+            program_header_count: read_u16(data, 56).ok_or(ElfError::Truncated)? as usize,
+        };
+        if elf.program_header_size < PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Unsupported);
+        }
+        let table_size = elf.program_header_size * elf.program_header_count;
+        if elf.program_headers.checked_add(table_size).is_none_or(|end| end > data.len()) {
+            return Err(ElfError::Truncated);
+        }
+        for header in elf.program_headers() {
+            if header.kind == PT_LOAD {
+                elf.check_segment(&header)?;
+            }
+        }
+        elf.user_address(elf.entry, 1)?;
+        Ok(elf)
+    }
+
+    /// Where the program starts, once loaded.
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new(self.base + self.entry)
+    }
+
+    fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.program_header_count).map(move |index| {
+            let at = self.program_headers + index * self.program_header_size;
+            let field = |offset| read_u64(self.data, at + offset).unwrap();
+            ProgramHeader {
+                kind: read_u32(self.data, at).unwrap(),
+                flags: read_u32(self.data, at + 4).unwrap(),
+                offset: field(8),
+                address: field(16),
+                file_size: field(32),
+                memory_size: field(40),
+            }
+        })
+    }
+
+    fn check_segment(&self, header: &ProgramHeader) -> Result<(), ElfError> {
+        if header.file_size > header.memory_size {
+            return Err(ElfError::Truncated);
+        }
+        self.file_bytes(header.offset, header.file_size)?;
+        self.user_address(header.address, header.memory_size)?;
+        Ok(())
+    }
+
+    /// `size` bytes of the file at `offset`.
+    fn file_bytes(&self, offset: u64, size: u64) -> Result<&'a [u8], ElfError> {
+        let start = usize::try_from(offset).map_err(|_| ElfError::Truncated)?;
+        let end = start.checked_add(size as usize).ok_or(ElfError::Truncated)?;
+        self.data.get(start..end).ok_or(ElfError::Truncated)
+    }
+
+    /// The loaded address of the `size` bytes linked at `address`, checked
+    /// to be in the user region.
+    fn user_address(&self, address: u64, size: u64) -> Result<VirtAddr, ElfError> {
+        let start = self.base.checked_add(address).ok_or(ElfError::BadAddress)?;
+        let end = start.checked_add(size).ok_or(ElfError::BadAddress)?;
+        if start < USER_START || end > USER_END {
+            return Err(ElfError::BadAddress);
+        }
+        Ok(VirtAddr::new(start))
+    }
+
+    /// Maps the `PT_LOAD` segments into `address_space`, copies their file
+    /// contents (the rest is zero) and applies the relocations.
+    pub fn load(&self, address_space: &mut AddressSpace) -> Result<VirtAddr, LoadError> {
+        for header in self.program_headers().filter(|header| header.kind == PT_LOAD) {
+            if header.memory_size == 0 {
+                continue;
+            }
+            let start = self.user_address(header.address, header.memory_size)?;
+            let mut flags = PageTableFlags::empty();
+            if header.flags & PF_W != 0 {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if header.flags & PF_X == 0 {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+
+            let pages = Page::<Size4KiB>::range_inclusive(
+                Page::containing_address(start),
+                Page::containing_address(start + (header.memory_size - 1)),
+            );
+            for page in pages {
+                address_space.map_user(page, flags).map_err(|_| LoadError::OutOfMemory)?;
+            }
+            if !address_space.write(start, self.file_bytes(header.offset, header.file_size)?) {
+                return Err(ElfError::BadAddress.into());
+            }
+        }
+
+        for header in self.program_headers().filter(|header| header.kind == PT_DYNAMIC) {
+            self.relocate(&header, address_space)?;
+        }
+        Ok(self.entry())
+    }
+
+    fn relocate(&self, dynamic: &ProgramHeader, address_space: &mut AddressSpace) -> Result<(), ElfError> {
+        let (mut table, mut size, mut entry_size) = (None, 0, RELA_SIZE);
+        let entries = self.file_bytes(dynamic.offset, dynamic.file_size)?;
+        for entry in entries.chunks_exact(DYNAMIC_SIZE) {
+            let (tag, value) = (read_u64(entry, 0).unwrap(), read_u64(entry, 8).unwrap());
+            match tag {
+                DT_NULL => break,
+                DT_RELA => table = Some(value),
+                DT_RELASZ => size = value,
+                DT_RELAENT => entry_size = value,
+                _ => {}
+            }
+        }
+        let Some(table) = table else {
+            return Ok(());
+        };
+        if entry_size < RELA_SIZE {
+            return Err(ElfError::BadRelocation);
+        }
+
+        let relocations = self.file_bytes(self.file_offset(table, size)?, size)?;
+        for relocation in relocations.chunks_exact(entry_size as usize) {
+            let offset = read_u64(relocation, 0).unwrap();
+            let info = read_u64(relocation, 8).unwrap();
+            let addend = read_u64(relocation, 16).unwrap();
+            match info & 0xffff_ffff {
+                R_X86_64_NONE => {}
+                R_X86_64_RELATIVE => {
+                    let target = self.user_address(offset, 8)?;
+                    let value = self.base.wrapping_add(addend);
+                    if !address_space.write(target, &value.to_le_bytes()) {
+                        return Err(ElfError::BadAddress);
+                    }
+                }
+                _ => return Err(ElfError::BadRelocation),
+            }
+        }
+        Ok(())
+    }
+
+    /// The file offset of the `size` bytes linked at `address`, which have
+    /// to be in the file part of a `PT_LOAD` segment.
+    fn file_offset(&self, address: u64, size: u64) -> Result<u64, ElfError> {
+        self.program_headers()
+            .filter(|header| header.kind == PT_LOAD)
+            .find(|header| {
+                address >= header.address
+                    && address.saturating_add(size) <= header.address + header.file_size
+            })
+            .map(|header| header.offset + (address - header.address))
+            .ok_or(ElfError::Truncated)
+    }
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn read_u32(data: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(at..at + 8)?.try_into().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn test_parse_user_program() {
+        let hello = crate::initrd::find("hello").unwrap();
+        let elf = Elf::parse(hello.data).unwrap();
+        // Built position independent, so it goes to the start of user memory
+        assert_eq!(elf.base, USER_START);
+        assert!(elf.program_headers().any(|header| header.kind == PT_LOAD && header.flags & PF_X != 0));
+    }
+
+    #[test_case]
+    fn test_parse_errors() {
+        assert_eq!(Elf::parse(b"#!/bin/sh").err(), Some(ElfError::NotElf));
+        assert_eq!(Elf::parse(b"\x7fELF\x02\x01").err(), Some(ElfError::Truncated));
+
+        let hello = crate::initrd::find("hello").unwrap().data;
+        let mut header = [0; HEADER_SIZE];
+        header.copy_from_slice(&hello[..HEADER_SIZE]);
+        header[4] = 1;
+        assert_eq!(Elf::parse(&header).err(), Some(ElfError::Unsupported));
+        // The program headers are cut off
+        assert_eq!(Elf::parse(&hello[..HEADER_SIZE]).err(), Some(ElfError::Truncated));
+    }
+}
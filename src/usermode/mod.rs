@@ -0,0 +1,337 @@
+// mod.rs - Running programs in ring 3
+
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::{Page, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+use crate::memory::{self, AddressSpace};
+use crate::thread::{self, Priority, UserContext};
+
+mod elf;
+mod process;
+mod programs;
+mod syscall;
+
+pub use elf::{Elf, ElfError};
+pub use process::{
+    current, foreground, kill, list, try_wait, wait, Handle, Pid, ProcessError, ProcessInfo, State,
+    BACKGROUND, FOREGROUND,
+};
+pub use programs::{find, Program, PROGRAMS};
+use process::{Process, PROCESSES};
+
+/// Where a program's code is loaded.
+const CODE_START: u64 = memory::USER_START;
+/// The user stack ends right below this address.
+const STACK_TOP: u64 = 0x0000_7000_0000_0000;
+const STACK_SIZE: u64 = 16 * 1024;
+
+/// Interrupts on, plus the always-set reserved bit.
+const USER_RFLAGS: u64 = 0x202;
+
+/// How a user program ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// It called `exit` with this code.
+    Exited(i64),
+    /// It caused an exception.
+    Killed,
+    /// It couldn't be loaded.
+    LoadFailed(LoadError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunError {
+    /// The process table is full.
+    TooManyProcesses,
+    InvalidExecutable(ElfError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadError {
+    OutOfMemory,
+    /// The arguments don't fit in the top page of the stack.
+    ArgumentsTooLong,
+    Elf(ElfError),
+}
+
+impl From<ElfError> for LoadError {
+    fn from(error: ElfError) -> Self {
+        LoadError::Elf(error)
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunError::TooManyProcesses => f.write_str("too many processes"),
+            RunError::InvalidExecutable(error) => error.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::OutOfMemory => f.write_str("out of memory"),
+            LoadError::ArgumentsTooLong => f.write_str("arguments too long"),
+            LoadError::Elf(error) => error.fmt(f),
+        }
+    }
+}
+
+/// Enables `syscall`. Call on the boot processor after `smp::init`, which
+/// sets up the per-CPU data the entry code relies on.
+pub fn init() {
+    syscall::init();
+}
+
+/// Starts the built-in `program` in ring 3 as a new process, on a kernel
+/// thread of its own. Its status is collected with `wait` once it ends.
+pub fn run(program: &'static Program, handles: &[Handle]) -> Result<Pid, RunError> {
+    let args = vec![program.name.to_string()];
+    spawn(args, handles, None, move |address_space| load_code(address_space, program.code()))
+}
+
+/// Starts the ELF executable `image` like `run`, with `args` (the program
+/// name first) passed on its stack.
+pub fn exec(image: &'static [u8], args: &[&str], handles: &[Handle]) -> Result<Pid, RunError> {
+    spawn_elf(image, args, handles, None)
+}
+
+fn spawn_elf(
+    image: &'static [u8],
+    args: &[&str],
+    handles: &[Handle],
+    parent: Option<Pid>,
+) -> Result<Pid, RunError> {
+    let elf = Elf::parse(image).map_err(RunError::InvalidExecutable)?;
+    let args = args.iter().map(|arg| arg.to_string()).collect();
+    spawn(args, handles, parent, move |address_space| elf.load(address_space))
+}
+
+/// Adds a process to the table and starts a thread that loads the program
+/// into a new address space with `load`, which returns the entry point, and
+/// enters it.
+fn spawn(
+    args: Vec<String>,
+    handles: &[Handle],
+    parent: Option<Pid>,
+    load: impl FnOnce(&mut AddressSpace) -> Result<VirtAddr, LoadError> + Send + 'static,
+) -> Result<Pid, RunError> {
+    let name = args.first().cloned().unwrap_or_default();
+    // Built before taking the lock, which keeps interrupts off
+    let process = Process {
+        parent,
+        name: name.clone(),
+        thread: None,
+        state: State::Running,
+        address_space: None,
+        handles: handles.to_vec(),
+    };
+    let pid = {
+        let mut processes = PROCESSES.lock();
+        if processes.len() >= process::MAX_PROCESSES {
+            return Err(RunError::TooManyProcesses);
+        }
+        let pid = process::next_pid();
+        processes.insert(pid, process);
+        pid
+    };
+
+    let thread = thread::spawn(&name, Priority::Normal, move || start(pid, load, &args));
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.thread = Some(thread);
+    }
+    log::info!("Started process {} ({})", pid, name);
+    Ok(pid)
+}
+
+fn start(
+    pid: Pid,
+    load: impl FnOnce(&mut AddressSpace) -> Result<VirtAddr, LoadError>,
+    args: &[String],
+) {
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.thread = Some(thread::current());
+    }
+    let loaded = AddressSpace::new()
+        .map_err(|_| LoadError::OutOfMemory)
+        .and_then(|mut address_space| {
+            let entry = load(&mut address_space)?;
+            let stack = setup_stack(&mut address_space, args)?;
+            Ok((address_space, entry, stack))
+        });
+    let (address_space, entry, stack) = match loaded {
+        Ok(loaded) => loaded,
+        Err(error) => finish(ExitStatus::LoadFailed(error)),
+    };
+
+    if process::is_killed() {
+        drop(address_space);
+        finish(ExitStatus::Killed);
+    }
+    let page_table = address_space.page_table();
+    if let Some(process) = PROCESSES.lock().get_mut(&pid) {
+        process.address_space = Some(address_space);
+    }
+    log::debug!("Entering {} at {:#x}", args[0], entry.as_u64());
+    unsafe { enter(page_table, entry, stack) }
+}
+
+/// Copies position-independent code to the start of the user region.
+fn load_code(address_space: &mut AddressSpace, code: &[u8]) -> Result<VirtAddr, LoadError> {
+    let start = VirtAddr::new(CODE_START);
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(start),
+        Page::containing_address(start + code.len() as u64 + 4095u64),
+    );
+    for page in pages {
+        address_space.map_user(page, PageTableFlags::empty()).map_err(|_| LoadError::OutOfMemory)?;
+    }
+    address_space.write(start, code);
+    Ok(start)
+}
+
+/// Maps the user stack and lays out `args` at its top as the System V ABI
+/// has it at process entry: argc at the stack pointer, then the argv
+/// pointers, a null pointer, an empty environment and an empty auxiliary
+/// vector. Returns the stack pointer.
+fn setup_stack(address_space: &mut AddressSpace, args: &[String]) -> Result<VirtAddr, LoadError> {
+    let pages = Page::<Size4KiB>::range(
+        Page::containing_address(VirtAddr::new(STACK_TOP - STACK_SIZE)),
+        Page::containing_address(VirtAddr::new(STACK_TOP)),
+    );
+    for page in pages {
+        address_space
+            .map_user(page, PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE)
+            .map_err(|_| LoadError::OutOfMemory)?;
+    }
+
+    // Built in a copy of the top page, strings at the very top
+    let mut top = vec![0u8; 4096];
+    let page_start = STACK_TOP - 4096;
+    let mut free = top.len();
+    let mut words = vec![args.len() as u64];
+    for arg in args {
+        free = free.checked_sub(arg.len() + 1).ok_or(LoadError::ArgumentsTooLong)?;
+        top[free..free + arg.len()].copy_from_slice(arg.as_bytes());
+        words.push(page_start + free as u64);
+    }
+    words.extend([0, 0, 0, 0]);
+
+    let start = free
+        .checked_sub(words.len() * 8)
+        .ok_or(LoadError::ArgumentsTooLong)?
+        & !0xf;
+    for (i, word) in words.iter().enumerate() {
+        top[start + i * 8..start + i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+    }
+    address_space.write(VirtAddr::new(page_start), &top);
+    Ok(VirtAddr::new(page_start + start as u64))
+}
+
+/// Switches to the program's address space and drops to ring 3 at `entry`.
+/// The calling thread's stack below this frame becomes its kernel stack.
+///
+/// # Safety
+///
+/// `page_table` must be the level 4 table of the process's address space,
+/// which stays alive until `finish`.
+unsafe fn enter(page_table: PhysFrame, entry: VirtAddr, stack: VirtAddr) -> ! {
+    interrupts::disable();
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+    let kernel_stack = VirtAddr::new(rsp & !0xf);
+    thread::set_user_context(Some(UserContext { page_table, kernel_stack }));
+
+    let (code, data) = crate::gdt::user_selectors();
+    unsafe {
+        asm!(
+            "push {user_ss}",
+            "push {user_rsp}",
+            "push {user_rflags}",
+            "push {user_cs}",
+            "push {user_rip}",
+            // User mode runs with the user's GS, the kernel's is swapped in
+            // on every entry from ring 3
+            "swapgs",
+            "iretq",
+            user_ss = in(reg) u64::from(data.0),
+            user_rsp = in(reg) stack.as_u64(),
+            user_rflags = in(reg) USER_RFLAGS,
+            user_cs = in(reg) u64::from(code.0),
+            user_rip = in(reg) entry.as_u64(),
+            options(noreturn),
+        );
+    }
+}
+
+/// Ends the calling thread's process: back to the kernel's address space,
+/// free the process's, and exit the thread. The process stays in the table
+/// until it is waited for.
+fn finish(status: ExitStatus) -> ! {
+    interrupts::enable();
+    thread::set_user_context(None);
+
+    let address_space = process::exit_current(status);
+    // Outside the lock: freeing frames takes the frame allocator's lock,
+    // which may need interrupts to be released
+    drop(address_space);
+    thread::exit();
+}
+
+/// Called by exception handlers for a fault in ring 3. Rewrites the
+/// interrupt stack frame so that `iretq` ends the program instead of
+/// retrying the faulting instruction.
+pub(crate) fn kill_faulting(stack_frame: &mut InterruptStackFrame) {
+    let user = thread::user_context().expect("user mode fault outside a user program");
+    let (code, data) = crate::gdt::kernel_selectors();
+
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::from_ptr(fault_exit as *const ());
+            frame.code_segment = u64::from(code.0);
+            // Enter as if called: the return address slot keeps the stack 16-byte aligned
+            frame.stack_pointer = user.kernel_stack - 8u64;
+            frame.stack_segment = u64::from(data.0);
+            frame.cpu_flags = 0x2;
+        });
+    }
+}
+
+/// Entered through `iretq` from `kill_faulting`, still with the user's GS.
+extern "C" fn fault_exit() -> ! {
+    unsafe { asm!("swapgs", options(nomem, nostack, preserves_flags)) };
+    finish(ExitStatus::Killed);
+}
+
+/// Ends the calling thread's program after a fault in the kernel while it
+/// was handling one of its system calls. Returns if the thread doesn't run
+/// a program.
+pub(crate) fn kill_current() {
+    if thread::user_context().is_some() {
+        finish(ExitStatus::Killed);
+    }
+}
+
+/// Called by the timer interrupt handler after it interrupted ring 3: ends
+/// the program there if it has been killed.
+pub(crate) fn kill_if_killed(stack_frame: &mut InterruptStackFrame) {
+    if process::is_killed() {
+        kill_faulting(stack_frame);
+    }
+}
+
+/// Ends the calling thread's process if it has been killed. For system
+/// calls, which run on its thread.
+fn exit_if_killed() {
+    if process::is_killed() {
+        finish(ExitStatus::Killed);
+    }
+}
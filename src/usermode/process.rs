@@ -0,0 +1,238 @@
+// process.rs - The process table
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::memory::AddressSpace;
+use crate::sync::IrqSafeMutex;
+use crate::thread::{self, ThreadId};
+use super::ExitStatus;
+
+/// How many processes the table holds, exited ones not yet waited for
+/// included.
+pub(super) const MAX_PROCESSES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    pub const fn new(pid: u64) -> Self {
+        Pid(pid)
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Running,
+    /// Asked to end by `kill`; it does at its next system call or timer
+    /// interrupt.
+    Killing,
+    /// Ended, and kept in the table until its exit status is collected.
+    Exited(ExitStatus),
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Running => "Running",
+            State::Killing => "Killing",
+            State::Exited(ExitStatus::Exited(_)) => "Exited",
+            State::Exited(ExitStatus::Killed) => "Killed",
+            State::Exited(ExitStatus::LoadFailed(_)) => "Failed",
+        }
+    }
+}
+
+/// What a process may use. Children get their parent's handles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handle {
+    /// Output through `write`.
+    Console,
+    /// Keys through `read_key`. Only the processes in the foreground have it.
+    Keyboard,
+}
+
+/// The handles of a program started in the foreground, which gets the keys
+/// typed while it runs.
+pub const FOREGROUND: &[Handle] = &[Handle::Console, Handle::Keyboard];
+/// The handles of a program started in the background.
+pub const BACKGROUND: &[Handle] = &[Handle::Console];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    AlreadyExited,
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProcessError::NoSuchProcess => f.write_str("no such process"),
+            ProcessError::AlreadyExited => f.write_str("process has already exited"),
+        }
+    }
+}
+
+pub(super) struct Process {
+    pub(super) parent: Option<Pid>,
+    pub(super) name: String,
+    /// Set by whichever of the spawning and the new thread gets to it first.
+    pub(super) thread: Option<ThreadId>,
+    pub(super) state: State,
+    pub(super) address_space: Option<AddressSpace>,
+    pub(super) handles: Vec<Handle>,
+}
+
+/// A snapshot of a process for `ps`.
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub thread: Option<ThreadId>,
+    pub state: State,
+    pub handles: Vec<Handle>,
+}
+
+pub(super) static PROCESSES: IrqSafeMutex<BTreeMap<Pid, Process>> =
+    IrqSafeMutex::named("PROCESSES", BTreeMap::new());
+
+static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+
+pub(super) fn next_pid() -> Pid {
+    Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+}
+
+/// The process the calling thread runs, if any.
+pub fn current() -> Option<Pid> {
+    let thread = thread::current();
+    PROCESSES
+        .lock()
+        .iter()
+        .find(|(_, process)| process.thread == Some(thread))
+        .map(|(&pid, _)| pid)
+}
+
+pub fn list() -> Vec<ProcessInfo> {
+    // Room for all of them before the lock is taken. The names and handles
+    // are still copied under it, which the interrupt-safe heap allows.
+    let mut list = Vec::with_capacity(MAX_PROCESSES);
+    list.extend(PROCESSES.lock().iter().map(|(&pid, process)| ProcessInfo {
+        pid,
+        parent: process.parent,
+        name: process.name.clone(),
+        thread: process.thread,
+        state: process.state,
+        handles: process.handles.clone(),
+    }));
+    list
+}
+
+/// The first running process that reads the keyboard.
+pub fn foreground() -> Option<Pid> {
+    PROCESSES
+        .lock()
+        .iter()
+        .find(|(_, process)| {
+            process.state == State::Running && process.handles.contains(&Handle::Keyboard)
+        })
+        .map(|(&pid, _)| pid)
+}
+
+pub(super) fn handles(pid: Pid) -> Vec<Handle> {
+    PROCESSES
+        .lock()
+        .get(&pid)
+        .map(|process| process.handles.clone())
+        .unwrap_or_default()
+}
+
+/// Whether the calling thread's process may use `handle`.
+pub(super) fn has_handle(handle: Handle) -> bool {
+    current().is_some_and(|pid| handles(pid).contains(&handle))
+}
+
+/// Asks process `pid` to end. It is killed the next time it enters the
+/// kernel or is interrupted, also when it's stuck in a loop in ring 3.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let mut processes = PROCESSES.lock();
+    let process = processes.get_mut(&pid).ok_or(ProcessError::NoSuchProcess)?;
+    match process.state {
+        State::Exited(_) => Err(ProcessError::AlreadyExited),
+        _ => {
+            process.state = State::Killing;
+            // The shell gets the keyboard back right away
+            process.handles.retain(|&handle| handle != Handle::Keyboard);
+            Ok(())
+        }
+    }
+}
+
+/// Whether the calling thread's process has been killed.
+pub(super) fn is_killed() -> bool {
+    let Some(pid) = current() else {
+        return false;
+    };
+    PROCESSES
+        .lock()
+        .get(&pid)
+        .is_some_and(|process| process.state == State::Killing)
+}
+
+/// Returns the exit status of process `pid` once it has exited, and
+/// removes it from the table.
+pub fn try_wait(pid: Pid) -> Result<Option<ExitStatus>, ProcessError> {
+    let (process, status) = {
+        let mut processes = PROCESSES.lock();
+        let process = processes.get(&pid).ok_or(ProcessError::NoSuchProcess)?;
+        let State::Exited(status) = process.state else {
+            return Ok(None);
+        };
+        (processes.remove(&pid), status)
+    };
+    // Freed here, outside the lock. It may still be on its way out of
+    // `finish`.
+    if let Some(thread) = process.and_then(|process| process.thread) {
+        thread::join(thread);
+    }
+    Ok(Some(status))
+}
+
+/// Blocks the calling thread until process `pid` has exited, like
+/// `try_wait`.
+pub fn wait(pid: Pid) -> Result<ExitStatus, ProcessError> {
+    loop {
+        if let Some(status) = try_wait(pid)? {
+            return Ok(status);
+        }
+        thread::sleep(1);
+    }
+}
+
+/// Records that the calling thread's process has ended and hands out its
+/// address space to be freed. Its children are left to the kernel.
+pub(super) fn exit_current(status: ExitStatus) -> Option<AddressSpace> {
+    let pid = current()?;
+    let mut processes = PROCESSES.lock();
+    for process in processes.values_mut() {
+        if process.parent == Some(pid) {
+            process.parent = None;
+        }
+    }
+    let process = processes.get_mut(&pid)?;
+    process.state = State::Exited(status);
+    process.handles.clear();
+    process.address_space.take()
+}
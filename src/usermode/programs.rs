@@ -0,0 +1,159 @@
+// programs.rs - Demo programs built into the kernel
+
+use core::arch::global_asm;
+
+/// A program that `run` can start. Its code is position independent, since
+/// it is copied into the program's own address space.
+pub struct Program {
+    pub name: &'static str,
+    pub description: &'static str,
+    start: *const u8,
+    end: *const u8,
+}
+
+// Only ever read: the pointers are addresses of code
+unsafe impl Sync for Program {}
+
+impl Program {
+    pub fn code(&self) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts(self.start, self.end as usize - self.start as usize) }
+    }
+}
+
+// Each program talks to the kernel only through `syscall`, with the numbers
+// from `syscall::SYSCALLS`.
+global_asm!(
+    r#"
+.pushsection .text.user_programs, "ax"
+
+.global user_hello_start
+user_hello_start:
+    lea .Lhello_text(%rip), %rdi
+    mov $(.Lhello_text_end - .Lhello_text), %esi
+    mov $1, %eax
+    syscall
+    xor %edi, %edi
+    xor %eax, %eax
+    syscall
+.Lhello_text:
+    .ascii "Hello from ring 3!\n"
+.Lhello_text_end:
+.global user_hello_end
+user_hello_end:
+
+.global user_echo_start
+user_echo_start:
+    lea .Lecho_text(%rip), %rdi
+    mov $(.Lecho_text_end - .Lecho_text), %esi
+    mov $1, %eax
+    syscall
+.Lecho_next_key:
+    mov $2, %eax
+    syscall
+    // Write the key back from the stack, Enter included
+    push %rax
+    mov %rsp, %rdi
+    mov $1, %esi
+    mov $1, %eax
+    syscall
+    pop %rax
+    cmp $10, %al
+    jne .Lecho_next_key
+    xor %edi, %edi
+    xor %eax, %eax
+    syscall
+.Lecho_text:
+    .ascii "Type a line, Enter ends it: "
+.Lecho_text_end:
+.global user_echo_end
+user_echo_end:
+
+.global user_dots_start
+user_dots_start:
+    mov $5, %ebx
+.Ldots_next:
+    lea .Ldots_text(%rip), %rdi
+    mov $1, %esi
+    mov $1, %eax
+    syscall
+    mov $200, %edi
+    mov $3, %eax
+    syscall
+    dec %ebx
+    jnz .Ldots_next
+    lea .Ldots_text + 1(%rip), %rdi
+    mov $1, %esi
+    mov $1, %eax
+    syscall
+    mov $5, %edi
+    xor %eax, %eax
+    syscall
+.Ldots_text:
+    .ascii ".\n"
+.global user_dots_end
+user_dots_end:
+
+.global user_fault_start
+user_fault_start:
+    lea .Lfault_text(%rip), %rdi
+    mov $(.Lfault_text_end - .Lfault_text), %esi
+    mov $1, %eax
+    syscall
+    // The VGA buffer is mapped, but only for the kernel
+    mov $0xb8000, %rax
+    movb $0x21, (%rax)
+    xor %edi, %edi
+    xor %eax, %eax
+    syscall
+.Lfault_text:
+    .ascii "Writing to the VGA buffer from ring 3...\n"
+.Lfault_text_end:
+.global user_fault_end
+user_fault_end:
+
+.popsection
+"#,
+    options(att_syntax)
+);
+
+extern "C" {
+    static user_hello_start: u8;
+    static user_hello_end: u8;
+    static user_echo_start: u8;
+    static user_echo_end: u8;
+    static user_dots_start: u8;
+    static user_dots_end: u8;
+    static user_fault_start: u8;
+    static user_fault_end: u8;
+}
+
+pub static PROGRAMS: [Program; 4] = [
+    Program {
+        name: "hello",
+        description: "Print a greeting and exit",
+        start: core::ptr::addr_of!(user_hello_start),
+        end: core::ptr::addr_of!(user_hello_end),
+    },
+    Program {
+        name: "echo",
+        description: "Echo keys until Enter",
+        start: core::ptr::addr_of!(user_echo_start),
+        end: core::ptr::addr_of!(user_echo_end),
+    },
+    Program {
+        name: "dots",
+        description: "Print a dot every 200 ms, exit with code 5",
+        start: core::ptr::addr_of!(user_dots_start),
+        end: core::ptr::addr_of!(user_dots_end),
+    },
+    Program {
+        name: "fault",
+        description: "Write to kernel memory and get killed",
+        start: core::ptr::addr_of!(user_fault_start),
+        end: core::ptr::addr_of!(user_fault_end),
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|program| program.name == name)
+}
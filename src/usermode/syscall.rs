@@ -0,0 +1,254 @@
+// syscall.rs - The `syscall` entry point and the system call table
+
+use alloc::vec::Vec;
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+use crate::keyboard_buffer::KEYBOARD_BUFFER;
+use crate::smp::{KERNEL_STACK_OFFSET, USER_STACK_OFFSET};
+use crate::{memory, print, thread, time};
+use super::process::{self, Handle};
+use super::ExitStatus;
+
+/// Returned for unknown system calls and invalid arguments.
+const ERROR: u64 = u64::MAX;
+
+type Handler = fn(u64, u64, u64) -> u64;
+
+/// The system calls, by the number a program passes in `rax`:
+///
+/// | rax | call       | arguments       | returns             |
+/// |-----|------------|-----------------|---------------------|
+/// | 0   | `exit`     | code            | doesn't             |
+/// | 1   | `write`    | buffer, length  | bytes written       |
+/// | 2   | `read_key` |                 | key, waits for one  |
+/// | 3   | `sleep`    | milliseconds    | 0                   |
+/// | 4   | `spawn`    | command, length | PID of the child    |
+/// | 5   | `wait`     | PID of a child  | its exit code       |
+const SYSCALLS: [Handler; 6] = [sys_exit, sys_write, sys_read_key, sys_sleep, sys_spawn, sys_wait];
+
+/// The longest a killed program sleeps on before it is ended.
+const KILL_CHECK_TICKS: u64 = 10;
+
+/// Return addresses from here on go back through `iretq`. On Intel CPUs
+/// `sysretq` to a non-canonical address faults in ring 0, after the user's
+/// stack and GS are back, and the address after a `syscall` at the very
+/// end of the user region is just that. Like Linux, the whole last page is
+/// left to `iretq`.
+const SYSRET_LIMIT: u64 = crate::memory::USER_END - 0x1000;
+
+/// The user selectors for the `iretq` return, set by `init`.
+static USER_CS: AtomicU64 = AtomicU64::new(0);
+static USER_SS: AtomicU64 = AtomicU64::new(0);
+
+// `syscall` leaves the user stack in place and the return address in rcx
+// and flags in r11, with interrupts masked by SFMASK. The entry code swaps
+// in the kernel's GS to find the thread's kernel stack in the per-CPU data,
+// keeps the user stack pointer and registers there, and calls the
+// dispatcher with the number in rax and the arguments in rdi, rsi and rdx.
+// Like on Linux, only rax, rcx and r11 are changed for the program.
+global_asm!(
+    r#"
+.global syscall_entry
+syscall_entry:
+    swapgs
+    mov %rsp, %gs:{user_stack}
+    mov %gs:{kernel_stack}, %rsp
+    pushq %gs:{user_stack}
+    push %rcx
+    push %r11
+    push %rdi
+    push %rsi
+    push %rdx
+    push %r8
+    push %r9
+    push %r10
+    // Nine pushes, so one more slot aligns the stack for the call
+    sub $8, %rsp
+
+    mov %rdx, %rcx
+    mov %rsi, %rdx
+    mov %rdi, %rsi
+    mov %rax, %rdi
+    call {dispatch}
+
+    // No interrupts from here until the program runs again
+    cli
+    add $8, %rsp
+    pop %r10
+    pop %r9
+    pop %r8
+    pop %rdx
+    pop %rsi
+    pop %rdi
+    pop %r11
+    pop %rcx
+
+    push %rax
+    movabs ${sysret_limit}, %rax
+    cmp %rax, %rcx
+    pop %rax
+    jae 1f
+    pop %rsp
+    swapgs
+    sysretq
+
+1:
+    // An iretq frame below the user stack pointer, which ends up in the
+    // ss slot: rip, cs, rflags, rsp, ss
+    sub $32, %rsp
+    mov %rcx, (%rsp)
+    mov %r11, 16(%rsp)
+    mov 32(%rsp), %rcx
+    mov %rcx, 24(%rsp)
+    mov {user_cs}(%rip), %rcx
+    mov %rcx, 8(%rsp)
+    mov {user_ss}(%rip), %rcx
+    mov %rcx, 32(%rsp)
+    swapgs
+    iretq
+"#,
+    user_stack = const USER_STACK_OFFSET,
+    kernel_stack = const KERNEL_STACK_OFFSET,
+    dispatch = sym dispatch,
+    sysret_limit = const SYSRET_LIMIT,
+    user_cs = sym USER_CS,
+    user_ss = sym USER_SS,
+    options(att_syntax)
+);
+
+extern "C" {
+    fn syscall_entry();
+}
+
+/// Points `syscall` at `syscall_entry` and enables it.
+pub(super) fn init() {
+    let (kernel_code, kernel_data) = crate::gdt::kernel_selectors();
+    let (user_code, user_data) = crate::gdt::user_selectors();
+    USER_CS.store(u64::from(user_code.0), Ordering::Relaxed);
+    USER_SS.store(u64::from(user_data.0), Ordering::Relaxed);
+    Star::write(user_code, user_data, kernel_code, kernel_data)
+        .expect("GDT layout doesn't suit sysret");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
+}
+
+extern "C" fn dispatch(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    // On the program's own kernel stack, so it can be preempted like any
+    // other thread
+    interrupts::enable();
+    let result = match SYSCALLS.get(number as usize) {
+        Some(handler) => handler(arg0, arg1, arg2),
+        None => ERROR,
+    };
+    super::exit_if_killed();
+    result
+}
+
+fn sys_exit(code: u64, _: u64, _: u64) -> u64 {
+    super::finish(ExitStatus::Exited(code as i64));
+}
+
+/// Prints `length` bytes of UTF-8 from `buffer` to the console.
+fn sys_write(buffer: u64, length: u64, _: u64) -> u64 {
+    if !process::has_handle(Handle::Console) {
+        return ERROR;
+    }
+    match user_str(buffer, length) {
+        Some(text) => {
+            print!("{}", text);
+            length
+        }
+        None => ERROR,
+    }
+}
+
+/// Waits for a key typed while the program runs in the foreground. carlsh
+/// passes them on through the same buffer as BASIC's INKEY().
+fn sys_read_key(_: u64, _: u64, _: u64) -> u64 {
+    loop {
+        if !process::has_handle(Handle::Keyboard) {
+            return ERROR;
+        }
+        if let Some(key) = KEYBOARD_BUFFER.lock().pop() {
+            return u64::from(key);
+        }
+        thread::sleep(1);
+        super::exit_if_killed();
+    }
+}
+
+fn sys_sleep(ms: u64, _: u64, _: u64) -> u64 {
+    // In steps, so that a killed program doesn't sleep on
+    // Saturated: a program asking for u64::MAX sleeps until it is killed
+    let until = time::ticks().saturating_add(time::ms_to_ticks(ms)).saturating_add(1);
+    loop {
+        let now = time::ticks();
+        if now >= until {
+            return 0;
+        }
+        thread::sleep((until - now).min(KILL_CHECK_TICKS));
+        super::exit_if_killed();
+    }
+}
+
+/// Starts the program from the initrd named by the first word of the
+/// command line in `command`, with the words as its arguments, as a child
+/// with the caller's handles.
+fn sys_spawn(command: u64, length: u64, _: u64) -> u64 {
+    let Some(command) = user_str(command, length) else {
+        return ERROR;
+    };
+    let args: Vec<&str> = command.split_whitespace().collect();
+    let Some(file) = args.first().and_then(|name| crate::initrd::find(name)) else {
+        return ERROR;
+    };
+    let Some(parent) = process::current() else {
+        return ERROR;
+    };
+    match super::spawn_elf(file.data, &args, &process::handles(parent), Some(parent)) {
+        Ok(pid) => pid.as_u64(),
+        Err(_) => ERROR,
+    }
+}
+
+/// Waits for a child to exit. Returns its exit code, or an error if it
+/// didn't exit by itself.
+fn sys_wait(pid: u64, _: u64, _: u64) -> u64 {
+    let Some(parent) = process::current() else {
+        return ERROR;
+    };
+    let child = process::list()
+        .into_iter()
+        .find(|process| process.pid.as_u64() == pid && process.parent == Some(parent));
+    let Some(child) = child else {
+        return ERROR;
+    };
+
+    loop {
+        match process::try_wait(child.pid) {
+            Ok(Some(ExitStatus::Exited(code))) => return code as u64,
+            Ok(Some(_)) | Err(_) => return ERROR,
+            Ok(None) => {}
+        }
+        thread::sleep(1);
+        super::exit_if_killed();
+    }
+}
+
+/// The `length` bytes of UTF-8 at `buffer`, if they are all mapped for the
+/// program.
+fn user_str(buffer: u64, length: u64) -> Option<&'static str> {
+    let accessible = VirtAddr::try_new(buffer)
+        .is_ok_and(|buffer| memory::is_user_accessible(buffer, length));
+    if !accessible {
+        return None;
+    }
+
+    let bytes = unsafe { core::slice::from_raw_parts(buffer as *const u8, length as usize) };
+    core::str::from_utf8(bytes).ok()
+}
@@ -1,3 +1,4 @@
+use alloc::string::String;
 use core::fmt;
 use volatile::Volatile;
 
@@ -31,6 +32,13 @@ impl ColorCode {
     fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// Flips the color bits of both halves, leaving the bright and blink
+    /// bits alone, as DOS mouse drivers draw their text pointer. Doing it
+    /// twice gives back the original.
+    fn inverted(self) -> ColorCode {
+        ColorCode(self.0 ^ 0x77)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -40,8 +48,8 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 const SCROLLBACK_SIZE: usize = 1000;
 
 #[repr(transparent)]
@@ -57,10 +65,24 @@ pub struct Writer {
     scrollback_position: usize,
     scroll_offset: usize,
     live_screen: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT], // Save current screen
+    /// Where the mouse pointer is drawn, once the mouse has moved.
+    pointer: Option<Position>,
+    /// The cells from one end to the other, in reading order, are
+    /// highlighted.
+    selection: Option<(Position, Position)>,
 }
 
+/// A cell on the screen, as (row, column).
+pub type Position = (usize, usize);
+
 impl Writer {
     pub fn write_byte(&mut self, byte: u8) {
+        self.toggle_overlay();
+        self.put_byte(byte);
+        self.toggle_overlay();
+    }
+
+    fn put_byte(&mut self, byte: u8) {
         // Auto-scroll to bottom if user types while scrolled
         if self.scroll_offset > 0 {
             self.scroll_offset = 0;
@@ -96,14 +118,76 @@ impl Writer {
     }
 
     pub fn write_string(&mut self, s: &str) {
+        self.toggle_overlay();
         for byte in s.bytes() {
             match byte {
-                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
+                0x20..=0x7e | b'\n' | 0x08 => self.put_byte(byte),
+                _ => self.put_byte(0xfe),
+            }
+        }
+        self.toggle_overlay();
+    }
+
+    /// Moves the mouse pointer to `pointer`, or hides it.
+    pub fn set_pointer(&mut self, pointer: Option<Position>) {
+        self.toggle_overlay();
+        self.pointer = pointer.filter(|&(row, col)| row < BUFFER_HEIGHT && col < BUFFER_WIDTH);
+        self.toggle_overlay();
+    }
+
+    /// Highlights the cells between two positions, both included, or
+    /// nothing. The selection goes away when the text under it moves.
+    pub fn set_selection(&mut self, selection: Option<(Position, Position)>) {
+        self.toggle_overlay();
+        self.selection = selection.filter(|&(from, to)| {
+            [from, to].iter().all(|&(row, col)| row < BUFFER_HEIGHT && col < BUFFER_WIDTH)
+        });
+        self.toggle_overlay();
+    }
+
+    /// The highlighted text, one line per screen row, without the spaces
+    /// at the end of each row.
+    pub fn selected_text(&self) -> String {
+        let mut text = String::new();
+        let Some((from, to)) = self.selection else {
+            return text;
+        };
+        let (first, last) = (from.min(to), from.max(to));
+        for row in first.0..=last.0 {
+            let start = if row == first.0 { first.1 } else { 0 };
+            let end = if row == last.0 { last.1 } else { BUFFER_WIDTH - 1 };
+            let line: String = (start..=end)
+                .map(|col| char::from(self.buffer.chars[row][col].read().ascii_character))
+                .collect();
+            if row != first.0 {
+                text.push('\n');
             }
+            text.push_str(line.trim_end());
+        }
+        text
+    }
+
+    /// Draws or removes the pointer and the selection. Everything that
+    /// changes the screen removes them first, so that no inverted colors
+    /// end up in the scrollback or in the live screen cache.
+    fn toggle_overlay(&mut self) {
+        if let Some((from, to)) = self.selection {
+            let (first, last) = (from.min(to), from.max(to));
+            for cell in first.0 * BUFFER_WIDTH + first.1..=last.0 * BUFFER_WIDTH + last.1 {
+                self.invert(cell / BUFFER_WIDTH, cell % BUFFER_WIDTH);
+            }
+        }
+        if let Some((row, col)) = self.pointer {
+            self.invert(row, col);
         }
     }
 
+    fn invert(&mut self, row: usize, col: usize) {
+        let mut character = self.buffer.chars[row][col].read();
+        character.color_code = character.color_code.inverted();
+        self.buffer.chars[row][col].write(character);
+    }
+
     fn set_cursor_position(&self, row: usize, col: usize) {
         let pos = row * BUFFER_WIDTH + col;
 
@@ -154,6 +238,7 @@ impl Writer {
         self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
         self.scroll_offset = 0;
+        self.selection = None;
     }
 
     fn save_line_to_scrollback(&mut self, row: usize) {
@@ -176,23 +261,29 @@ impl Writer {
     }
 
     pub fn clear(&mut self) {
+        self.toggle_overlay();
         for row in 0..BUFFER_HEIGHT {
             self.clear_row(row);
         }
         self.column_position = 0;
         self.scroll_offset = 0;
+        self.selection = None;
+        self.toggle_overlay();
     }
 
     pub fn scroll_up(&mut self, lines: usize) {
+        self.toggle_overlay();
         // Can scroll up through scrollback_position lines
         let max = self.scrollback_position;
         if self.scroll_offset < max {
             self.scroll_offset = (self.scroll_offset + lines).min(max);
             self.redraw_from_scrollback();
         }
+        self.toggle_overlay();
     }
 
     pub fn scroll_down(&mut self, lines: usize) {
+        self.toggle_overlay();
         if self.scroll_offset > 0 {
             self.scroll_offset = self.scroll_offset.saturating_sub(lines);
             
@@ -203,6 +294,7 @@ impl Writer {
                 self.redraw_from_scrollback();
             }
         }
+        self.toggle_overlay();
     }
 
     fn redraw_from_scrollback(&mut self) {
@@ -214,6 +306,7 @@ impl Writer {
             0
         };
         
+        self.selection = None;
         for screen_row in 0..BUFFER_HEIGHT {
             let sb_line = start + screen_row;
             
@@ -230,6 +323,7 @@ impl Writer {
 
     fn restore_from_live_screen(&mut self) {
         // Restore the live screen from cache
+        self.selection = None;
         for row in 0..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 self.buffer.chars[row][col].write(self.live_screen[row][col]);
@@ -246,10 +340,10 @@ impl fmt::Write for Writer {
 }
 
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::sync::IrqSafeMutex;
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
+    pub static ref WRITER: IrqSafeMutex<Writer> = IrqSafeMutex::named("WRITER", Writer {
         column_position: 0,
         color_code: ColorCode::new(Color::Green, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
@@ -263,9 +357,81 @@ lazy_static! {
             ascii_character: b' ',
             color_code: ColorCode::new(Color::Green, Color::Black),
         }; BUFFER_WIDTH]; BUFFER_HEIGHT],
+        pointer: None,
+        selection: None,
     });
 }
 
+/// Draws straight into video memory, bypassing `WRITER`, which may be locked
+/// or halfway through an update when the kernel panics. Text wraps at the
+/// right edge and is cut off at the bottom.
+pub struct CrashScreen {
+    row: usize,
+    column: usize,
+    color_code: ColorCode,
+    buffer: &'static mut Buffer,
+}
+
+impl CrashScreen {
+    /// Fills the screen with white on red.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may draw to the screen from now on.
+    pub unsafe fn new() -> CrashScreen {
+        let screen = CrashScreen {
+            row: 0,
+            column: 0,
+            color_code: ColorCode::new(Color::White, Color::Red),
+            buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        };
+
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: screen.color_code,
+        };
+        for row in screen.buffer.chars.iter_mut() {
+            for character in row.iter_mut() {
+                character.write(blank);
+            }
+        }
+        screen
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        if byte == b'\n' {
+            self.row += 1;
+            self.column = 0;
+            return;
+        }
+        if self.column >= BUFFER_WIDTH {
+            self.row += 1;
+            self.column = 0;
+        }
+        if self.row >= BUFFER_HEIGHT {
+            return;
+        }
+
+        self.buffer.chars[self.row][self.column].write(ScreenChar {
+            ascii_character: byte,
+            color_code: self.color_code,
+        });
+        self.column += 1;
+    }
+}
+
+impl fmt::Write for CrashScreen {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            match byte {
+                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                _ => self.write_byte(0xfe),
+            }
+        }
+        Ok(())
+    }
+}
+
 pub fn clear_screen() {
     WRITER.lock().clear();
 }
@@ -278,6 +444,18 @@ pub fn scroll_down(lines: usize) {
     WRITER.lock().scroll_down(lines);
 }
 
+pub fn set_pointer(pointer: Option<Position>) {
+    WRITER.lock().set_pointer(pointer);
+}
+
+pub fn set_selection(selection: Option<(Position, Position)>) {
+    WRITER.lock().set_selection(selection);
+}
+
+pub fn selected_text() -> String {
+    WRITER.lock().selected_text()
+}
+
 #[macro_export]
 macro_rules! print {
     ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
@@ -290,7 +468,166 @@ macro_rules! println {
 }
 
 #[doc(hidden)]
+#[track_caller]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
     WRITER.lock().write_fmt(args).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fmt::Write;
+
+    fn row_contains(writer: &Writer, row: usize, s: &str) -> bool {
+        let mut line = [0u8; BUFFER_WIDTH];
+        for (col, byte) in line.iter_mut().enumerate() {
+            *byte = writer.buffer.chars[row][col].read().ascii_character;
+        }
+        line.windows(s.len()).any(|window| window == s.as_bytes())
+    }
+
+    #[test_case]
+    fn test_println_simple() {
+        println!("test_println_simple output");
+    }
+
+    #[test_case]
+    fn test_println_many() {
+        for _ in 0..200 {
+            println!("test_println_many output");
+        }
+    }
+
+    #[test_case]
+    fn test_println_output() {
+        let s = "Some test string that fits on a single line";
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\n{}", s).expect("writeln failed");
+        for (i, c) in s.chars().enumerate() {
+            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+        }
+    }
+
+    #[test_case]
+    fn test_scrolled_off_line_reachable_through_scrollback() {
+        let marker = "scrollback marker line";
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\n{}", marker).expect("writeln failed");
+        for _ in 0..BUFFER_HEIGHT + 5 {
+            writeln!(writer).expect("writeln failed");
+        }
+        assert!((0..BUFFER_HEIGHT).all(|row| !row_contains(&writer, row, marker)));
+
+        writer.scroll_up(1);
+        assert_eq!(writer.scroll_offset, 1);
+        assert!((0..BUFFER_HEIGHT).any(|row| row_contains(&writer, row, marker)));
+
+        writer.scroll_down(1);
+        assert_eq!(writer.scroll_offset, 0);
+        assert!((0..BUFFER_HEIGHT).all(|row| !row_contains(&writer, row, marker)));
+    }
+
+    #[test_case]
+    fn test_scroll_down_restores_live_screen() {
+        let mut writer = WRITER.lock();
+        for i in 0..BUFFER_HEIGHT * 2 {
+            writeln!(writer, "live screen line {}", i).expect("writeln failed");
+        }
+
+        writer.scroll_up(BUFFER_HEIGHT);
+        writer.scroll_down(BUFFER_HEIGHT);
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                assert_eq!(writer.buffer.chars[row][col].read(), writer.live_screen[row][col]);
+            }
+        }
+    }
+
+    #[test_case]
+    fn test_scroll_up_is_bounded_by_scrollback() {
+        let mut writer = WRITER.lock();
+        let max = writer.scrollback_position;
+        writer.scroll_up(max + 100);
+        assert_eq!(writer.scroll_offset, max);
+        writer.scroll_down(max + 100);
+        assert_eq!(writer.scroll_offset, 0);
+    }
+
+    #[test_case]
+    fn test_writing_while_scrolled_returns_to_live_view() {
+        let mut writer = WRITER.lock();
+        for _ in 0..BUFFER_HEIGHT {
+            writeln!(writer).expect("writeln failed");
+        }
+        writer.scroll_up(3);
+        assert!(writer.scroll_offset > 0);
+
+        writer.write_byte(b'x');
+        assert_eq!(writer.scroll_offset, 0);
+        let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 1][writer.column_position - 1].read();
+        assert_eq!(screen_char.ascii_character, b'x');
+    }
+
+    #[test_case]
+    fn test_selection_text_and_overlay() {
+        let mut writer = WRITER.lock();
+        writeln!(writer, "\nfirst selected line  ").expect("writeln failed");
+        writeln!(writer, "second selected line").expect("writeln failed");
+        let (first, second) = (BUFFER_HEIGHT - 3, BUFFER_HEIGHT - 2);
+        let before = writer.buffer.chars[first][6].read();
+
+        // Selected backwards, from the end of "second" to the start of
+        // "selected" in the row above
+        writer.set_selection(Some(((second, 5), (first, 6))));
+        assert_eq!(writer.selected_text(), "selected line\nsecond");
+        let highlighted = writer.buffer.chars[first][6].read();
+        assert_eq!(highlighted.color_code, before.color_code.inverted());
+        assert_eq!(writer.live_screen[first][6], before);
+
+        writer.set_selection(None);
+        assert_eq!(writer.buffer.chars[first][6].read(), before);
+        assert_eq!(writer.selected_text(), "");
+    }
+
+    #[test_case]
+    fn test_pointer_stays_out_of_scrollback() {
+        let mut writer = WRITER.lock();
+        writeln!(writer).expect("writeln failed");
+        let before = writer.buffer.chars[0][0].read();
+        writer.set_pointer(Some((0, 0)));
+        writer.set_selection(Some(((0, 0), (0, 3))));
+        assert_ne!(writer.buffer.chars[0][0].read(), before);
+
+        // The top row goes to the scrollback and the selection with it
+        writeln!(writer).expect("writeln failed");
+        assert_eq!(writer.selection, None);
+        let saved = (writer.scrollback_position - 1) % SCROLLBACK_SIZE;
+        assert_eq!(writer.scrollback[saved][0], before);
+        let pointed = writer.buffer.chars[0][0].read();
+        assert_eq!(pointed.color_code, writer.live_screen[0][0].color_code.inverted());
+
+        writer.set_pointer(None);
+    }
+
+    #[test_case]
+    fn test_crash_screen_wraps_and_clips() {
+        // Keep other output off the screen meanwhile
+        let _writer = WRITER.lock();
+        let mut screen = unsafe { CrashScreen::new() };
+        for _ in 0..=BUFFER_WIDTH {
+            write!(screen, "x").expect("write failed");
+        }
+
+        let red = ColorCode::new(Color::White, Color::Red);
+        let wrapped = screen.buffer.chars[1][0].read();
+        assert_eq!(wrapped, ScreenChar { ascii_character: b'x', color_code: red });
+        assert_eq!(screen.buffer.chars[BUFFER_HEIGHT - 1][0].read().color_code, red);
+
+        for _ in 0..BUFFER_HEIGHT {
+            writeln!(screen).expect("writeln failed");
+        }
+        write!(screen, "cut off").expect("write failed");
+    }
+}
@@ -0,0 +1,85 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_kernel::allocator::HEAP_SIZE;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_kernel::{allocator, memory};
+
+    rust_kernel::init();
+    unsafe { memory::init(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn simple_allocation() {
+    let heap_value_1 = Box::new(41);
+    let heap_value_2 = Box::new(13);
+    assert_eq!(*heap_value_1, 41);
+    assert_eq!(*heap_value_2, 13);
+}
+
+#[test_case]
+fn large_vec() {
+    let n = 1000;
+    let mut vec = Vec::new();
+    for i in 0..n {
+        vec.push(i);
+    }
+    assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+}
+
+#[test_case]
+fn many_boxes() {
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+}
+
+#[test_case]
+fn many_boxes_long_lived() {
+    let long_lived = Box::new(1);
+    for i in 0..HEAP_SIZE {
+        let x = Box::new(i);
+        assert_eq!(*x, i);
+    }
+    assert_eq!(*long_lived, 1);
+}
+
+#[test_case]
+fn strings_and_maps() {
+    let mut map = BTreeMap::new();
+    for i in 0..100u16 {
+        let mut line = String::from("PRINT ");
+        line.push_str(if i % 2 == 0 { "\"EVEN\"" } else { "\"ODD\"" });
+        map.insert(i * 10, line);
+    }
+    assert_eq!(map.len(), 100);
+    assert_eq!(map[&20], "PRINT \"EVEN\"");
+    assert_eq!(map[&990], "PRINT \"ODD\"");
+}
+
+#[test_case]
+fn large_allocation_uses_fallback() {
+    let buffer = alloc::vec![0xabu8; 64 * 1024];
+    assert!(buffer.iter().all(|&b| b == 0xab));
+}
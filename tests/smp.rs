@@ -0,0 +1,59 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_kernel::smp;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_kernel::{acpi, allocator, apic, memory};
+
+    rust_kernel::init();
+    unsafe { memory::init(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    if acpi::init() {
+        apic::init();
+    }
+    smp::init();
+
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn all_processors_online() {
+    let processors = rust_kernel::acpi::get()
+        .and_then(|acpi| acpi.madt())
+        .map_or(1, |madt| madt.processors.iter().filter(|p| p.enabled).count());
+
+    // QEMU runs the tests with `-smp 4`
+    assert_eq!(smp::cpus().len(), processors);
+    assert!(smp::cpus().iter().all(|cpu| cpu.is_online()));
+}
+
+#[test_case]
+fn boot_processor_comes_first() {
+    let cpus = smp::cpus();
+    assert!(cpus[0].is_bsp);
+    assert!(cpus[1..].iter().all(|cpu| !cpu.is_bsp));
+    assert_eq!(smp::current().map(|cpu| cpu.index), Some(0));
+}
+
+#[test_case]
+fn apic_ids_are_distinct() {
+    let cpus = smp::cpus();
+    for (i, cpu) in cpus.iter().enumerate() {
+        assert_eq!(cpu.index, i);
+        assert!(cpus[i + 1..].iter().all(|other| other.apic_id != cpu.apic_id));
+    }
+}
@@ -0,0 +1,140 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_kernel::initrd;
+use rust_kernel::usermode::{self, ExitStatus, Pid, ProcessError};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use rust_kernel::{acpi, allocator, apic, memory, smp, thread};
+
+    rust_kernel::init();
+    unsafe { memory::init(boot_info) };
+    allocator::init_heap().expect("heap initialization failed");
+    thread::init();
+    if acpi::init() {
+        apic::init();
+    }
+    smp::init();
+    usermode::init();
+
+    test_main();
+    rust_kernel::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_kernel::test_panic_handler(info)
+}
+
+fn run(name: &str) -> ExitStatus {
+    let pid = usermode::run(usermode::find(name).unwrap(), usermode::FOREGROUND).unwrap();
+    usermode::wait(pid).unwrap()
+}
+
+fn start(args: &[&str]) -> Pid {
+    let file = initrd::find(args[0]).unwrap();
+    usermode::exec(file.data, args, usermode::BACKGROUND).unwrap()
+}
+
+fn exec(args: &[&str]) -> ExitStatus {
+    usermode::wait(start(args)).unwrap()
+}
+
+#[test_case]
+fn program_exits_with_code() {
+    assert_eq!(run("hello"), ExitStatus::Exited(0));
+    assert_eq!(run("dots"), ExitStatus::Exited(5));
+}
+
+#[test_case]
+fn fault_kills_only_the_program() {
+    assert_eq!(run("fault"), ExitStatus::Killed);
+    assert_eq!(run("hello"), ExitStatus::Exited(0));
+}
+
+#[test_case]
+fn programs_run_side_by_side() {
+    let first = start(&["count", "1"]);
+    let second = start(&["count", "1"]);
+    assert_ne!(first, second);
+    assert_eq!(usermode::wait(first), Ok(ExitStatus::Exited(0)));
+    assert_eq!(usermode::wait(second), Ok(ExitStatus::Exited(0)));
+    // Waiting removed them from the process table
+    assert_eq!(usermode::wait(first), Err(ProcessError::NoSuchProcess));
+}
+
+#[test_case]
+fn address_space_is_freed() {
+    let allocated = rust_kernel::memory::frame_allocator().allocated_frames();
+    run("hello");
+    assert_eq!(rust_kernel::memory::frame_allocator().allocated_frames(), allocated);
+}
+
+#[test_case]
+fn exec_runs_elf_programs() {
+    assert_eq!(exec(&["hello"]), ExitStatus::Exited(0));
+    // The arguments reach it: a bad count is rejected with code 1
+    assert_eq!(exec(&["count", "many"]), ExitStatus::Exited(1));
+}
+
+#[test_case]
+fn exec_rejects_other_files() {
+    static NOT_ELF: [u8; 4] = *b"#!sh";
+    assert_eq!(
+        usermode::exec(&NOT_ELF, &["script"], usermode::BACKGROUND),
+        Err(usermode::RunError::InvalidExecutable(usermode::ElfError::NotElf))
+    );
+    assert!(usermode::list().is_empty());
+}
+
+#[test_case]
+fn kill_ends_a_spinning_program() {
+    let allocated = rust_kernel::memory::frame_allocator().allocated_frames();
+    let pid = start(&["spin"]);
+    rust_kernel::thread::sleep(5);
+    assert_eq!(usermode::kill(pid), Ok(()));
+    assert_eq!(usermode::wait(pid), Ok(ExitStatus::Killed));
+    assert_eq!(usermode::kill(pid), Err(ProcessError::NoSuchProcess));
+    assert_eq!(rust_kernel::memory::frame_allocator().allocated_frames(), allocated);
+}
+
+#[test_case]
+fn kill_ends_a_sleeping_program() {
+    let pid = start(&["count", "100"]);
+    rust_kernel::thread::sleep(5);
+    usermode::kill(pid).unwrap();
+    assert_eq!(usermode::wait(pid), Ok(ExitStatus::Killed));
+}
+
+#[test_case]
+fn endless_sleep_only_blocks_the_program() {
+    let pid = start(&["sleep", "18446744073709551615"]);
+    rust_kernel::thread::sleep(5);
+    assert_eq!(usermode::try_wait(pid), Ok(None));
+    usermode::kill(pid).unwrap();
+    assert_eq!(usermode::wait(pid), Ok(ExitStatus::Killed));
+}
+
+#[test_case]
+fn child_processes_know_their_parent() {
+    let parent = start(&["spawn", "count", "1"]);
+    let child = loop {
+        let child = usermode::list().into_iter().find(|process| process.parent == Some(parent));
+        if let Some(child) = child {
+            break child;
+        }
+        rust_kernel::thread::sleep(1);
+    };
+    assert_eq!(child.name, "count");
+    assert_eq!(child.handles, usermode::BACKGROUND);
+    // The parent collects the child's exit code and passes it on
+    assert_eq!(usermode::wait(parent), Ok(ExitStatus::Exited(0)));
+    assert_eq!(usermode::wait(child.pid), Err(ProcessError::NoSuchProcess));
+}
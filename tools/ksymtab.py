@@ -0,0 +1,133 @@
+#!/usr/bin/env python3
+"""Fills the `.ksymtab` section of a linked kernel with its function symbols.
+
+The kernel reserves the section (see `src/backtrace.rs`) and uses it to
+symbolize backtraces. Names are demangled here, so the kernel only has to
+look addresses up.
+
+Table layout, little endian:
+    magic       8 bytes  b"KSYMTAB1"
+    count       u32      number of entries
+    strings     u32      offset of the string table from the start
+    entries     count * (address u64, size u32, name offset u32), by address
+    string table         NUL-terminated names
+"""
+
+import re
+import struct
+import subprocess
+import sys
+
+MAGIC = b"KSYMTAB1"
+SECTION = ".ksymtab"
+
+SHT_SYMTAB = 2
+SHF_EXECINSTR = 0x4
+STT_NOTYPE = 0
+STT_FUNC = 2
+
+DEMANGLERS = (["llvm-cxxfilt"], ["c++filt"])
+# Demangled names keep their hashes: `core[1a2b3c4d5e6f7a8b]` in v0 names,
+# a trailing `::h1a2b3c4d5e6f7a8b` in legacy ones
+HASHES = re.compile(r"\[[0-9a-f]{16}\]|::h[0-9a-f]{16}$")
+
+
+def demangle(names):
+    """Demangles the Rust names with the first demangler that is installed.
+    Names stay mangled if there is none."""
+    for command in DEMANGLERS:
+        try:
+            result = subprocess.run(command, input="\n".join(names) + "\n",
+                                    capture_output=True, text=True, check=True)
+        except (OSError, subprocess.CalledProcessError):
+            continue
+        demangled = result.stdout.splitlines()
+        if len(demangled) == len(names):
+            return [HASHES.sub("", name) for name in demangled]
+    return names
+
+
+def sections(elf):
+    (shoff,) = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3a)
+    headers = [struct.unpack_from("<IIQQQQIIQQ", elf, shoff + i * shentsize) for i in range(shnum)]
+
+    names_offset = headers[shstrndx][4]
+    result = []
+    for header in headers:
+        name_end = elf.index(b"\0", names_offset + header[0])
+        name = elf[names_offset + header[0]:name_end].decode()
+        result.append((name,) + header[1:])
+    return result
+
+
+def function_symbols(elf, section_headers):
+    """Yields (address, size, name) for every symbol in executable code."""
+    for _, sh_type, _, _, offset, size, link, _, _, entsize in section_headers:
+        if sh_type != SHT_SYMTAB:
+            continue
+        strings_offset = section_headers[link][4]
+        for i in range(size // entsize):
+            name_index, info, _, shndx, value, sym_size = struct.unpack_from("<IBBHQQ", elf, offset + i * entsize)
+            if info & 0xf not in (STT_FUNC, STT_NOTYPE) or value == 0:
+                continue
+            if shndx == 0 or shndx >= len(section_headers):
+                continue
+            if not section_headers[shndx][2] & SHF_EXECINSTR:
+                continue
+            name_end = elf.index(b"\0", strings_offset + name_index)
+            name = elf[strings_offset + name_index:name_end].decode(errors="replace")
+            if name and not name.startswith(".L"):
+                yield value, sym_size, name
+
+
+def build_table(symbols):
+    symbols = list(symbols)
+    names = demangle([name for _, _, name in symbols])
+
+    by_address = {}
+    for (address, size, _), name in zip(symbols, names):
+        # Keep one symbol per address, preferring one that has a size
+        if address not in by_address or (size and not by_address[address][0]):
+            by_address[address] = (size, name)
+
+    string_offsets = {}
+    strings = bytearray()
+    entries = bytearray()
+    for address in sorted(by_address):
+        size, name = by_address[address]
+        if name not in string_offsets:
+            string_offsets[name] = len(strings)
+            strings += name.encode() + b"\0"
+        entries += struct.pack("<QII", address, min(size, 0xffffffff), string_offsets[name])
+
+    header_size = len(MAGIC) + 8
+    header = MAGIC + struct.pack("<II", len(by_address), header_size + len(entries))
+    return header + entries + strings
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit(f"usage: {sys.argv[0]} <kernel elf>")
+    path = sys.argv[1]
+
+    with open(path, "rb") as file:
+        elf = bytearray(file.read())
+
+    section_headers = sections(elf)
+    target = next((s for s in section_headers if s[0] == SECTION), None)
+    if target is None:
+        sys.exit(f"{path}: no {SECTION} section")
+
+    table = build_table(function_symbols(elf, section_headers))
+    offset, size = target[4], target[5]
+    if len(table) > size:
+        sys.exit(f"{path}: symbol table needs {len(table)} bytes, {SECTION} has {size}")
+
+    elf[offset:offset + size] = table + bytes(size - len(table))
+    with open(path, "wb") as file:
+        file.write(elf)
+
+
+if __name__ == "__main__":
+    main()
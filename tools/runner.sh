@@ -0,0 +1,5 @@
+#!/bin/sh
+# Cargo runner: fills in the kernel symbol table, then boots with bootimage.
+set -e
+python3 "$(dirname "$0")/ksymtab.py" "$1"
+exec bootimage runner "$@"
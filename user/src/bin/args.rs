@@ -0,0 +1,13 @@
+#![no_std]
+#![no_main]
+
+use carlos_user::{println, Args};
+
+#[no_mangle]
+fn main(args: Args) -> i32 {
+    println!("argc = {}", args.len());
+    for (index, arg) in args.iter().enumerate() {
+        println!("argv[{}] = \"{}\"", index, arg);
+    }
+    0
+}
@@ -0,0 +1,23 @@
+#![no_std]
+#![no_main]
+
+use carlos_user::{print, println, sleep_ms, Args};
+
+/// Counts up to the first argument (default 5), one number a second.
+#[no_mangle]
+fn main(args: Args) -> i32 {
+    let limit = match args.get(1).map(str::parse::<u32>) {
+        None => 5,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            println!("Usage: count [limit]");
+            return 1;
+        }
+    };
+    for n in 1..=limit {
+        print!("{} ", n);
+        sleep_ms(1000);
+    }
+    println!();
+    0
+}
@@ -0,0 +1,13 @@
+#![no_std]
+#![no_main]
+
+use carlos_user::{println, Args};
+
+#[no_mangle]
+fn main(args: Args) -> i32 {
+    match args.get(1) {
+        Some(name) => println!("Hello, {}!", name),
+        None => println!("Hello from an ELF binary!"),
+    }
+    0
+}
@@ -0,0 +1,15 @@
+#![no_std]
+#![no_main]
+
+use carlos_user::{println, sleep_ms, Args};
+
+/// Sleeps for the first argument in milliseconds.
+#[no_mangle]
+fn main(args: Args) -> i32 {
+    let Some(Ok(ms)) = args.get(1).map(str::parse::<u64>) else {
+        println!("Usage: sleep <milliseconds>");
+        return 1;
+    };
+    sleep_ms(ms);
+    0
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use carlos_user::{println, spawn, wait, Args};
+
+/// Runs its arguments as a command in a child process and exits with the
+/// child's exit code.
+#[no_mangle]
+fn main(args: Args) -> i32 {
+    let mut buffer = [0u8; 256];
+    let mut len = 0;
+    for arg in args.iter().skip(1) {
+        let end = len + arg.len() + 1;
+        if end > buffer.len() {
+            println!("spawn: command too long");
+            return 1;
+        }
+        buffer[len..end - 1].copy_from_slice(arg.as_bytes());
+        buffer[end - 1] = b' ';
+        len = end;
+    }
+    let command = core::str::from_utf8(&buffer[..len]).unwrap();
+    if command.is_empty() {
+        println!("Usage: spawn <program> [args...]");
+        return 1;
+    }
+
+    let Some(pid) = spawn(command) else {
+        println!("spawn: can't start {}", command);
+        return 1;
+    };
+    println!("Started child {}", pid);
+    match wait(pid) {
+        Some(code) => {
+            println!("Child {} exited with code {}", pid, code);
+            code
+        }
+        None => {
+            println!("Child {} was killed", pid);
+            1
+        }
+    }
+}
@@ -0,0 +1,12 @@
+#![no_std]
+#![no_main]
+
+use carlos_user::{println, Args};
+
+/// Loops forever without a system call, until it is killed.
+#[no_mangle]
+fn main(_args: Args) -> i32 {
+    println!("Spinning, Ctrl-C or kill to stop");
+    #[allow(clippy::empty_loop)]
+    loop {}
+}
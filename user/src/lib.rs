@@ -0,0 +1,155 @@
+//! Runtime for CarlOS user programs: the entry point, system calls and
+//! console output.
+//!
+//! A program defines `#[no_mangle] fn main(args: Args) -> i32`; its return
+//! value is the exit code.
+
+#![no_std]
+
+use core::arch::{asm, naked_asm};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+
+const SYS_EXIT: u64 = 0;
+const SYS_WRITE: u64 = 1;
+const SYS_READ_KEY: u64 = 2;
+const SYS_SLEEP: u64 = 3;
+const SYS_SPAWN: u64 = 4;
+const SYS_WAIT: u64 = 5;
+
+/// Returned by system calls that failed.
+const ERROR: u64 = u64::MAX;
+
+unsafe fn syscall(number: u64, arg0: u64, arg1: u64, arg2: u64) -> u64 {
+    let result;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number => result,
+            in("rdi") arg0,
+            in("rsi") arg1,
+            in("rdx") arg2,
+            out("rcx") _,
+            out("r11") _,
+            options(nostack),
+        );
+    }
+    result
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall(SYS_EXIT, code as i64 as u64, 0, 0) };
+    unreachable!("exit returned");
+}
+
+/// Prints `text` to the console.
+pub fn write(text: &str) {
+    unsafe { syscall(SYS_WRITE, text.as_ptr() as u64, text.len() as u64, 0) };
+}
+
+/// Waits for the next key typed.
+pub fn read_key() -> u8 {
+    unsafe { syscall(SYS_READ_KEY, 0, 0, 0) as u8 }
+}
+
+pub fn sleep_ms(ms: u64) {
+    unsafe { syscall(SYS_SLEEP, ms, 0, 0) };
+}
+
+/// Starts the program named by the first word of `command` with the words
+/// as its arguments, as a child. Returns its PID.
+pub fn spawn(command: &str) -> Option<u64> {
+    let pid = unsafe { syscall(SYS_SPAWN, command.as_ptr() as u64, command.len() as u64, 0) };
+    (pid != ERROR).then_some(pid)
+}
+
+/// Waits for child `pid` to exit and returns its exit code, or `None` if it
+/// was killed or isn't a child.
+pub fn wait(pid: u64) -> Option<i32> {
+    let code = unsafe { syscall(SYS_WAIT, pid, 0, 0) };
+    (code != ERROR).then_some(code as i32)
+}
+
+pub struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write(s);
+        Ok(())
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    let _ = Console.write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+/// The program's arguments, starting with its name.
+#[derive(Clone, Copy)]
+pub struct Args {
+    argc: usize,
+    argv: *const *const u8,
+}
+
+impl Args {
+    pub fn len(&self) -> usize {
+        self.argc
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.argc == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&'static str> {
+        if index >= self.argc {
+            return None;
+        }
+        unsafe {
+            let arg = *self.argv.add(index);
+            let len = (0..).position(|i| *arg.add(i) == 0).unwrap();
+            core::str::from_utf8(core::slice::from_raw_parts(arg, len)).ok()
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static str> + '_ {
+        (0..self.argc).filter_map(|index| self.get(index))
+    }
+}
+
+/// Entered from the kernel with the stack as the System V ABI describes
+/// it: argc, then the argv pointers.
+#[unsafe(naked)]
+#[no_mangle]
+unsafe extern "C" fn _start() -> ! {
+    naked_asm!(
+        "mov rdi, [rsp]",
+        "lea rsi, [rsp + 8]",
+        "call {start}",
+        "ud2",
+        start = sym start,
+    )
+}
+
+extern "C" fn start(argc: usize, argv: *const *const u8) -> ! {
+    extern "Rust" {
+        fn main(args: Args) -> i32;
+    }
+    exit(unsafe { main(Args { argc, argv }) })
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    println!("{}", info);
+    exit(101)
+}